@@ -26,7 +26,7 @@ impl Database {
         let wal = Rc::new(RefCell::new(WalFile::new(
             io.clone(),
             wal_path,
-            db_header.borrow().page_size as usize,
+            db_header.borrow().page_size() as usize,
         )));
         let db = limbo_core::Database::open(io, page_io, wal).unwrap();
         let conn = db.connect();
@@ -126,13 +126,14 @@ impl limbo_core::File for File {
             limbo_core::Completion::Read(r) => r,
             _ => unreachable!(),
         };
-        {
+        let nr = {
             let mut buf = r.buf_mut();
             let buf: &mut [u8] = buf.as_mut_slice();
             let nr = self.vfs.pread(self.fd, buf, pos);
             assert!(nr >= 0);
-        }
-        r.complete();
+            nr
+        };
+        r.complete(nr as i32);
         Ok(())
     }
 
@@ -233,11 +234,13 @@ impl limbo_core::DatabaseStorage for DatabaseStorage {
             _ => unreachable!(),
         };
         let size = r.buf().len();
-        assert!(page_idx > 0);
-        if !(512..=65536).contains(&size) || size & (size - 1) != 0 {
-            return Err(limbo_core::LimboError::NotADB);
+        if !limbo_core::is_valid_page_size(size) {
+            return Err(limbo_core::LimboError::InternalError(format!(
+                "cannot read page {}: {} bytes is not a valid page size",
+                page_idx, size
+            )));
         }
-        let pos = (page_idx - 1) * size;
+        let pos = limbo_core::page_offset(page_idx, size)?;
         self.file.pread(pos, c)?;
         Ok(())
     }
@@ -249,7 +252,13 @@ impl limbo_core::DatabaseStorage for DatabaseStorage {
         c: Rc<limbo_core::Completion>,
     ) -> Result<()> {
         let size = buffer.borrow().len();
-        let pos = (page_idx - 1) * size;
+        if !limbo_core::is_valid_page_size(size) {
+            return Err(limbo_core::LimboError::InternalError(format!(
+                "cannot write page {}: {} bytes is not a valid page size",
+                page_idx, size
+            )));
+        }
+        let pos = limbo_core::page_offset(page_idx, size)?;
         self.file.pwrite(pos, buffer, c)?;
         Ok(())
     }
@@ -1,10 +1,11 @@
 use criterion::{criterion_group, criterion_main, Criterion, Throughput};
-use limbo_core::{Database, PlatformIO, IO};
+use limbo_core::{Database, PlatformIO, RecordOptions, RecordReader, IO};
 use pprof::criterion::{Output, PProfProfiler};
 use std::sync::Arc;
 
 fn bench(c: &mut Criterion) {
     limbo_bench(c);
+    record_bench(c);
 
     // https://github.com/penberg/limbo/issues/174
     // The rusqlite benchmark crashes on Mac M1 when using the flamegraph features
@@ -101,6 +102,37 @@ fn limbo_bench(criterion: &mut Criterion) {
     );
 }
 
+fn record_bench(criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("record");
+    group.throughput(Throughput::Elements(1));
+
+    // Eight one-byte integers: every serial type fits in a single header byte.
+    let mut single_byte = vec![9];
+    single_byte.extend([1; 8]);
+    single_byte.extend(1..=8);
+    // Eight 100-byte strings: serial type 213 takes a two-byte varint.
+    let mut multi_byte = vec![17];
+    for _ in 0..8 {
+        multi_byte.extend([0x81, 0x55]);
+    }
+    multi_byte.extend([b'a'; 800]);
+
+    for (name, payload) in [
+        ("Decode record: 8 single-byte serial types", single_byte),
+        ("Decode record: 8 multi-byte serial types", multi_byte),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let record = RecordReader::new(&payload, RecordOptions::default())
+                    .unwrap()
+                    .record()
+                    .unwrap();
+                assert_eq!(record.values.len(), 8);
+            });
+        });
+    }
+}
+
 fn rusqlite_bench(criterion: &mut Criterion) {
     let mut group = criterion.benchmark_group("rusqlite");
     group.throughput(Throughput::Elements(1));
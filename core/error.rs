@@ -6,6 +6,16 @@ pub enum LimboError {
     Corrupt(String),
     #[error("File is not a database")]
     NotADB,
+    #[error("Short read: got {0} of {1} bytes")]
+    ShortRead(usize, usize),
+    #[error("Attempt to write a readonly database")]
+    ReadOnly,
+    #[error("String or blob too big: {0} bytes exceeds the maximum of {1}")]
+    TooBig(usize, usize),
+    #[error("Database changed during scan")]
+    DatabaseChangedDuringScan,
+    #[error("Read quota of {0} bytes exceeded")]
+    QuotaExceeded(usize),
     #[error("Internal error: {0}")]
     InternalError(String),
     #[error("Parse error: {0}")]
@@ -40,6 +50,17 @@ pub enum LimboError {
     Constraint(String),
 }
 
+impl LimboError {
+    /// Appends `context` to the message of a corruption error so that it can be
+    /// traced back to where parsing failed. Other errors are returned unchanged.
+    pub fn context(self, context: impl FnOnce() -> String) -> Self {
+        match self {
+            LimboError::Corrupt(msg) => LimboError::Corrupt(format!("{} {}", msg, context())),
+            err => err,
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! bail_parse_error {
     ($($arg:tt)*) => {
@@ -89,7 +89,7 @@ impl IO for DarwinIO {
                     std::result::Result::Ok(n) => {
                         match &cf {
                             CompletionCallback::Read(_, ref c, _) => {
-                                c.complete(0);
+                                c.complete(n as i32);
                             }
                             CompletionCallback::Write(_, ref c, _, _) => {
                                 c.complete(n as i32);
@@ -201,7 +201,7 @@ impl File for DarwinFile {
             std::result::Result::Ok(n) => {
                 trace!("pread n: {}", n);
                 // Read succeeded immediately
-                c.complete(0);
+                c.complete(n as i32);
                 Ok(())
             }
             Err(Errno::AGAIN) => {
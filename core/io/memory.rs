@@ -0,0 +1,170 @@
+use crate::{Buffer, Completion, File, OpenFlags, Result, IO};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// An `IO` without a file system, for databases that live entirely in memory such
+/// as those opened with [`crate::Database::open_bytes`].
+///
+/// Files are held in memory and looked up by path: opening a path again returns
+/// the same file, and opening a new path creates an empty one. A database image
+/// added with [`MemoryIO::add_file`] can be opened with
+/// [`crate::Database::open_file`] like a file on disk.
+pub struct MemoryIO {
+    files: RefCell<HashMap<String, Rc<MemoryFile>>>,
+}
+
+impl MemoryIO {
+    pub fn new() -> Self {
+        Self {
+            files: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a file at `path` holding `data`, replacing any file already there.
+    pub fn add_file(&self, path: &str, data: Vec<u8>) {
+        let file = Rc::new(MemoryFile {
+            data: RefCell::new(data),
+        });
+        self.files.borrow_mut().insert(path.to_string(), file);
+    }
+
+    /// Returns a copy of the contents of the file at `path`, if there is one.
+    pub fn file_contents(&self, path: &str) -> Option<Vec<u8>> {
+        let files = self.files.borrow();
+        files.get(path).map(|file| file.data.borrow().clone())
+    }
+}
+
+impl Default for MemoryIO {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IO for MemoryIO {
+    fn open_file(&self, path: &str, _flags: OpenFlags, _direct: bool) -> Result<Rc<dyn File>> {
+        let mut files = self.files.borrow_mut();
+        let file = files.entry(path.to_string()).or_insert_with(|| {
+            Rc::new(MemoryFile {
+                data: RefCell::new(Vec::new()),
+            })
+        });
+        Ok(file.clone())
+    }
+
+    fn run_once(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn generate_random_number(&self) -> i64 {
+        let mut buf = [0u8; 8];
+        getrandom::getrandom(&mut buf).unwrap();
+        i64::from_ne_bytes(buf)
+    }
+
+    fn get_current_time(&self) -> String {
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+}
+
+pub struct MemoryFile {
+    data: RefCell<Vec<u8>>,
+}
+
+impl File for MemoryFile {
+    fn lock_file(&self, _exclusive: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn unlock_file(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn pread(&self, pos: usize, c: Rc<Completion>) -> Result<()> {
+        let nr = {
+            let r = match &(*c) {
+                Completion::Read(r) => r,
+                _ => unreachable!(),
+            };
+            let mut buf = r.buf_mut();
+            let buf = buf.as_mut_slice();
+            let data = self.data.borrow();
+            let available = data.get(pos..).unwrap_or_default();
+            let n = buf.len().min(available.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        c.complete(nr as i32);
+        Ok(())
+    }
+
+    fn pwrite(&self, pos: usize, buffer: Rc<RefCell<Buffer>>, c: Rc<Completion>) -> Result<()> {
+        let buf = buffer.borrow();
+        let buf = buf.as_slice();
+        {
+            let mut data = self.data.borrow_mut();
+            if data.len() < pos + buf.len() {
+                data.resize(pos + buf.len(), 0);
+            }
+            data[pos..pos + buf.len()].copy_from_slice(buf);
+        }
+        c.complete(buf.len() as i32);
+        Ok(())
+    }
+
+    fn sync(&self, c: Rc<Completion>) -> Result<()> {
+        c.complete(0);
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.data.borrow().len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::database::FileStorage;
+    use crate::storage::sqlite3_ondisk::read_geometry;
+    use crate::types::OwnedValue;
+    use crate::Database;
+    use std::sync::Arc;
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn test_open_database_image() {
+        let image = include_bytes!("../../testing/testing.db").to_vec();
+
+        let memory_io = Arc::new(MemoryIO::new());
+        memory_io.add_file("image.db", image.clone());
+        let io: Arc<dyn IO> = memory_io.clone();
+        let file = io.open_file("image.db", OpenFlags::None, false).unwrap();
+        let geometry = read_geometry(&io, Rc::new(FileStorage::new(file))).unwrap();
+        assert_eq!(geometry.page_size, 4096);
+        assert_eq!(geometry.page_count as usize * 4096, image.len());
+
+        let db = Database::open_file(io.clone(), "image.db").unwrap();
+        let users = db.scan_table("users").unwrap().count();
+        assert_eq!(users, 10000);
+        let products = db
+            .scan_table("products")
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(products.len(), 11);
+        let (rowid, record) = &products[0];
+        assert_eq!(*rowid, 1);
+        // SQLite stores the whole REAL price 79.0 as an integer.
+        assert_eq!(
+            record.values[1..],
+            [
+                OwnedValue::Text(Rc::new("hat".to_string())),
+                OwnedValue::Integer(79)
+            ]
+        );
+        assert_eq!(memory_io.file_contents("image.db").unwrap(), image);
+        assert_eq!(memory_io.file_contents("missing.db"), None);
+    }
+}
@@ -33,7 +33,7 @@ pub trait IO {
     fn get_current_time(&self) -> String;
 }
 
-pub type Complete = dyn Fn(Rc<RefCell<Buffer>>);
+pub type Complete = dyn Fn(Rc<RefCell<Buffer>>, i32);
 pub type WriteComplete = dyn Fn(i32);
 pub type SyncComplete = dyn Fn(i32);
 
@@ -51,7 +51,7 @@ pub struct ReadCompletion {
 impl Completion {
     pub fn complete(&self, result: i32) {
         match self {
-            Completion::Read(r) => r.complete(),
+            Completion::Read(r) => r.complete(result),
             Completion::Write(w) => w.complete(result),
             Completion::Sync(s) => s.complete(result), // fix
         }
@@ -79,8 +79,8 @@ impl ReadCompletion {
         self.buf.borrow_mut()
     }
 
-    pub fn complete(&self) {
-        (self.complete)(self.buf.clone());
+    pub fn complete(&self, bytes_read: i32) {
+        (self.complete)(self.buf.clone(), bytes_read);
     }
 }
 
@@ -186,3 +186,5 @@ cfg_block! {
 }
 
 mod common;
+mod memory;
+pub use memory::MemoryIO;
@@ -56,16 +56,27 @@ impl File for WindowsFile {
     fn pread(&self, pos: usize, c: Rc<Completion>) -> Result<()> {
         let mut file = self.file.borrow_mut();
         file.seek(std::io::SeekFrom::Start(pos as u64))?;
-        {
+        let nr = {
             let r = match &(*c) {
                 Completion::Read(r) => r,
                 _ => unreachable!(),
             };
             let mut buf = r.buf_mut();
             let buf = buf.as_mut_slice();
-            file.read_exact(buf)?;
-        }
-        c.complete(0);
+            // Like pread(2), a read past the end of the file returns fewer bytes,
+            // which the completion reports as a short read.
+            let mut nr = 0;
+            while nr < buf.len() {
+                match file.read(&mut buf[nr..]) {
+                    Ok(0) => break,
+                    Ok(n) => nr += n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            nr
+        };
+        c.complete(nr as i32);
         Ok(())
     }
 
@@ -17,17 +17,20 @@ static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 use fallible_iterator::FallibleIterator;
 use log::trace;
-use schema::Schema;
 use sqlite3_parser::ast;
 use sqlite3_parser::{ast::Cmd, lexer::sql::Parser};
+use std::collections::HashMap;
 use std::rc::Weak;
 use std::sync::{Arc, OnceLock};
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
 use storage::btree::btree_init_page;
 #[cfg(feature = "fs")]
 use storage::database::FileStorage;
 use storage::pager::allocate_page;
-use storage::sqlite3_ondisk::{DatabaseHeader, DATABASE_HEADER_SIZE};
+use storage::sqlite3_ondisk::DATABASE_HEADER_SIZE;
 pub use storage::wal::WalFile;
 use util::parse_schema_rows;
 
@@ -37,17 +40,36 @@ use translate::planner::prepare_select_plan;
 pub use error::LimboError;
 pub type Result<T> = std::result::Result<T, error::LimboError>;
 
+pub use io::MemoryIO;
 pub use io::OpenFlags;
 #[cfg(feature = "fs")]
 pub use io::PlatformIO;
 pub use io::{Buffer, Completion, File, WriteCompletion, IO};
+pub use schema::{read_schema_entries, Schema, SchemaEntry, SchemaEntryType};
+pub use storage::btree::{
+    dump_tables, find_index_insert_position, find_insert_position, first_leaf, index_join_scan,
+    merge_scan, scan_columnar, scan_table, scan_with_page, BTreeCursor, ColumnBatch, ColumnVector,
+    ColumnarScan, DumpScan, IndexJoinScan, MergeScan, PageScan, RecordFilter, TableScan,
+};
 pub use storage::buffer_pool::BufferPool;
-pub use storage::database::DatabaseStorage;
+pub use storage::database::{
+    page_offset, DatabaseStorage, MemoryStorage, ReadQuota, MAX_DATABASE_FILE_SIZE,
+};
 pub use storage::pager::Page;
 pub use storage::pager::Pager;
+pub use storage::sqlite3_ondisk::{
+    build_cell_pointer_array, cell_has_overflow, cell_payload_size, is_valid_page_size,
+    migrate_header, min_page_size_for_row, parse_btree_page, payload_thresholds, ptrmap_page_for,
+    read_geometry, read_index_payload, read_page1, read_ptrmap_entries, serialize_btree_page,
+    total_freeblock_bytes, validate_header_invariants, value_serial_type, AffinityMismatch,
+    DatabaseGeometry, DatabaseHeader, FileFormatVersion, ParsedCell, ParsedPage, PtrmapType,
+    RecordOptions, RecordReader, SerialType, TextEncoding, TruncatedValue,
+    DEFAULT_MAX_RECORD_COLUMNS, SQLITE_MAGIC,
+};
+pub use storage::transaction::Transaction;
 pub use storage::wal::CheckpointStatus;
 pub use storage::wal::Wal;
-pub use types::Value;
+pub use types::{records_equal, FromRecord, OwnedRecord, Value};
 
 pub static DATABASE_VERSION: OnceLock<String> = OnceLock::new();
 
@@ -63,6 +85,7 @@ pub struct Database {
     schema: Rc<RefCell<Schema>>,
     header: Rc<RefCell<DatabaseHeader>>,
     transaction_state: RefCell<TransactionState>,
+    last_insert_rowid: Cell<i64>,
 }
 
 impl Database {
@@ -72,12 +95,30 @@ impl Database {
         maybe_init_database_file(&file, &io)?;
         let page_io = Rc::new(FileStorage::new(file));
         let wal_path = format!("{}-wal", path);
-        let db_header = Pager::begin_open(page_io.clone())?;
-        io.run_once()?;
+        let db_header = Pager::read_header(&io, page_io.clone())?;
         let wal = Rc::new(RefCell::new(WalFile::new(
             io.clone(),
             wal_path,
-            db_header.borrow().page_size as usize,
+            db_header.borrow().page_size() as usize,
+        )));
+        Self::open(io, page_io, wal)
+    }
+
+    /// Opens a database from the bytes of a database file, e.g. one embedded in the
+    /// binary, without touching the file system.
+    ///
+    /// `data` is shared with the database rather than copied, and is never written
+    /// to: the WAL is kept in memory and checkpoints fail. The page size is read
+    /// from the header.
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn open_bytes(data: Arc<[u8]>) -> Result<Rc<Database>> {
+        let io: Arc<dyn IO> = Arc::new(io::MemoryIO::new());
+        let page_io = Rc::new(MemoryStorage::new(data));
+        let db_header = Pager::read_header(&io, page_io.clone())?;
+        let wal = Rc::new(RefCell::new(WalFile::new(
+            io.clone(),
+            ":memory:-wal".to_string(),
+            db_header.borrow().page_size() as usize,
         )));
         Self::open(io, page_io, wal)
     }
@@ -87,8 +128,36 @@ impl Database {
         page_io: Rc<dyn DatabaseStorage>,
         wal: Rc<RefCell<dyn Wal>>,
     ) -> Result<Rc<Database>> {
-        let db_header = Pager::begin_open(page_io.clone())?;
-        io.run_once()?;
+        let (pager, header, schema) = Self::read_schema(io, page_io, wal)?;
+        Ok(Rc::new(Database {
+            pager,
+            schema: Rc::new(RefCell::new(schema)),
+            header,
+            transaction_state: RefCell::new(TransactionState::None),
+            last_insert_rowid: Cell::new(0),
+        }))
+    }
+
+    /// Reads the schema of a database without opening it, for metadata queries.
+    ///
+    /// Only the database header and the pages of the schema table are read, never
+    /// the pages of the tables and indexes it describes.
+    pub fn open_schema_only(
+        io: Arc<dyn IO>,
+        page_io: Rc<dyn DatabaseStorage>,
+        wal: Rc<RefCell<dyn Wal>>,
+    ) -> Result<Schema> {
+        let (_, _, schema) = Self::read_schema(io, page_io, wal)?;
+        Ok(schema)
+    }
+
+    fn read_schema(
+        io: Arc<dyn IO>,
+        page_io: Rc<dyn DatabaseStorage>,
+        wal: Rc<RefCell<dyn Wal>>,
+    ) -> Result<(Rc<Pager>, Rc<RefCell<DatabaseHeader>>, Schema)> {
+        let db_header = Pager::read_header(&io, page_io.clone())?;
+        validate_header_invariants(&db_header.borrow())?;
         DATABASE_VERSION.get_or_init(|| {
             let version = db_header.borrow().version_number;
             version.to_string()
@@ -109,14 +178,151 @@ impl Database {
         let mut schema = Schema::new();
         let rows = conn.query("SELECT * FROM sqlite_schema")?;
         parse_schema_rows(rows, &mut schema, io)?;
-        let schema = Rc::new(RefCell::new(schema));
-        let header = db_header;
-        Ok(Rc::new(Database {
-            pager,
-            schema,
-            header,
-            transaction_state: RefCell::new(TransactionState::None),
-        }))
+        Ok((pager, db_header, schema))
+    }
+
+    /// Returns the rowid of the most recent row inserted in the current write
+    /// transaction, or 0 if none was, see `sqlite3_last_insert_rowid()`.
+    pub fn last_insert_rowid(&self) -> i64 {
+        self.last_insert_rowid.get()
+    }
+
+    /// Begins a transaction that writes whole pages, see [`Transaction`].
+    pub fn begin(&self) -> Transaction {
+        Transaction::new(self.pager.clone(), self.header.clone())
+    }
+
+    /// Checks the consistency of the database file and returns a description of
+    /// each problem found. An empty list means no problems were found.
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let root_pages = self.schema.borrow().root_pages();
+        storage::integrity::integrity_check(&self.pager, &self.header.borrow(), &root_pages)
+    }
+
+    /// Counts the pages of the database file by kind: `table-interior`, `table-leaf`,
+    /// `index-interior`, `index-leaf`, `overflow`, `freelist` and `ptrmap`.
+    pub fn page_type_histogram(&self) -> Result<HashMap<&'static str, u64>> {
+        let root_pages = self.schema.borrow().root_pages();
+        storage::integrity::page_type_histogram(&self.pager, &self.header.borrow(), &root_pages)
+    }
+
+    /// Returns true if every freelist leaf page is filled with zeros, as they are
+    /// when pages are freed with `PRAGMA secure_delete` on.
+    pub fn freelist_pages_zeroed(&self) -> Result<bool> {
+        storage::integrity::freelist_pages_zeroed(&self.pager, &self.header.borrow())
+    }
+
+    /// Returns every page on the freelist, each trunk page followed by its leaf pages.
+    /// Fails if the freelist is malformed or its length does not match the header.
+    pub fn freelist(&self) -> Result<Vec<u32>> {
+        storage::integrity::freelist(&self.pager, &self.header.borrow())
+    }
+
+    /// Returns how many pages `PRAGMA incremental_vacuum` would truncate from the
+    /// database file.
+    pub fn reclaimable_pages(&self) -> Result<u32> {
+        storage::integrity::reclaimable_pages(&self.header.borrow(), self.pager.usable_size())
+    }
+
+    /// Scans `table` in rowid order, see [`scan_table`].
+    pub fn scan_table(&self, table: &str) -> Result<TableScan> {
+        let Some(table) = self.schema.borrow().get_table(table) else {
+            return Err(LimboError::ParseError(format!("no such table: {}", table)));
+        };
+        let cursor = BTreeCursor::new(self.pager.clone(), table.root_page, self.header.clone());
+        Ok(scan_table(cursor))
+    }
+
+    /// Scans `table` in batches of up to `batch_size` rows, transposed into columns,
+    /// see [`scan_columnar`].
+    pub fn scan_columnar(&self, table: &str, batch_size: usize) -> Result<ColumnarScan> {
+        let Some(table) = self.schema.borrow().get_table(table) else {
+            return Err(LimboError::ParseError(format!("no such table: {}", table)));
+        };
+        let cursor = BTreeCursor::new(self.pager.clone(), table.root_page, self.header.clone());
+        Ok(scan_columnar(cursor, batch_size))
+    }
+
+    /// Scans every table with a rowid, in name order, for a logical dump of the
+    /// database, see [`dump_tables`]. Internal `sqlite_` tables are skipped unless
+    /// `include_internal` is set.
+    pub fn dump_all(&self, include_internal: bool) -> Result<DumpScan> {
+        let schema = self.schema.borrow();
+        let mut tables = schema
+            .tables
+            .values()
+            .filter(|table| table.has_rowid)
+            .filter(|table| include_internal || !table.name.starts_with("sqlite_"))
+            .collect::<Vec<_>>();
+        tables.sort_by(|a, b| a.name.cmp(&b.name));
+        let cursors = tables
+            .into_iter()
+            .map(|table| {
+                let cursor =
+                    BTreeCursor::new(self.pager.clone(), table.root_page, self.header.clone());
+                (table.name.clone(), cursor)
+            })
+            .collect();
+        Ok(dump_tables(cursors))
+    }
+
+    /// Returns true if readers can run while the database is written, see
+    /// [`DatabaseHeader::supports_concurrent_readers`].
+    pub fn supports_concurrent_readers(&self) -> bool {
+        self.header.borrow().supports_concurrent_readers()
+    }
+
+    /// Returns true if `page_idx` is the root page of a table or index, including
+    /// page 1, the root of the schema table.
+    pub fn is_btree_root(&self, page_idx: u32) -> bool {
+        self.schema.borrow().is_btree_root(page_idx)
+    }
+
+    /// Writes a copy of the database file to `out`, e.g. to send it over the network,
+    /// and returns the number of bytes written.
+    ///
+    /// Pages are written in order, as the pager reads them, up to the page count in
+    /// the header, so that bytes past the end of the database are left out.
+    pub fn stream_to<W: std::io::Write>(&self, out: &mut W) -> Result<u64> {
+        let database_size = self.header.borrow().database_size as usize;
+        let mut written = 0;
+        for page_idx in 1..=database_size {
+            let page = self.pager.read_page_blocking(page_idx)?;
+            let page = page.borrow();
+            let data = page.contents.as_ref().unwrap().as_ptr();
+            out.write_all(data)?;
+            written += data.len() as u64;
+        }
+        Ok(written)
+    }
+
+    /// Reads the rows of `sqlite_schema` from its b-tree, see
+    /// [`read_schema_entries`].
+    pub fn schema_entries(&self) -> Result<Vec<SchemaEntry>> {
+        read_schema_entries(BTreeCursor::new(self.pager.clone(), 1, self.header.clone()))
+    }
+
+    /// Returns the pages of the schema table, whose root is page 1, in ascending
+    /// order.
+    pub fn schema_pages(&self) -> Result<Vec<usize>> {
+        storage::integrity::btree_pages(&self.pager, &self.header.borrow(), 1)
+    }
+
+    /// Returns the pointer map entry of page `page_idx`, as an `(entry_type,
+    /// parent_page)` pair, or `None` if the database is not in auto-vacuum mode or
+    /// the page has no entry.
+    pub fn ptrmap_entry(&self, page_idx: usize) -> Result<Option<(PtrmapType, u32)>> {
+        if !self.header.borrow().is_auto_vacuum() {
+            return Ok(None);
+        }
+        let usable_size = self.pager.usable_size();
+        let Some(ptrmap_page) = ptrmap_page_for(page_idx, usable_size) else {
+            return Ok(None);
+        };
+        let page = self.pager.read_page_blocking(ptrmap_page)?;
+        let page = page.borrow();
+        let entries = read_ptrmap_entries(page.contents.as_ref().unwrap(), usable_size)?;
+        Ok(entries.get(page_idx - ptrmap_page - 1).copied())
     }
 
     pub fn connect(self: &Rc<Database>) -> Rc<Connection> {
@@ -135,7 +341,7 @@ pub fn maybe_init_database_file(file: &Rc<dyn File>, io: &Arc<dyn IO>) -> Result
         let db_header = DatabaseHeader::default();
         let page1 = allocate_page(
             1,
-            &Rc::new(BufferPool::new(db_header.page_size as usize)),
+            &Rc::new(BufferPool::new(db_header.page_size() as usize)),
             DATABASE_HEADER_SIZE,
         );
         {
@@ -1,8 +1,9 @@
-use crate::{util::normalize_ident, Result};
+use crate::storage::btree::{scan_table, BTreeCursor};
+use crate::{types::OwnedValue, util::normalize_ident, LimboError, Result};
 use core::fmt;
 use fallible_iterator::FallibleIterator;
 use log::trace;
-use sqlite3_parser::ast::{Expr, Literal, TableOptions};
+use sqlite3_parser::ast::{Expr, Literal, TableOptions, UnaryOperator};
 use sqlite3_parser::{
     ast::{Cmd, CreateTableBody, QualifiedName, ResultColumn, Stmt},
     lexer::sql::Parser,
@@ -14,6 +15,89 @@ pub struct Schema {
     pub tables: HashMap<String, Rc<BTreeTable>>,
     // table_name to list of indexes for the table
     pub indexes: HashMap<String, Vec<Rc<Index>>>,
+    pub views: HashMap<String, Rc<SchemaEntry>>,
+    pub triggers: HashMap<String, Rc<SchemaEntry>>,
+}
+
+/// Type of a row of `sqlite_schema`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaEntryType {
+    Table,
+    Index,
+    View,
+    Trigger,
+}
+
+impl std::str::FromStr for SchemaEntryType {
+    type Err = LimboError;
+
+    fn from_str(ty: &str) -> Result<Self> {
+        match ty {
+            "table" => Ok(Self::Table),
+            "index" => Ok(Self::Index),
+            "view" => Ok(Self::View),
+            "trigger" => Ok(Self::Trigger),
+            _ => Err(LimboError::ParseError(format!(
+                "unknown schema entry type: {}",
+                ty
+            ))),
+        }
+    }
+}
+
+impl SchemaEntryType {
+    /// Returns true for tables and indexes, the entries stored in a b-tree.
+    pub fn has_btree(&self) -> bool {
+        matches!(self, Self::Table | Self::Index)
+    }
+}
+
+/// A row of `sqlite_schema`. The schema keeps views and triggers, which have no
+/// b-tree, as entries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaEntry {
+    pub entry_type: SchemaEntryType,
+    pub name: String,
+    /// The table an index or a trigger belongs to; for a table or a view, its own
+    /// name.
+    pub table_name: String,
+    /// The root page of the b-tree of a table or an index; 0 for views and triggers.
+    pub root_page: usize,
+    /// The `CREATE` statement of the object, empty for automatic indexes.
+    pub sql: String,
+}
+
+/// Reads the rows of `sqlite_schema` from its b-tree, without going through SQL.
+///
+/// `cursor` must be over the b-tree rooted at page 1, whose b-tree header follows
+/// the database header. As when loading the schema, rows of an unknown type are
+/// skipped.
+pub fn read_schema_entries(cursor: BTreeCursor) -> Result<Vec<SchemaEntry>> {
+    let mut entries = Vec::new();
+    for row in scan_table(cursor) {
+        let (_, record) = row?;
+        let Ok(entry_type) = record.get::<&str>(0)?.parse::<SchemaEntryType>() else {
+            continue;
+        };
+        let sql = match record.values.get(4) {
+            Some(OwnedValue::Null) => String::new(),
+            _ => record.get(4)?,
+        };
+        entries.push(SchemaEntry {
+            entry_type,
+            name: record.get(1)?,
+            table_name: record.get(2)?,
+            root_page: record.get::<i64>(3)? as usize,
+            sql,
+        });
+    }
+    Ok(entries)
+}
+
+impl Default for Schema {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Schema {
@@ -21,7 +105,24 @@ impl Schema {
         let mut tables: HashMap<String, Rc<BTreeTable>> = HashMap::new();
         let indexes: HashMap<String, Vec<Rc<Index>>> = HashMap::new();
         tables.insert("sqlite_schema".to_string(), Rc::new(sqlite_schema_table()));
-        Self { tables, indexes }
+        Self {
+            tables,
+            indexes,
+            views: HashMap::new(),
+            triggers: HashMap::new(),
+        }
+    }
+
+    /// Adds a view or a trigger.
+    pub fn add_entry(&mut self, entry: Rc<SchemaEntry>) {
+        let name = normalize_ident(&entry.name);
+        match entry.entry_type {
+            SchemaEntryType::View => self.views.insert(name, entry),
+            SchemaEntryType::Trigger => self.triggers.insert(name, entry),
+            SchemaEntryType::Table | SchemaEntryType::Index => {
+                unreachable!("{:?} entries are added as b-trees", entry.entry_type)
+            }
+        };
     }
 
     pub fn add_table(&mut self, table: Rc<BTreeTable>) {
@@ -34,6 +135,26 @@ impl Schema {
         self.tables.get(&name).cloned()
     }
 
+    /// Returns the root pages of all tables and indexes, including `sqlite_schema`.
+    pub fn root_pages(&self) -> Vec<usize> {
+        let tables = self.tables.values().map(|table| table.root_page);
+        let indexes = self.indexes.values().flatten().map(|index| index.root_page);
+        tables.chain(indexes).collect()
+    }
+
+    /// Returns true if `page_idx` is the root page of a table or index. A root page
+    /// may be a leaf or an interior page.
+    pub fn is_btree_root(&self, page_idx: u32) -> bool {
+        self.tables
+            .values()
+            .any(|table| table.root_page == page_idx as usize)
+            || self
+                .indexes
+                .values()
+                .flatten()
+                .any(|index| index.root_page == page_idx as usize)
+    }
+
     pub fn add_index(&mut self, index: Rc<Index>) {
         let table_name = normalize_ident(&index.table_name);
         self.indexes
@@ -41,6 +162,23 @@ impl Schema {
             .or_default()
             .push(index.clone())
     }
+
+    /// Returns the rowid selected by `column = value` when `column` is the integer
+    /// primary key of `table`, which aliases the rowid, and `value` is an integer.
+    /// Such a predicate can be answered with a rowid seek instead of a scan.
+    pub fn resolve_rowid_alias(
+        &self,
+        table: &str,
+        column: &str,
+        value: &OwnedValue,
+    ) -> Option<i64> {
+        let table = self.get_table(table)?;
+        let (_, column) = table.get_column(column)?;
+        match value {
+            OwnedValue::Integer(rowid) if table.column_is_rowid_alias(column) => Some(*rowid),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -112,6 +250,16 @@ impl Table {
         }
     }
 
+    /// Returns the value of column `index` in rows whose record does not include it,
+    /// which is the column default or NULL.
+    pub fn column_default(&self, index: usize) -> OwnedValue {
+        let default = match self {
+            Table::BTree(table) => table.columns.get(index).and_then(|c| c.default.as_ref()),
+            Table::Index(_) | Table::Pseudo(_) => None,
+        };
+        default.map_or(OwnedValue::Null, ColumnDefault::value)
+    }
+
     pub fn columns(&self) -> &Vec<Column> {
         match self {
             Table::BTree(table) => &table.columns,
@@ -219,6 +367,8 @@ impl PseudoTable {
             name: normalize_ident(name),
             ty,
             primary_key,
+            is_boolean: false,
+            default: None,
         });
     }
     pub fn get_column(&self, name: &str) -> Option<(usize, &Column)> {
@@ -275,6 +425,10 @@ fn create_table(
             }
             for (col_name, col_def) in columns {
                 let name = col_name.0.to_string();
+                let is_boolean = col_def
+                    .col_type
+                    .as_ref()
+                    .is_some_and(|data_type| data_type.name.to_uppercase().starts_with("BOOL"));
                 let ty = match col_def.col_type {
                     Some(data_type) => {
                         let type_name = data_type.name.as_str().to_uppercase();
@@ -309,10 +463,21 @@ fn create_table(
                 } else if primary_key_column_names.contains(&name) {
                     primary_key = true;
                 }
+                let default = col_def
+                    .constraints
+                    .iter()
+                    .find_map(|c| match &c.constraint {
+                        sqlite3_parser::ast::ColumnConstraint::Default(expr) => {
+                            ColumnDefault::from_expr(expr)
+                        }
+                        _ => None,
+                    });
                 cols.push(Column {
                     name: normalize_ident(&name),
                     ty,
                     primary_key,
+                    is_boolean,
+                    default,
                 });
             }
             if options.contains(TableOptions::WITHOUT_ROWID) {
@@ -353,6 +518,84 @@ pub struct Column {
     pub name: String,
     pub ty: Type,
     pub primary_key: bool,
+    /// Whether the column is declared as `BOOLEAN`. Booleans have numeric affinity
+    /// and are stored as the integers 0 and 1.
+    pub is_boolean: bool,
+    /// The `DEFAULT` clause of the column, if it is a literal.
+    pub default: Option<ColumnDefault>,
+}
+
+/// A literal column default.
+///
+/// Besides filling in omitted values on insert, the default is the value of the
+/// column in rows written before it was added with `ALTER TABLE ADD COLUMN`: such
+/// rows have records that are shorter than the column list.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnDefault {
+    Value(OwnedValue),
+    CurrentTime,
+    CurrentDate,
+    CurrentTimestamp,
+}
+
+impl ColumnDefault {
+    /// Parses a `DEFAULT` expression. Only literals, optionally signed or
+    /// parenthesized, are supported.
+    pub fn from_expr(expr: &Expr) -> Option<Self> {
+        match expr {
+            Expr::Literal(Literal::Numeric(value)) => Some(Self::Value(parse_numeric(value)?)),
+            Expr::Literal(Literal::String(value)) => {
+                let value = value
+                    .strip_prefix('\'')
+                    .and_then(|v| v.strip_suffix('\''))
+                    .unwrap_or(value);
+                Some(Self::Value(OwnedValue::Text(Rc::new(
+                    value.replace("''", "'"),
+                ))))
+            }
+            Expr::Literal(Literal::Null) => Some(Self::Value(OwnedValue::Null)),
+            Expr::Literal(Literal::CurrentTime) => Some(Self::CurrentTime),
+            Expr::Literal(Literal::CurrentDate) => Some(Self::CurrentDate),
+            Expr::Literal(Literal::CurrentTimestamp) => Some(Self::CurrentTimestamp),
+            Expr::Unary(UnaryOperator::Positive, expr) => Self::from_expr(expr),
+            Expr::Unary(UnaryOperator::Negative, expr) => match Self::from_expr(expr)? {
+                Self::Value(OwnedValue::Integer(i)) => {
+                    Some(Self::Value(OwnedValue::Integer(i.checked_neg()?)))
+                }
+                Self::Value(OwnedValue::Float(f)) => Some(Self::Value(OwnedValue::Float(-f))),
+                _ => None,
+            },
+            Expr::Parenthesized(exprs) if exprs.len() == 1 => Self::from_expr(&exprs[0]),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of the default, evaluating the current time in UTC for the
+    /// time keywords.
+    pub fn value(&self) -> OwnedValue {
+        let format = match self {
+            Self::Value(value) => return value.clone(),
+            Self::CurrentTime => "%H:%M:%S",
+            Self::CurrentDate => "%Y-%m-%d",
+            Self::CurrentTimestamp => "%Y-%m-%d %H:%M:%S",
+        };
+        OwnedValue::Text(Rc::new(chrono::Utc::now().format(format).to_string()))
+    }
+}
+
+fn parse_numeric(value: &str) -> Option<OwnedValue> {
+    if let Ok(i) = value.parse::<i64>() {
+        return Some(OwnedValue::Integer(i));
+    }
+    if let Some(hex) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        return u64::from_str_radix(hex, 16)
+            .ok()
+            .map(|i| OwnedValue::Integer(i as i64));
+    }
+    value.parse::<f64>().ok().map(OwnedValue::Float)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -390,26 +633,36 @@ pub fn sqlite_schema_table() -> BTreeTable {
                 name: "type".to_string(),
                 ty: Type::Text,
                 primary_key: false,
+                is_boolean: false,
+                default: None,
             },
             Column {
                 name: "name".to_string(),
                 ty: Type::Text,
                 primary_key: false,
+                is_boolean: false,
+                default: None,
             },
             Column {
                 name: "tbl_name".to_string(),
                 ty: Type::Text,
                 primary_key: false,
+                is_boolean: false,
+                default: None,
             },
             Column {
                 name: "rootpage".to_string(),
                 ty: Type::Integer,
                 primary_key: false,
+                is_boolean: false,
+                default: None,
             },
             Column {
                 name: "sql".to_string(),
                 ty: Type::Text,
                 primary_key: false,
+                is_boolean: false,
+                default: None,
             },
         ],
     }
@@ -700,4 +953,156 @@ mod tests {
         let actual = sqlite_schema_table().to_sql();
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    pub fn test_resolve_rowid_alias() -> Result<()> {
+        let mut schema = Schema::new();
+        let sql = r#"CREATE TABLE t1 (a INTEGER PRIMARY KEY, b INTEGER);"#;
+        schema.add_table(Rc::new(BTreeTable::from_sql(sql, 2)?));
+        assert_eq!(
+            schema.resolve_rowid_alias("t1", "a", &OwnedValue::Integer(42)),
+            Some(42)
+        );
+        assert_eq!(
+            schema.resolve_rowid_alias("t1", "b", &OwnedValue::Integer(42)),
+            None,
+            "column 'b' is not a rowid alias"
+        );
+        assert_eq!(
+            schema.resolve_rowid_alias("t1", "a", &OwnedValue::Text(Rc::new("42".to_string()))),
+            None,
+            "only integer values select a rowid"
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_schema_with_interior_page1() {
+        let sql = (0..200)
+            .map(|i| format!("CREATE TABLE t{i} (x INTEGER PRIMARY KEY, v TEXT); CREATE INDEX t{i}_v ON t{i} (v);"))
+            .collect::<String>();
+        let (_dir, db) = crate::storage::btree::tests::open_test_database(&format!(
+            "PRAGMA page_size = 1024; {sql}"
+        ));
+        let page = db.pager.read_page_blocking(1).unwrap();
+        assert_eq!(
+            page.borrow().contents.as_ref().unwrap().page_type(),
+            crate::storage::sqlite3_ondisk::PageType::TableInterior
+        );
+        let schema = db.schema.borrow();
+        for i in 0..200 {
+            assert!(schema.get_table(&format!("t{i}")).is_some());
+            assert_eq!(schema.indexes[&format!("t{i}")][0].name, format!("t{i}_v"));
+        }
+    }
+
+    #[test]
+    pub fn test_views_and_triggers() {
+        let (_dir, db) = crate::storage::btree::tests::open_test_database(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             CREATE TABLE log (x INTEGER);
+             CREATE VIEW \"Big Rows\" AS SELECT * FROM t WHERE x > 10;
+             CREATE TRIGGER t_insert AFTER INSERT ON t BEGIN INSERT INTO log VALUES (new.x); END;",
+        );
+        let schema = db.schema.borrow();
+        let view = &schema.views["big rows"];
+        assert_eq!(view.entry_type, SchemaEntryType::View);
+        assert_eq!(view.root_page, 0);
+        assert!(view.sql.starts_with("CREATE VIEW"));
+        let trigger = &schema.triggers["t_insert"];
+        assert_eq!(trigger.entry_type, SchemaEntryType::Trigger);
+        assert_eq!(trigger.table_name, "t");
+        assert_eq!(trigger.root_page, 0);
+        assert!(schema.get_table("Big Rows").is_none());
+        assert!(schema.get_table("t_insert").is_none());
+        assert!(!schema.root_pages().contains(&0));
+    }
+
+    #[test]
+    pub fn test_read_schema_entries() {
+        let (_dir, db) = crate::storage::btree::tests::open_test_database(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT UNIQUE);
+             CREATE INDEX t_v ON t (v);
+             CREATE VIEW big AS SELECT * FROM t WHERE x > 10;",
+        );
+        let entries = db.schema_entries().unwrap();
+        let summary = entries
+            .iter()
+            .map(|e| (e.entry_type, e.name.as_str(), e.table_name.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (SchemaEntryType::Table, "t", "t"),
+                (SchemaEntryType::Index, "sqlite_autoindex_t_1", "t"),
+                (SchemaEntryType::Index, "t_v", "t"),
+                (SchemaEntryType::View, "big", "big"),
+            ]
+        );
+        let schema = db.schema.borrow();
+        assert_eq!(
+            entries[0].root_page,
+            schema.get_table("t").unwrap().root_page
+        );
+        assert!(entries[0].sql.starts_with("CREATE TABLE t"));
+        // Automatic indexes have a b-tree but no SQL.
+        assert!(entries[1].root_page > 0);
+        assert_eq!(entries[1].sql, "");
+        assert_eq!(entries[2].root_page, schema.indexes["t"][0].root_page);
+        assert_eq!(entries[3].root_page, 0);
+    }
+
+    #[test]
+    pub fn test_is_btree_root() {
+        let (_dir, db) = crate::storage::btree::tests::open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             CREATE INDEX t_x ON t (x);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO t SELECT i, zeroblob(500) FROM n;",
+        );
+        let schema = db.schema.borrow();
+        let root_page = schema.get_table("t").unwrap().root_page;
+        let index_root_page = schema.indexes["t"][0].root_page;
+        assert!(schema.is_btree_root(1));
+        assert!(schema.is_btree_root(root_page as u32));
+        assert!(schema.is_btree_root(index_root_page as u32));
+        let leaf_page = db.pager.read_page_blocking(root_page).unwrap();
+        let leaf_page = leaf_page
+            .borrow()
+            .contents
+            .as_ref()
+            .unwrap()
+            .rightmost_pointer();
+        assert!(!schema.is_btree_root(leaf_page.unwrap()));
+    }
+
+    #[test]
+    pub fn test_column_defaults() -> Result<()> {
+        let sql = r#"CREATE TABLE t1 (a INTEGER DEFAULT 5, b REAL DEFAULT -1.5, c TEXT DEFAULT 'it''s', d DEFAULT NULL, e TEXT DEFAULT CURRENT_TIMESTAMP, f INTEGER);"#;
+        let table = BTreeTable::from_sql(sql, 0)?;
+        let defaults = table
+            .columns
+            .iter()
+            .map(|c| c.default.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            defaults,
+            vec![
+                Some(ColumnDefault::Value(OwnedValue::Integer(5))),
+                Some(ColumnDefault::Value(OwnedValue::Float(-1.5))),
+                Some(ColumnDefault::Value(OwnedValue::Text(Rc::new(
+                    "it's".to_string()
+                )))),
+                Some(ColumnDefault::Value(OwnedValue::Null)),
+                Some(ColumnDefault::CurrentTimestamp),
+                None,
+            ]
+        );
+        let OwnedValue::Text(timestamp) = ColumnDefault::CurrentTimestamp.value() else {
+            panic!("CURRENT_TIMESTAMP should be text");
+        };
+        assert_eq!(timestamp.len(), "YYYY-MM-DD HH:MM:SS".len());
+        Ok(())
+    }
 }
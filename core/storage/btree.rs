@@ -1,19 +1,21 @@
-use log::debug;
+use log::{debug, warn};
 
 use crate::storage::pager::{Page, Pager};
 use crate::storage::sqlite3_ondisk::{
-    read_btree_cell, read_varint, write_varint, BTreeCell, DatabaseHeader, PageContent, PageType,
-    TableInteriorCell, TableLeafCell,
+    payload_thresholds, read_btree_cell, read_varint, write_varint, BTreeCell, DatabaseHeader,
+    PageContent, PageType, RecordOptions, RecordReader, TableInteriorCell, TableLeafCell,
 };
 use crate::types::{Cursor, CursorResult, OwnedRecord, OwnedValue, SeekKey, SeekOp};
-use crate::Result;
+use crate::{LimboError, Result};
 
 use std::cell::{Ref, RefCell};
+use std::collections::HashSet;
 use std::pin::Pin;
 use std::rc::Rc;
 
 use super::sqlite3_ondisk::{
-    write_varint_to_vec, IndexInteriorCell, IndexLeafCell, OverflowCell, DATABASE_HEADER_SIZE,
+    read_u32_at, read_varint_at, write_varint_to_vec, IndexInteriorCell, IndexLeafCell,
+    OverflowCell, DATABASE_HEADER_SIZE,
 };
 
 /*
@@ -73,6 +75,9 @@ struct WriteInfo {
     page_copy: RefCell<Option<PageContent>>, // this holds the copy a of a page needed for buffer references
 }
 
+/// Predicate deciding whether a row is yielded by a cursor, see [`BTreeCursor::filter`].
+pub type RecordFilter = Box<dyn Fn(&OwnedRecord) -> bool>;
+
 pub struct BTreeCursor {
     pager: Rc<Pager>,
     /// Page id of the root page used to go back up fast.
@@ -92,6 +97,8 @@ pub struct BTreeCursor {
     /// Page stack used to traverse the btree.
     /// Each cursor has a stack because each cursor traverses the btree independently.
     stack: PageStack,
+    /// Predicate rows must satisfy to be yielded by a scan.
+    filter: Option<RecordFilter>,
 }
 
 /// Stack of pages representing the tree traversal order.
@@ -138,9 +145,16 @@ impl BTreeCursor {
                 cell_indices: RefCell::new([0; BTCURSOR_MAX_DEPTH + 1]),
                 stack: RefCell::new([const { None }; BTCURSOR_MAX_DEPTH + 1]),
             },
+            filter: None,
         }
     }
 
+    /// Reads a record from a cell payload, decoding text in the database encoding.
+    fn read_record(&self, payload: &[u8]) -> Result<OwnedRecord> {
+        let options = RecordOptions::for_header(&self.database_header.borrow())?;
+        RecordReader::new(payload, options)?.record()
+    }
+
     fn is_empty_table(&mut self) -> Result<CursorResult<bool>> {
         let page = self.pager.read_page(self.root_page)?;
         let page = RefCell::borrow(&page);
@@ -190,6 +204,12 @@ impl BTreeCursor {
             let mem_page = mem_page_rc.borrow();
             let contents = mem_page.contents.as_ref().unwrap();
 
+            if contents.is_empty_leaf() {
+                // nothing to yield here, move on to the previous cell of the parent
+                self.stack.set_cell_index(-1);
+                continue;
+            }
+
             let cell_count = contents.cell_count();
             let cell_idx = if cell_idx >= cell_count {
                 self.stack.set_cell_index(cell_count as i32 - 1);
@@ -198,13 +218,15 @@ impl BTreeCursor {
                 cell_idx
             };
 
-            let cell = contents.cell_get(
-                cell_idx,
-                self.pager.clone(),
-                self.max_local(contents.page_type()),
-                self.min_local(contents.page_type()),
-                self.usable_space(),
-            )?;
+            let cell = contents
+                .cell_get(
+                    cell_idx,
+                    self.pager.clone(),
+                    self.max_local(contents.page_type()),
+                    self.min_local(contents.page_type()),
+                    self.usable_space(),
+                )
+                .map_err(|e| e.context(|| format!("on page {}", mem_page.id)))?;
 
             match cell {
                 BTreeCell::TableInteriorCell(TableInteriorCell {
@@ -221,8 +243,7 @@ impl BTreeCursor {
                     _rowid, _payload, ..
                 }) => {
                     self.stack.retreat();
-                    let record: OwnedRecord =
-                        crate::storage::sqlite3_ondisk::read_record(&_payload)?;
+                    let record: OwnedRecord = self.read_record(&_payload)?;
                     return Ok(CursorResult::Ok((Some(_rowid), Some(record))));
                 }
                 BTreeCell::IndexInteriorCell(_) => todo!(),
@@ -286,13 +307,15 @@ impl BTreeCursor {
             }
             assert!(cell_idx < contents.cell_count());
 
-            let cell = contents.cell_get(
-                cell_idx,
-                self.pager.clone(),
-                self.max_local(contents.page_type()),
-                self.min_local(contents.page_type()),
-                self.usable_space(),
-            )?;
+            let cell = contents
+                .cell_get(
+                    cell_idx,
+                    self.pager.clone(),
+                    self.max_local(contents.page_type()),
+                    self.min_local(contents.page_type()),
+                    self.usable_space(),
+                )
+                .map_err(|e| e.context(|| format!("on page {}", mem_page.id)))?;
             match &cell {
                 BTreeCell::TableInteriorCell(TableInteriorCell {
                     _left_child_page,
@@ -311,7 +334,7 @@ impl BTreeCursor {
                 }) => {
                     assert!(predicate.is_none());
                     self.stack.advance();
-                    let record = crate::storage::sqlite3_ondisk::read_record(_payload)?;
+                    let record = self.read_record(_payload)?;
                     return Ok(CursorResult::Ok((Some(*_rowid), Some(record))));
                 }
                 BTreeCell::IndexInteriorCell(IndexInteriorCell {
@@ -328,7 +351,7 @@ impl BTreeCursor {
                     self.going_upwards = false;
                     self.stack.advance();
 
-                    let record = crate::storage::sqlite3_ondisk::read_record(payload)?;
+                    let record = self.read_record(payload)?;
                     if predicate.is_none() {
                         let rowid = match record.values.last() {
                             Some(OwnedValue::Integer(rowid)) => *rowid as u64,
@@ -358,7 +381,7 @@ impl BTreeCursor {
                 }
                 BTreeCell::IndexLeafCell(IndexLeafCell { payload, .. }) => {
                     self.stack.advance();
-                    let record = crate::storage::sqlite3_ondisk::read_record(payload)?;
+                    let record = self.read_record(payload)?;
                     if predicate.is_none() {
                         let rowid = match record.values.last() {
                             Some(OwnedValue::Integer(rowid)) => *rowid as u64,
@@ -404,13 +427,15 @@ impl BTreeCursor {
             let contents = page.contents.as_ref().unwrap();
 
             for cell_idx in 0..contents.cell_count() {
-                let cell = contents.cell_get(
-                    cell_idx,
-                    self.pager.clone(),
-                    self.max_local(contents.page_type()),
-                    self.min_local(contents.page_type()),
-                    self.usable_space(),
-                )?;
+                let cell = contents
+                    .cell_get(
+                        cell_idx,
+                        self.pager.clone(),
+                        self.max_local(contents.page_type()),
+                        self.min_local(contents.page_type()),
+                        self.usable_space(),
+                    )
+                    .map_err(|e| e.context(|| format!("on page {}", page.id)))?;
                 match &cell {
                     BTreeCell::TableLeafCell(TableLeafCell {
                         _rowid: cell_rowid,
@@ -427,7 +452,7 @@ impl BTreeCursor {
                         };
                         self.stack.advance();
                         if found {
-                            let record = crate::storage::sqlite3_ondisk::read_record(payload)?;
+                            let record = self.read_record(payload)?;
                             return Ok(CursorResult::Ok((Some(*cell_rowid), Some(record))));
                         }
                     }
@@ -435,7 +460,7 @@ impl BTreeCursor {
                         let SeekKey::IndexKey(index_key) = key else {
                             unreachable!("index seek key should be a record");
                         };
-                        let record = crate::storage::sqlite3_ondisk::read_record(payload)?;
+                        let record = self.read_record(payload)?;
                         let found = match op {
                             SeekOp::GT => record > *index_key,
                             SeekOp::GE => record >= *index_key,
@@ -556,13 +581,16 @@ impl BTreeCursor {
 
             let mut found_cell = false;
             for cell_idx in 0..contents.cell_count() {
-                match &contents.cell_get(
-                    cell_idx,
-                    self.pager.clone(),
-                    self.max_local(contents.page_type()),
-                    self.min_local(contents.page_type()),
-                    self.usable_space(),
-                )? {
+                match &contents
+                    .cell_get(
+                        cell_idx,
+                        self.pager.clone(),
+                        self.max_local(contents.page_type()),
+                        self.min_local(contents.page_type()),
+                        self.usable_space(),
+                    )
+                    .map_err(|e| e.context(|| format!("on page {}", page.id)))?
+                {
                     BTreeCell::TableInteriorCell(TableInteriorCell {
                         _left_child_page,
                         _rowid,
@@ -600,7 +628,7 @@ impl BTreeCursor {
                         let SeekKey::IndexKey(index_key) = key else {
                             unreachable!("index seek key should be a record");
                         };
-                        let record = crate::storage::sqlite3_ondisk::read_record(payload)?;
+                        let record = self.read_record(payload)?;
                         let target_leaf_page_is_in_the_left_subtree = match cmp {
                             SeekOp::GT => index_key < &record,
                             SeekOp::GE => index_key <= &record,
@@ -776,7 +804,7 @@ impl BTreeCursor {
 
         let maxpc = {
             let db_header = self.database_header.borrow();
-            let usable_space = (db_header.page_size - db_header.unused_space as u16) as usize;
+            let usable_space = db_header.usable_size();
             usable_space as u16
         };
 
@@ -982,8 +1010,7 @@ impl BTreeCursor {
                     contents.write_u16(BTREE_HEADER_OFFSET_CELL_COUNT, 0);
 
                     let db_header = RefCell::borrow(&self.database_header);
-                    let cell_content_area_start =
-                        db_header.page_size - db_header.unused_space as u16;
+                    let cell_content_area_start = db_header.usable_size() as u16;
                     contents.write_u16(BTREE_HEADER_OFFSET_CELL_CONTENT, cell_content_area_start);
 
                     contents.write_u8(BTREE_HEADER_OFFSET_FRAGMENTED, 0);
@@ -1270,7 +1297,7 @@ impl BTreeCursor {
 
         page_ref.write_u16(BTREE_HEADER_OFFSET_CELL_CONTENT, top as u16);
 
-        let usable_space = (db_header.page_size - db_header.unused_space as u16) as usize;
+        let usable_space = db_header.usable_size();
         assert!(top + amount <= usable_space);
         top as u16
     }
@@ -1279,7 +1306,7 @@ impl BTreeCursor {
         log::debug!("defragment_page");
         let cloned_page = page.clone();
         // TODO(pere): usable space should include offset probably
-        let usable_space = (db_header.page_size - db_header.unused_space as u16) as u64;
+        let usable_space = db_header.usable_size() as u64;
         let mut cbrk = usable_space;
 
         // TODO: implement fast algorithm
@@ -1372,7 +1399,7 @@ impl BTreeCursor {
         // TODO(pere): maybe free space is not calculated correctly with offset
         let buf = page.as_ptr();
 
-        let usable_space = (db_header.page_size - db_header.unused_space as u16) as usize;
+        let usable_space = db_header.usable_size();
         let mut first_byte_in_cell_content = page.cell_content_area();
         if first_byte_in_cell_content == 0 {
             first_byte_in_cell_content = u16::MAX;
@@ -1438,7 +1465,8 @@ impl BTreeCursor {
         ));
         // TODO: make record raw from start, having to serialize is not good
         let mut record_buf = Vec::new();
-        record.serialize(&mut record_buf);
+        let schema_format = self.database_header.borrow().schema_format();
+        record.serialize_with_schema_format(&mut record_buf, schema_format);
 
         // fill in header
         if matches!(page_type, PageType::TableLeaf) {
@@ -1515,28 +1543,16 @@ impl BTreeCursor {
     }
 
     fn max_local(&self, page_type: PageType) -> usize {
-        let usable_space = self.usable_space();
-        match page_type {
-            PageType::IndexInterior | PageType::TableInterior => {
-                (usable_space - 12) * 64 / 255 - 23
-            }
-            PageType::IndexLeaf | PageType::TableLeaf => usable_space - 35,
-        }
+        payload_thresholds(&page_type, self.usable_space()).0
     }
 
     fn min_local(&self, page_type: PageType) -> usize {
-        let usable_space = self.usable_space();
-        match page_type {
-            PageType::IndexInterior | PageType::TableInterior => {
-                (usable_space - 12) * 32 / 255 - 23
-            }
-            PageType::IndexLeaf | PageType::TableLeaf => (usable_space - 12) * 32 / 255 - 23,
-        }
+        payload_thresholds(&page_type, self.usable_space()).1
     }
 
     fn usable_space(&self) -> usize {
         let db_header = RefCell::borrow(&self.database_header);
-        (db_header.page_size - db_header.unused_space as u16) as usize
+        db_header.usable_size()
     }
 
     fn find_cell(&self, page: &PageContent, int_key: u64) -> usize {
@@ -1666,7 +1682,7 @@ fn find_free_cell(page_ref: &PageContent, db_header: Ref<DatabaseHeader>, amount
 
     let buf = page_ref.as_ptr();
 
-    let usable_space = (db_header.page_size - db_header.unused_space as u16) as usize;
+    let usable_space = db_header.usable_size();
     let maxpc = usable_space - amount;
     let mut found = false;
     while pc <= maxpc {
@@ -1706,7 +1722,12 @@ impl Cursor for BTreeCursor {
     fn rewind(&mut self) -> Result<CursorResult<()>> {
         self.move_to_root();
 
-        let (rowid, record) = return_if_io!(self.get_next_record(None));
+        let (rowid, record) = loop {
+            let (rowid, record) = return_if_io!(self.get_next_record(None));
+            if self.matches_filter(&record) {
+                break (rowid, record);
+            }
+        };
         self.rowid.replace(rowid);
         self.record.replace(record);
         Ok(CursorResult::Ok(()))
@@ -1720,21 +1741,27 @@ impl Cursor for BTreeCursor {
     }
 
     fn next(&mut self) -> Result<CursorResult<()>> {
-        let (rowid, record) = return_if_io!(self.get_next_record(None));
+        let (rowid, record) = loop {
+            let (rowid, record) = return_if_io!(self.get_next_record(None));
+            if self.matches_filter(&record) {
+                break (rowid, record);
+            }
+        };
         self.rowid.replace(rowid);
         self.record.replace(record);
         Ok(CursorResult::Ok(()))
     }
 
     fn prev(&mut self) -> Result<CursorResult<()>> {
-        match self.get_prev_record()? {
-            CursorResult::Ok((rowid, record)) => {
-                self.rowid.replace(rowid);
-                self.record.replace(record);
-                Ok(CursorResult::Ok(()))
+        let (rowid, record) = loop {
+            let (rowid, record) = return_if_io!(self.get_prev_record());
+            if self.matches_filter(&record) {
+                break (rowid, record);
             }
-            CursorResult::IO => Ok(CursorResult::IO),
-        }
+        };
+        self.rowid.replace(rowid);
+        self.record.replace(record);
+        Ok(CursorResult::Ok(()))
     }
 
     fn wait_for_completion(&mut self) -> Result<()> {
@@ -1767,6 +1794,21 @@ impl Cursor for BTreeCursor {
             OwnedValue::Integer(i) => i,
             _ => unreachable!("btree tables are indexed by integers!"),
         };
+        if !self.database_header.borrow().can_write() {
+            return Err(LimboError::ReadOnly);
+        }
+        // Reject oversized values up front, before any overflow pages are allocated.
+        let max_length = self.pager.max_length();
+        for value in &_record.values {
+            let len = match value {
+                OwnedValue::Text(t) => t.len(),
+                OwnedValue::Blob(b) => b.len(),
+                _ => continue,
+            };
+            if len > max_length {
+                return Err(LimboError::TooBig(len, max_length));
+            }
+        }
         if !moved_before {
             return_if_io!(self.move_to(SeekKey::TableRowId(*int_key as u64), SeekOp::EQ));
         }
@@ -1805,13 +1847,16 @@ impl Cursor for BTreeCursor {
         if cell_idx >= contents.cell_count() {
             Ok(CursorResult::Ok(false))
         } else {
-            let equals = match &contents.cell_get(
-                cell_idx,
-                self.pager.clone(),
-                self.max_local(contents.page_type()),
-                self.min_local(contents.page_type()),
-                self.usable_space(),
-            )? {
+            let equals = match &contents
+                .cell_get(
+                    cell_idx,
+                    self.pager.clone(),
+                    self.max_local(contents.page_type()),
+                    self.min_local(contents.page_type()),
+                    self.usable_space(),
+                )
+                .map_err(|e| e.context(|| format!("on page {}", page.id)))?
+            {
                 BTreeCell::TableLeafCell(l) => l._rowid == int_key,
                 _ => unreachable!(),
             };
@@ -1834,6 +1879,794 @@ impl Cursor for BTreeCursor {
     }
 }
 
+impl BTreeCursor {
+    /// Runs a cursor operation to completion, driving the pager's I/O whenever the
+    /// operation yields.
+    fn run_to_completion<T>(
+        &mut self,
+        mut op: impl FnMut(&mut Self) -> Result<CursorResult<T>>,
+    ) -> Result<T> {
+        loop {
+            match op(self)? {
+                CursorResult::Ok(v) => return Ok(v),
+                CursorResult::IO => self.pager.io.run_once()?,
+            }
+        }
+    }
+
+    /// Restricts the rows yielded when moving the cursor to those whose record
+    /// satisfies `predicate`. Rejected rows are skipped during the scan.
+    pub fn filter(&mut self, predicate: RecordFilter) {
+        self.filter = Some(predicate);
+    }
+
+    fn matches_filter(&self, record: &Option<OwnedRecord>) -> bool {
+        match (&self.filter, record) {
+            (Some(filter), Some(record)) => filter(record),
+            _ => true,
+        }
+    }
+
+    /// Returns the row with rowid `rowid`, or `None` if the table has no such row.
+    ///
+    /// Each page on the way down is binary searched on the rowids of its cells, so
+    /// only one page per level is read and only the matching row is decoded. The
+    /// cursor position is left unchanged.
+    pub fn lookup_rowid(&self, rowid: i64) -> Result<Option<OwnedRecord>> {
+        let usable_size = self.database_header.borrow().usable_size();
+        let mut page_idx = self.root_page;
+        for _ in 0..=BTCURSOR_MAX_DEPTH {
+            let page = self.pager.read_page_blocking(page_idx)?;
+            let page = page.borrow();
+            let contents = page.contents.as_ref().unwrap();
+            let position = binary_search_cells(
+                contents,
+                self.pager.clone(),
+                usable_size,
+                |cell| match cell {
+                    BTreeCell::TableInteriorCell(TableInteriorCell { _rowid, .. })
+                    | BTreeCell::TableLeafCell(TableLeafCell { _rowid, .. }) => {
+                        Ok((_rowid as i64).cmp(&rowid))
+                    }
+                    _ => Err(LimboError::InternalError(format!(
+                        "page {} is not a table b-tree page",
+                        page_idx
+                    ))),
+                },
+            )?;
+            let (max_local, min_local) = payload_thresholds(&contents.page_type(), usable_size);
+            if contents.is_leaf() {
+                let Ok(idx) = position else {
+                    return Ok(None);
+                };
+                let cell = contents.cell_get(
+                    idx,
+                    self.pager.clone(),
+                    max_local,
+                    min_local,
+                    usable_size,
+                )?;
+                let BTreeCell::TableLeafCell(TableLeafCell { _payload, .. }) = cell else {
+                    unreachable!("a table leaf page holds table leaf cells");
+                };
+                return Ok(Some(self.read_record(&_payload)?));
+            }
+            // The first cell whose rowid is not below the one sought has the rowid
+            // in its left child.
+            let (Ok(idx) | Err(idx)) = position;
+            page_idx = if idx < contents.cell_count() {
+                let cell = contents.cell_get(
+                    idx,
+                    self.pager.clone(),
+                    max_local,
+                    min_local,
+                    usable_size,
+                )?;
+                let BTreeCell::TableInteriorCell(TableInteriorCell {
+                    _left_child_page, ..
+                }) = cell
+                else {
+                    unreachable!("a table interior page holds table interior cells");
+                };
+                _left_child_page as usize
+            } else {
+                contents.rightmost_pointer().unwrap() as usize
+            };
+        }
+        crate::bail_corrupt_error!(
+            "Table b-tree rooted at page {} is deeper than {} levels",
+            self.root_page,
+            BTCURSOR_MAX_DEPTH
+        );
+    }
+
+    /// Returns the rowids of all rows in the table, in ascending order.
+    ///
+    /// Only the rowid varint of each leaf cell is read; payloads are skipped, which
+    /// makes this much cheaper than a full scan. A page reached twice or a tree
+    /// deeper than [`BTCURSOR_MAX_DEPTH`] levels is reported as corrupt.
+    pub fn all_rowids(&self) -> Result<Vec<i64>> {
+        let mut rowids = Vec::new();
+        let mut visited = HashSet::new();
+        self.collect_rowids(self.root_page, 0, &mut visited, &mut rowids)?;
+        Ok(rowids)
+    }
+
+    fn collect_rowids(
+        &self,
+        page_idx: usize,
+        depth: usize,
+        visited: &mut HashSet<usize>,
+        rowids: &mut Vec<i64>,
+    ) -> Result<()> {
+        if depth > BTCURSOR_MAX_DEPTH {
+            crate::bail_corrupt_error!(
+                "Table b-tree rooted at page {} is deeper than {} levels",
+                self.root_page,
+                BTCURSOR_MAX_DEPTH
+            );
+        }
+        if !visited.insert(page_idx) {
+            crate::bail_corrupt_error!(
+                "Page {} is referenced more than once in the b-tree rooted at page {}",
+                page_idx,
+                self.root_page
+            );
+        }
+        let page = self.pager.read_page_blocking(page_idx)?;
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        let page_type = contents.page_type();
+        let buf = contents.as_ptr();
+        for cell_idx in 0..contents.cell_count() {
+            let pos = read_cell_pointer(contents, page_idx, cell_idx)?;
+            match page_type {
+                PageType::TableInterior => {
+                    let left_child_page = read_u32_at(buf, pos)?;
+                    self.collect_rowids(left_child_page as usize, depth + 1, visited, rowids)?;
+                }
+                PageType::TableLeaf => {
+                    let (_, payload_size_len) = read_varint_at(buf, pos)?;
+                    let (rowid, _) = read_varint_at(buf, pos + payload_size_len)?;
+                    // Negative rowids are stored as their two's complement.
+                    rowids.push(rowid as i64);
+                }
+                PageType::IndexInterior | PageType::IndexLeaf => {
+                    return Err(LimboError::InternalError(format!(
+                        "page {} is not a table b-tree page",
+                        page_idx
+                    )));
+                }
+            }
+        }
+        if let Some(rightmost_pointer) = contents.rightmost_pointer() {
+            self.collect_rowids(rightmost_pointer as usize, depth + 1, visited, rowids)?;
+        }
+        Ok(())
+    }
+
+    /// Reads every row reachable from the root page on a best-effort basis.
+    ///
+    /// Pages with an invalid page type and cells that fail to parse are logged
+    /// and skipped, so a damaged b-tree yields whatever rows can be salvaged.
+    pub fn recover_rows(&self) -> Vec<OwnedRecord> {
+        let mut rows = Vec::new();
+        let mut visited = HashSet::new();
+        self.recover_page(self.root_page, &mut visited, &mut rows);
+        rows
+    }
+
+    fn recover_page(
+        &self,
+        page_idx: usize,
+        visited: &mut HashSet<usize>,
+        rows: &mut Vec<OwnedRecord>,
+    ) {
+        let database_size = self.database_header.borrow().database_size as usize;
+        if page_idx == 0 || page_idx > database_size || !visited.insert(page_idx) {
+            warn!("recover: skipping invalid page reference {}", page_idx);
+            return;
+        }
+        let page = match self.pager.read_page_blocking(page_idx) {
+            Ok(page) => page,
+            Err(err) => {
+                warn!("recover: skipping page {}: {}", page_idx, err);
+                return;
+            }
+        };
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        let Some(page_type) = contents.maybe_page_type() else {
+            warn!("recover: skipping page {} with invalid page type", page_idx);
+            return;
+        };
+        for cell_idx in 0..contents.cell_count() {
+            let cell = match contents.cell_get(
+                cell_idx,
+                self.pager.clone(),
+                self.max_local(page_type.clone()),
+                self.min_local(page_type.clone()),
+                self.usable_space(),
+            ) {
+                Ok(cell) => cell,
+                Err(err) => {
+                    warn!(
+                        "recover: skipping cell {} on page {}: {}",
+                        cell_idx, page_idx, err
+                    );
+                    continue;
+                }
+            };
+            let payload = match &cell {
+                BTreeCell::TableInteriorCell(TableInteriorCell {
+                    _left_child_page, ..
+                }) => {
+                    self.recover_page(*_left_child_page as usize, visited, rows);
+                    continue;
+                }
+                BTreeCell::IndexInteriorCell(IndexInteriorCell {
+                    left_child_page,
+                    payload,
+                    ..
+                }) => {
+                    self.recover_page(*left_child_page as usize, visited, rows);
+                    payload
+                }
+                BTreeCell::TableLeafCell(TableLeafCell { _payload, .. }) => _payload,
+                BTreeCell::IndexLeafCell(IndexLeafCell { payload, .. }) => payload,
+            };
+            match self.read_record(payload) {
+                Ok(record) => rows.push(record),
+                Err(err) => {
+                    warn!(
+                        "recover: skipping cell {} on page {}: {}",
+                        cell_idx, page_idx, err
+                    );
+                }
+            }
+        }
+        if let Some(rightmost_pointer) = contents.rightmost_pointer() {
+            self.recover_page(rightmost_pointer as usize, visited, rows);
+        }
+    }
+}
+
+/// Iterator returned by [`merge_scan`].
+pub struct MergeScan {
+    left: std::iter::Peekable<TableScan>,
+    right: std::iter::Peekable<TableScan>,
+}
+
+/// Scans two table b-trees in lockstep, pairing up rows with equal rowids.
+///
+/// Both cursors walk their tables in rowid order, so this is the building block for a
+/// sort-merge join on rowid. Rows that only exist on one side are emitted with `None`
+/// on the other side, which gives full outer join semantics.
+pub fn merge_scan(left: BTreeCursor, right: BTreeCursor) -> MergeScan {
+    MergeScan {
+        left: scan_table(left).peekable(),
+        right: scan_table(right).peekable(),
+    }
+}
+
+impl MergeScan {
+    /// Returns the rowid of the next row of `scan` without consuming it.
+    fn peek_rowid(scan: &mut std::iter::Peekable<TableScan>) -> Result<Option<u64>> {
+        match scan.peek() {
+            Some(Ok((rowid, _))) => Ok(Some(*rowid)),
+            Some(Err(_)) => match scan.next() {
+                Some(Err(e)) => Err(e),
+                _ => unreachable!(),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Consumes the next row of `scan`, which [`Self::peek_rowid`] found.
+    fn take_record(scan: &mut std::iter::Peekable<TableScan>) -> Option<OwnedRecord> {
+        match scan.next() {
+            Some(Ok((_, record))) => Some(record),
+            _ => unreachable!(),
+        }
+    }
+
+    fn advance(&mut self) -> Result<Option<(Option<OwnedRecord>, Option<OwnedRecord>)>> {
+        let left_rowid = Self::peek_rowid(&mut self.left)?;
+        let right_rowid = Self::peek_rowid(&mut self.right)?;
+        let (advance_left, advance_right) = match (left_rowid, right_rowid) {
+            (None, None) => return Ok(None),
+            (Some(_), None) => (true, false),
+            (None, Some(_)) => (false, true),
+            (Some(l), Some(r)) => (l <= r, r <= l),
+        };
+        let left_record = if advance_left {
+            Self::take_record(&mut self.left)
+        } else {
+            None
+        };
+        let right_record = if advance_right {
+            Self::take_record(&mut self.right)
+        } else {
+            None
+        };
+        Ok(Some((left_record, right_record)))
+    }
+}
+
+impl Iterator for MergeScan {
+    type Item = Result<(Option<OwnedRecord>, Option<OwnedRecord>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.advance().transpose()
+    }
+}
+
+/// Iterator returned by [`index_join_scan`].
+pub struct IndexJoinScan {
+    index: TableScan,
+    table: BTreeCursor,
+}
+
+/// Walks an index b-tree in key order and fetches the table row each index entry
+/// points to, yielding `(index_key, table_row)` pairs.
+///
+/// This is the access pattern for `ORDER BY` on an indexed column. The index key
+/// includes the trailing rowid.
+pub fn index_join_scan(index: BTreeCursor, table: BTreeCursor) -> IndexJoinScan {
+    IndexJoinScan {
+        index: scan_table(index),
+        table,
+    }
+}
+
+impl IndexJoinScan {
+    fn advance(&mut self) -> Result<Option<(OwnedRecord, OwnedRecord)>> {
+        let Some((rowid, index_key)) = self.index.advance()? else {
+            return Ok(None);
+        };
+        let (_, table_row) = self
+            .table
+            .run_to_completion(|c| c.seek(SeekKey::TableRowId(rowid), SeekOp::EQ))?;
+        let Some(table_row) = table_row else {
+            return Err(LimboError::Corrupt(format!(
+                "index entry refers to missing rowid {}",
+                rowid
+            )));
+        };
+        Ok(Some((index_key, table_row)))
+    }
+}
+
+impl Iterator for IndexJoinScan {
+    type Item = Result<(OwnedRecord, OwnedRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.advance().transpose()
+    }
+}
+
+/// Iterator returned by [`scan_table`].
+///
+/// The other scans over a cursor are built on this one, which moves the cursor to
+/// the first row on the first call and to the next row on each later one.
+pub struct TableScan {
+    cursor: BTreeCursor,
+    started: bool,
+}
+
+/// Scans a table b-tree in rowid order, yielding `(rowid, record)` for each row.
+///
+/// The cursor descends interior pages through their child pointers and right-most
+/// pointers, so this covers every leaf of the b-tree.
+pub fn scan_table(cursor: BTreeCursor) -> TableScan {
+    TableScan {
+        cursor,
+        started: false,
+    }
+}
+
+impl TableScan {
+    fn advance(&mut self) -> Result<Option<(u64, OwnedRecord)>> {
+        if !self.started {
+            self.started = true;
+            self.cursor.run_to_completion(|c| c.rewind())?;
+        } else {
+            self.cursor.run_to_completion(|c| c.next())?;
+        }
+        let Some(rowid) = *self.cursor.rowid.borrow() else {
+            return Ok(None);
+        };
+        let record = self.cursor.record.borrow().clone().unwrap();
+        Ok(Some((rowid, record)))
+    }
+}
+
+impl Iterator for TableScan {
+    type Item = Result<(u64, OwnedRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.advance().transpose()
+    }
+}
+
+/// Iterator returned by [`scan_with_page`].
+pub struct PageScan {
+    scan: TableScan,
+    change_check: Option<ChangeCheck>,
+}
+
+/// Detects writes to the database file while a scan is in progress.
+struct ChangeCheck {
+    change_counter: u32,
+    interval: usize,
+    rows: usize,
+}
+
+/// Scans a table b-tree in rowid order, yielding `(page_idx, rowid, record)` for
+/// each row, where `page_idx` is the page holding the row's cell.
+///
+/// This is meant for tooling that inspects how rows are laid out across pages.
+pub fn scan_with_page(cursor: BTreeCursor) -> PageScan {
+    PageScan {
+        scan: scan_table(cursor),
+        change_check: None,
+    }
+}
+
+impl PageScan {
+    /// Makes the scan fail with `LimboError::DatabaseChangedDuringScan` if the file
+    /// change counter moves on, so that the caller can retry on a fresh snapshot.
+    ///
+    /// The change counter is read now, and read again every `interval` rows, see
+    /// [`Pager::read_change_counter`].
+    pub fn check_changes(mut self, interval: usize) -> Result<Self> {
+        assert!(interval > 0);
+        self.change_check = Some(ChangeCheck {
+            change_counter: self.scan.cursor.pager.read_change_counter()?,
+            interval,
+            rows: 0,
+        });
+        Ok(self)
+    }
+
+    fn advance(&mut self) -> Result<Option<(usize, u64, OwnedRecord)>> {
+        if let Some(check) = &mut self.change_check {
+            check.rows += 1;
+            if check.rows % check.interval == 0
+                && self.scan.cursor.pager.read_change_counter()? != check.change_counter
+            {
+                return Err(LimboError::DatabaseChangedDuringScan);
+            }
+        }
+        let Some((rowid, record)) = self.scan.advance()? else {
+            return Ok(None);
+        };
+        // Reading a cell only advances the cell index, so the current page is still
+        // the one the row came from.
+        let page_idx = self.scan.cursor.stack.top().borrow().id;
+        Ok(Some((page_idx, rowid, record)))
+    }
+}
+
+impl Iterator for PageScan {
+    type Item = Result<(usize, u64, OwnedRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.advance().transpose()
+    }
+}
+
+/// A column of a [`ColumnBatch`].
+///
+/// A column whose non-NULL values all have the same type is typed, with NULLs as
+/// `None`. Columns mixing types, or holding only NULLs, are `Mixed`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnVector {
+    Integer(Vec<Option<i64>>),
+    Float(Vec<Option<f64>>),
+    Text(Vec<Option<Rc<String>>>),
+    Blob(Vec<Option<Rc<Vec<u8>>>>),
+    Mixed(Vec<OwnedValue>),
+}
+
+impl ColumnVector {
+    fn from_values(values: Vec<OwnedValue>) -> Self {
+        let mut non_null = values.iter().filter(|v| !matches!(v, OwnedValue::Null));
+        let Some(first) = non_null.next() else {
+            return Self::Mixed(values);
+        };
+        let kind = std::mem::discriminant(first);
+        if non_null.any(|v| std::mem::discriminant(v) != kind) {
+            return Self::Mixed(values);
+        }
+        macro_rules! typed {
+            ($variant:ident) => {
+                Self::$variant(
+                    values
+                        .into_iter()
+                        .map(|v| match v {
+                            OwnedValue::$variant(v) => Some(v),
+                            _ => None,
+                        })
+                        .collect(),
+                )
+            };
+        }
+        match first {
+            OwnedValue::Integer(_) => typed!(Integer),
+            OwnedValue::Float(_) => typed!(Float),
+            OwnedValue::Text(_) => typed!(Text),
+            OwnedValue::Blob(_) => typed!(Blob),
+            _ => Self::Mixed(values),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Integer(v) => v.len(),
+            Self::Float(v) => v.len(),
+            Self::Text(v) => v.len(),
+            Self::Blob(v) => v.len(),
+            Self::Mixed(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A batch of rows transposed into one vector per column, see [`scan_columnar`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnBatch {
+    /// The rowid of each row.
+    pub rowids: Vec<u64>,
+    /// The values of each column, as stored in the records. A rowid alias column
+    /// is stored as NULL, its values are the rowids.
+    pub columns: Vec<ColumnVector>,
+}
+
+impl ColumnBatch {
+    /// Returns the number of rows in the batch.
+    pub fn len(&self) -> usize {
+        self.rowids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rowids.is_empty()
+    }
+}
+
+/// Iterator returned by [`scan_columnar`].
+pub struct ColumnarScan {
+    scan: TableScan,
+    batch_size: usize,
+}
+
+/// Scans a table b-tree in rowid order, yielding its rows in batches of up to
+/// `batch_size` rows, transposed into columns.
+///
+/// This is the layout columnar formats such as Arrow expect. Rows with fewer
+/// values than the widest row of their batch, e.g. rows written before a column
+/// was added, are padded with NULLs.
+pub fn scan_columnar(cursor: BTreeCursor, batch_size: usize) -> ColumnarScan {
+    assert!(batch_size > 0);
+    ColumnarScan {
+        scan: scan_table(cursor),
+        batch_size,
+    }
+}
+
+impl ColumnarScan {
+    fn advance(&mut self) -> Result<Option<ColumnBatch>> {
+        let mut rowids = Vec::with_capacity(self.batch_size);
+        let mut rows = Vec::with_capacity(self.batch_size);
+        while rows.len() < self.batch_size {
+            let Some((rowid, record)) = self.scan.advance()? else {
+                break;
+            };
+            rowids.push(rowid);
+            rows.push(record.values);
+        }
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        let width = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+        let mut columns = vec![Vec::with_capacity(rows.len()); width];
+        for row in rows {
+            let len = row.len();
+            for (column, value) in columns.iter_mut().zip(row) {
+                column.push(value);
+            }
+            for column in &mut columns[len..] {
+                column.push(OwnedValue::Null);
+            }
+        }
+        Ok(Some(ColumnBatch {
+            rowids,
+            columns: columns.into_iter().map(ColumnVector::from_values).collect(),
+        }))
+    }
+}
+
+impl Iterator for ColumnarScan {
+    type Item = Result<ColumnBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.advance().transpose()
+    }
+}
+
+/// Iterator returned by [`dump_tables`].
+pub struct DumpScan {
+    tables: std::vec::IntoIter<(String, BTreeCursor)>,
+    current: Option<(String, TableScan)>,
+}
+
+/// Scans the given table b-trees one after another, each in rowid order, yielding
+/// `(table name, rowid, values)` for every row.
+///
+/// As in the record, the value of an `INTEGER PRIMARY KEY` column is NULL, the
+/// rowid standing in for it.
+pub fn dump_tables(tables: Vec<(String, BTreeCursor)>) -> DumpScan {
+    let mut tables = tables.into_iter();
+    DumpScan {
+        current: tables
+            .next()
+            .map(|(name, cursor)| (name, scan_table(cursor))),
+        tables,
+    }
+}
+
+impl DumpScan {
+    fn advance(&mut self) -> Result<Option<(String, i64, Vec<OwnedValue>)>> {
+        while let Some((name, scan)) = &mut self.current {
+            if let Some((rowid, record)) = scan.advance()? {
+                return Ok(Some((name.clone(), rowid as i64, record.values)));
+            }
+            self.current = self
+                .tables
+                .next()
+                .map(|(name, cursor)| (name, scan_table(cursor)));
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for DumpScan {
+    type Item = Result<(String, i64, Vec<OwnedValue>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.advance().transpose()
+    }
+}
+
+/// Returns the left-most leaf page of the b-tree rooted at `root_page`, i.e. the
+/// leaf holding the smallest key.
+///
+/// Interior pages are descended through the left child of their first cell, or
+/// through the right-most pointer if they have no cells.
+pub fn first_leaf(pager: &Pager, root_page: usize) -> Result<usize> {
+    let mut page_idx = root_page;
+    for _ in 0..=BTCURSOR_MAX_DEPTH {
+        let page = pager.read_page_blocking(page_idx)?;
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        // Only interior pages have a right-most pointer.
+        let Some(rightmost_pointer) = contents.rightmost_pointer() else {
+            return Ok(page_idx);
+        };
+        page_idx = if contents.cell_count() == 0 {
+            rightmost_pointer as usize
+        } else {
+            let pos = read_cell_pointer(contents, page_idx, 0)?;
+            read_u32_at(contents.as_ptr(), pos)? as usize
+        };
+    }
+    crate::bail_corrupt_error!(
+        "B-tree rooted at page {} is deeper than {} levels",
+        root_page,
+        BTCURSOR_MAX_DEPTH
+    );
+}
+
+/// Returns the index at which a cell for `rowid` goes in the table b-tree page
+/// `page` to keep its cells in rowid order.
+///
+/// On an interior page, a rowid equal to a divider key belongs to the left child
+/// of that divider, so its index is returned. On a leaf page, an equal rowid is
+/// already stored and fails with a constraint error.
+pub fn find_insert_position(
+    page: &PageContent,
+    pager: Rc<Pager>,
+    usable_size: usize,
+    rowid: i64,
+) -> Result<usize> {
+    let position = binary_search_cells(page, pager, usable_size, |cell| match cell {
+        BTreeCell::TableLeafCell(TableLeafCell { _rowid, .. })
+        | BTreeCell::TableInteriorCell(TableInteriorCell { _rowid, .. }) => {
+            Ok((_rowid as i64).cmp(&rowid))
+        }
+        _ => Err(LimboError::InternalError(format!(
+            "expected a table page, got {:?}",
+            page.page_type()
+        ))),
+    })?;
+    match position {
+        Ok(_) if page.is_leaf() => Err(LimboError::Constraint(format!(
+            "UNIQUE constraint failed: rowid {} already exists",
+            rowid
+        ))),
+        Ok(idx) | Err(idx) => Ok(idx),
+    }
+}
+
+/// Returns the index at which a cell for `key` goes in the index b-tree page
+/// `page` to keep its cells in key order. A key equal to an existing one goes
+/// before it. `header` is the header of the database the page belongs to.
+pub fn find_index_insert_position(
+    page: &PageContent,
+    pager: Rc<Pager>,
+    header: &DatabaseHeader,
+    key: &OwnedRecord,
+) -> Result<usize> {
+    let options = RecordOptions::for_header(header)?;
+    let position = binary_search_cells(page, pager, header.usable_size(), |cell| match cell {
+        BTreeCell::IndexLeafCell(IndexLeafCell { payload, .. })
+        | BTreeCell::IndexInteriorCell(IndexInteriorCell { payload, .. }) => {
+            Ok(RecordReader::new(&payload, options)?.record()?.cmp(key))
+        }
+        _ => Err(LimboError::InternalError(format!(
+            "expected an index page, got {:?}",
+            page.page_type()
+        ))),
+    })?;
+    let (Ok(idx) | Err(idx)) = position;
+    Ok(idx)
+}
+
+/// Reads the offset of cell `cell_idx` of page `page_idx` from its cell pointer
+/// array, failing with a corrupt error if the pointer is past the end of the page.
+fn read_cell_pointer(contents: &PageContent, page_idx: usize, cell_idx: usize) -> Result<usize> {
+    let (cell_pointers, _) = contents.cell_get_raw_pointer_region();
+    let pointer = cell_pointers + cell_idx * 2;
+    match contents.as_ptr().get(pointer..pointer + 2) {
+        Some(pointer) => Ok(u16::from_be_bytes([pointer[0], pointer[1]]) as usize),
+        None => crate::bail_corrupt_error!(
+            "Cell pointer {} of page {} is past the end of the page",
+            cell_idx,
+            page_idx
+        ),
+    }
+}
+
+/// Binary searches the cells of `page`, which `compare` orders against the key
+/// searched for, like [`slice::binary_search_by`]. When several cells are equal to
+/// the key, the first of them is found.
+fn binary_search_cells(
+    page: &PageContent,
+    pager: Rc<Pager>,
+    usable_size: usize,
+    compare: impl Fn(BTreeCell) -> Result<std::cmp::Ordering>,
+) -> Result<std::result::Result<usize, usize>> {
+    let (max_local, min_local) = payload_thresholds(&page.page_type(), usable_size);
+    let (mut low, mut high) = (0, page.cell_count());
+    let mut found = false;
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let cell = page.cell_get(mid, pager.clone(), max_local, min_local, usable_size)?;
+        match compare(cell)? {
+            std::cmp::Ordering::Less => low = mid + 1,
+            std::cmp::Ordering::Equal => {
+                found = true;
+                high = mid;
+            }
+            std::cmp::Ordering::Greater => high = mid,
+        }
+    }
+    Ok(if found { Ok(low) } else { Err(low) })
+}
+
 pub fn btree_init_page(
     page: &Rc<RefCell<Page>>,
     page_type: PageType,
@@ -1850,7 +2683,8 @@ pub fn btree_init_page(
     contents.write_u16(BTREE_HEADER_OFFSET_FREEBLOCK, 0);
     contents.write_u16(BTREE_HEADER_OFFSET_CELL_COUNT, 0);
 
-    let cell_content_area_start = db_header.page_size - db_header.unused_space as u16;
+    // As in SQLite, a content area starting at 65536 is stored as 0.
+    let cell_content_area_start = db_header.usable_size() as u16;
     contents.write_u16(BTREE_HEADER_OFFSET_CELL_CONTENT, cell_content_area_start);
 
     contents.write_u8(BTREE_HEADER_OFFSET_FRAGMENTED, 0);
@@ -1860,3 +2694,804 @@ pub fn btree_init_page(
 fn to_static_buf(buf: &[u8]) -> &'static [u8] {
     unsafe { std::mem::transmute::<&[u8], &'static [u8]>(buf) }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{Database, PlatformIO, IO};
+    use rstest::rstest;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    /// Creates a database file with SQLite by running `sql` and opens it with Limbo.
+    ///
+    /// The returned directory owns the database file and must be kept alive for as
+    /// long as the database is used.
+    pub(crate) fn open_test_database(sql: &str) -> (TempDir, Rc<Database>) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("test.db");
+        {
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            conn.execute_batch(sql).unwrap();
+        }
+        let db = Database::open_file(test_io(), path.to_str().unwrap()).unwrap();
+        (dir, db)
+    }
+
+    /// Creates an IO for a test database, so that the databases opened in a test
+    /// do not share pending reads.
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub(crate) fn test_io() -> Arc<dyn IO> {
+        Arc::new(PlatformIO::new().unwrap())
+    }
+
+    /// Opens a cursor over the b-tree of `table` in `db`.
+    pub(crate) fn table_cursor(db: &Database, table: &str) -> BTreeCursor {
+        let root_page = db.schema.borrow().get_table(table).unwrap().root_page;
+        BTreeCursor::new(db.pager.clone(), root_page, db.header.clone())
+    }
+
+    fn value_column(record: Option<OwnedRecord>) -> Option<String> {
+        record.map(|r| r.values[1].to_string())
+    }
+
+    #[test]
+    fn test_merge_scan() {
+        let (_dir, db) = open_test_database(
+            "CREATE TABLE l (x INTEGER PRIMARY KEY, v TEXT);
+             CREATE TABLE r (x INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO l VALUES (1, 'l1'), (2, 'l2'), (4, 'l4');
+             INSERT INTO r VALUES (2, 'r2'), (3, 'r3'), (4, 'r4');",
+        );
+        let rows = merge_scan(table_cursor(&db, "l"), table_cursor(&db, "r"))
+            .map(|row| {
+                let (l, r) = row.unwrap();
+                (value_column(l), value_column(r))
+            })
+            .collect::<Vec<_>>();
+        let s = |v: &str| Some(v.to_string());
+        assert_eq!(
+            rows,
+            vec![
+                (s("l1"), None),
+                (s("l2"), s("r2")),
+                (None, s("r3")),
+                (s("l4"), s("r4")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_corrupt_cell_error_has_location() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, 'a');",
+        );
+        let mut cursor = table_cursor(&db, "t");
+        let page = db.pager.read_page_blocking(2).unwrap();
+        {
+            let page = page.borrow();
+            let contents = page.contents.as_ref().unwrap();
+            // Point the first cell at the last byte of the page and make its
+            // payload size varint run past the end of the buffer.
+            let (cell_pointers, _) = contents.cell_get_raw_pointer_region();
+            contents.write_u16(cell_pointers, 4095);
+            contents.write_u8(4095, 0xff);
+        }
+        let err = cursor.run_to_completion(|c| c.rewind()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Corrupt database: Invalid varint at offset 4095 on page 2"
+        );
+    }
+
+    #[test]
+    fn test_large_text_round_trip() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, printf('%.5000c', 'a')), (2, printf('%.3000c', 'b'));",
+        );
+        let mut cursor = table_cursor(&db, "t");
+        cursor.run_to_completion(|c| c.rewind()).unwrap();
+        assert_eq!(
+            value_column(cursor.record().unwrap().clone()),
+            Some("a".repeat(5000))
+        );
+        cursor.run_to_completion(|c| c.next()).unwrap();
+        assert_eq!(
+            value_column(cursor.record().unwrap().clone()),
+            Some("b".repeat(3000))
+        );
+    }
+
+    #[test]
+    fn test_recover_rows_skips_corrupt_page() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 20)
+             INSERT INTO t SELECT i, zeroblob(1000) FROM n;",
+        );
+        let cursor = table_cursor(&db, "t");
+        assert_eq!(cursor.recover_rows().len(), 20);
+
+        let page = db.pager.read_page_blocking(3).unwrap();
+        let lost = {
+            let page = page.borrow();
+            let contents = page.contents.as_ref().unwrap();
+            assert_eq!(contents.page_type(), PageType::TableLeaf);
+            contents.write_u8(0, 0);
+            contents.cell_count()
+        };
+        let rows = cursor.recover_rows();
+        assert!(lost > 0);
+        assert_eq!(rows.len(), 20 - lost);
+    }
+
+    #[test]
+    fn test_index_join_scan() {
+        let (_dir, db) = open_test_database(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             CREATE INDEX t_v ON t (v);
+             INSERT INTO t VALUES (1, 'c'), (2, 'a'), (3, 'd'), (4, 'b');",
+        );
+        let index_root = db.schema.borrow().indexes.get("t").unwrap()[0].root_page;
+        let index = BTreeCursor::new(db.pager.clone(), index_root, db.header.clone());
+        let rows = index_join_scan(index, table_cursor(&db, "t"))
+            .map(|row| {
+                let (key, row) = row.unwrap();
+                assert_eq!(key.values[0], row.values[1]);
+                (key.values[0].to_string(), key.values[1].to_string())
+            })
+            .collect::<Vec<_>>();
+        let s = |v: &str, rowid: &str| (v.to_string(), rowid.to_string());
+        assert_eq!(
+            rows,
+            vec![s("a", "2"), s("b", "4"), s("c", "1"), s("d", "3")]
+        );
+    }
+
+    #[test]
+    fn test_insert_rejects_oversized_value() {
+        let (_dir, db) = open_test_database("CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);");
+        db.pager.set_max_length(100);
+        let mut cursor = table_cursor(&db, "t");
+        let record = OwnedRecord::new(vec![
+            OwnedValue::Null,
+            OwnedValue::Blob(Rc::new(vec![0; 101])),
+        ]);
+        let err = cursor
+            .run_to_completion(|c| c.insert(&OwnedValue::Integer(1), &record, false))
+            .unwrap_err();
+        assert!(matches!(err, LimboError::TooBig(101, 100)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_insert_64k_pages() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 65536;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);",
+        );
+        assert_eq!(db.pager.usable_size(), 65536);
+        assert!(!db.header.borrow().can_write());
+        let mut cursor = table_cursor(&db, "t");
+        let record = OwnedRecord::new(vec![
+            OwnedValue::Null,
+            OwnedValue::Blob(Rc::new(vec![0; 500])),
+        ]);
+        let err = cursor
+            .run_to_completion(|c| c.insert(&OwnedValue::Integer(1), &record, false))
+            .unwrap_err();
+        assert!(matches!(err, LimboError::ReadOnly), "{:?}", err);
+        let err = db
+            .connect()
+            .execute("INSERT INTO t VALUES (1, zeroblob(500))")
+            .unwrap_err();
+        assert!(matches!(err, LimboError::ReadOnly), "{:?}", err);
+        assert_eq!(db.scan_table("t").unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_scan_with_page() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 20)
+             INSERT INTO t SELECT i, zeroblob(1000) FROM n;",
+        );
+        let rows = scan_with_page(table_cursor(&db, "t"))
+            .map(|row| row.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows.iter().map(|(_, rowid, _)| *rowid).collect::<Vec<_>>(),
+            (1..=20).collect::<Vec<_>>()
+        );
+        // Rows of a leaf are reported together, and each leaf reports as many rows as
+        // it has cells.
+        let mut leaves: Vec<(usize, usize)> = Vec::new();
+        for (page_idx, _, _) in &rows {
+            match leaves.last_mut() {
+                Some((last, count)) if last == page_idx => *count += 1,
+                _ => leaves.push((*page_idx, 1)),
+            }
+        }
+        assert!(leaves.len() > 1);
+        for (page_idx, count) in leaves {
+            let page = db.pager.read_page_blocking(page_idx).unwrap();
+            let page = page.borrow();
+            let contents = page.contents.as_ref().unwrap();
+            assert_eq!(contents.page_type(), PageType::TableLeaf);
+            assert_eq!(contents.cell_count(), count, "page {}", page_idx);
+        }
+    }
+
+    #[test]
+    fn test_scan_columnar() {
+        let (_dir, db) = open_test_database(
+            "CREATE TABLE t (n INTEGER, v);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO t SELECT i, CASE WHEN i % 2 = 0 THEN 'even' ELSE i END FROM n;
+             UPDATE t SET n = NULL WHERE n = 3;",
+        );
+        let batches = scan_columnar(table_cursor(&db, "t"), 4)
+            .map(|batch| batch.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            batches.iter().map(|batch| batch.len()).collect::<Vec<_>>(),
+            vec![4, 4, 2]
+        );
+        for batch in &batches {
+            assert_eq!(batch.columns.len(), 2);
+            assert!(batch.columns.iter().all(|c| c.len() == batch.len()));
+        }
+        assert_eq!(batches[0].rowids, vec![1, 2, 3, 4]);
+        assert_eq!(
+            batches[0].columns[0],
+            ColumnVector::Integer(vec![Some(1), Some(2), None, Some(4)])
+        );
+        assert_eq!(
+            batches[0].columns[1],
+            ColumnVector::Mixed(vec![
+                OwnedValue::Integer(1),
+                OwnedValue::Text(Rc::new("even".to_string())),
+                OwnedValue::Integer(3),
+                OwnedValue::Text(Rc::new("even".to_string())),
+            ])
+        );
+    }
+
+    #[rstest]
+    #[case::beginning(5, Some(0))]
+    #[case::middle(25, Some(2))]
+    #[case::end(50, Some(4))]
+    #[case::duplicate(20, None)]
+    fn test_find_insert_position(#[case] rowid: i64, #[case] expected: Option<usize>) {
+        let (_dir, db) = open_test_database(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY);
+             INSERT INTO t VALUES (10), (20), (30), (40);",
+        );
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let page = db.pager.read_page_blocking(root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        let position =
+            find_insert_position(contents, db.pager.clone(), db.pager.usable_size(), rowid);
+        match expected {
+            Some(idx) => assert_eq!(position.unwrap(), idx),
+            None => assert_eq!(
+                position.unwrap_err().to_string(),
+                "Runtime error: UNIQUE constraint failed: rowid 20 already exists"
+            ),
+        }
+    }
+
+    #[test]
+    fn test_find_index_insert_position() {
+        let (_dir, db) = open_test_database(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             CREATE INDEX t_v ON t (v);
+             INSERT INTO t VALUES (1, 'b'), (2, 'd'), (3, 'd'), (4, 'f');",
+        );
+        let root_page = db.schema.borrow().indexes["t"][0].root_page;
+        let page = db.pager.read_page_blocking(root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        let key = |v: &str, rowid: i64| {
+            OwnedRecord::new(vec![
+                OwnedValue::Text(Rc::new(v.to_string())),
+                OwnedValue::Integer(rowid),
+            ])
+        };
+        let position = |key: OwnedRecord| {
+            find_index_insert_position(contents, db.pager.clone(), &db.header.borrow(), &key)
+                .unwrap()
+        };
+        assert_eq!(position(key("a", 9)), 0);
+        assert_eq!(position(key("d", 0)), 1);
+        assert_eq!(position(key("d", 3)), 2);
+        assert_eq!(position(key("e", 5)), 3);
+        assert_eq!(position(key("g", 5)), 4);
+
+        let table_root = db.schema.borrow().get_table("t").unwrap().root_page;
+        let table_page = db.pager.read_page_blocking(table_root).unwrap();
+        let table_page = table_page.borrow();
+        let table_contents = table_page.contents.as_ref().unwrap();
+        assert!(find_index_insert_position(
+            table_contents,
+            db.pager.clone(),
+            &db.header.borrow(),
+            &key("a", 1)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_reserved_bytes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("test.db");
+        {
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            conn.execute_batch("PRAGMA page_size = 1024;").unwrap();
+            // Reserve 40 bytes at the end of every page, as encryption extensions do.
+            // The bindings predate SQLITE_FCNTL_RESERVE_BYTES.
+            const SQLITE_FCNTL_RESERVE_BYTES: std::ffi::c_int = 38;
+            let mut reserved_bytes: std::ffi::c_int = 40;
+            let rc = unsafe {
+                rusqlite::ffi::sqlite3_file_control(
+                    conn.handle(),
+                    c"main".as_ptr(),
+                    SQLITE_FCNTL_RESERVE_BYTES,
+                    &mut reserved_bytes as *mut std::ffi::c_int as *mut std::ffi::c_void,
+                )
+            };
+            assert_eq!(rc, rusqlite::ffi::SQLITE_OK);
+            conn.execute_batch(
+                "CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+                 WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+                 INSERT INTO t SELECT i, printf('%.*c', i * 7 % 3000, 'a') FROM n;",
+            )
+            .unwrap();
+        }
+        let db = Database::open_file(test_io(), path.to_str().unwrap()).unwrap();
+        assert_eq!(db.header.borrow().unused_space, 40);
+        assert_eq!(db.pager.usable_size(), 984);
+
+        let rows = db
+            .scan_table("t")
+            .unwrap()
+            .map(|row| {
+                let (rowid, record) = row.unwrap();
+                (rowid, value_column(Some(record)).unwrap())
+            })
+            .collect::<Vec<_>>();
+        let expected = (1..=500)
+            .map(|i| (i, "a".repeat((i * 7 % 3000) as usize)))
+            .collect::<Vec<_>>();
+        assert_eq!(rows, expected);
+        assert_eq!(db.integrity_check().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_lookup_rowid() {
+        use crate::storage::database::{FileStorage, ReadQuota};
+        use crate::storage::wal::WalFile;
+        let (dir, _db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10000)
+             INSERT INTO t SELECT i * 2, 'row ' || (i * 2) FROM n;
+             INSERT INTO t VALUES (-5, 'row -5');",
+        );
+        let path = dir.path().join("test.db");
+        let io = test_io();
+        let file = io
+            .open_file(path.to_str().unwrap(), crate::io::OpenFlags::None, false)
+            .unwrap();
+        let storage = Rc::new(ReadQuota::new(Rc::new(FileStorage::new(file)), usize::MAX));
+        let wal = Rc::new(RefCell::new(WalFile::new(
+            io.clone(),
+            format!("{}-wal", path.to_str().unwrap()),
+            1024,
+        )));
+        let db = Database::open(io, storage.clone(), wal).unwrap();
+        let cursor = table_cursor(&db, "t");
+
+        for rowid in [-5, 2, 2000, 9998, 20000] {
+            let before = storage.used();
+            let record = cursor.lookup_rowid(rowid).unwrap();
+            assert_eq!(value_column(record), Some(format!("row {}", rowid)));
+            // The root, two levels of interior pages and a leaf, at most.
+            assert!(storage.used() - before <= 4 * 1024);
+        }
+        for rowid in [i64::MIN, -4, 0, 1, 3, 19999, 20001, i64::MAX] {
+            assert_eq!(cursor.lookup_rowid(rowid).unwrap(), None, "rowid {}", rowid);
+        }
+    }
+
+    #[test]
+    fn test_scan_table() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10000)
+             INSERT INTO t SELECT 10001 - i, 'row ' || (10001 - i) FROM n;",
+        );
+        // Two levels of interior pages above the leaves.
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let root = db.pager.read_page_blocking(root_page).unwrap();
+        let child = root.borrow().contents.as_ref().unwrap().rightmost_pointer();
+        let child = db
+            .pager
+            .read_page_blocking(child.unwrap() as usize)
+            .unwrap();
+        assert!(!child.borrow().contents.as_ref().unwrap().is_leaf());
+
+        let rows = db
+            .scan_table("t")
+            .unwrap()
+            .map(|row| {
+                let (rowid, record) = row.unwrap();
+                (rowid, value_column(Some(record)).unwrap())
+            })
+            .collect::<Vec<_>>();
+        let expected = (1..=10000)
+            .map(|i| (i, format!("row {}", i)))
+            .collect::<Vec<_>>();
+        assert_eq!(rows, expected);
+
+        assert_eq!(
+            db.scan_table("missing").err().unwrap().to_string(),
+            "Parse error: no such table: missing"
+        );
+    }
+
+    #[test]
+    fn test_rewind_rescans_without_reads() {
+        use crate::storage::database::{FileStorage, ReadQuota};
+        use crate::storage::wal::WalFile;
+
+        let (dir, _db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 20)
+             INSERT INTO t SELECT i, zeroblob(100) FROM n;",
+        );
+        let path = dir.path().join("test.db");
+        let io = test_io();
+        let file = io
+            .open_file(path.to_str().unwrap(), crate::io::OpenFlags::None, false)
+            .unwrap();
+        let storage = Rc::new(ReadQuota::new(Rc::new(FileStorage::new(file)), usize::MAX));
+        let wal = Rc::new(RefCell::new(WalFile::new(
+            io.clone(),
+            format!("{}-wal", path.to_str().unwrap()),
+            1024,
+        )));
+        let db = Database::open(io, storage.clone(), wal).unwrap();
+        let mut cursor = table_cursor(&db, "t");
+        let scan = |cursor: &mut BTreeCursor| {
+            let mut rows = Vec::new();
+            cursor.run_to_completion(|c| c.rewind()).unwrap();
+            loop {
+                let Some(rowid) = *cursor.rowid.borrow() else {
+                    break;
+                };
+                rows.push((rowid, cursor.record.borrow().clone().unwrap()));
+                cursor.run_to_completion(|c| c.next()).unwrap();
+            }
+            rows
+        };
+        let first = scan(&mut cursor);
+        assert_eq!(first.len(), 20);
+        // The pages of the first pass are still cached, so rewinding and scanning
+        // again reads no page.
+        let used = storage.used();
+        let second = scan(&mut cursor);
+        assert_eq!(second, first);
+        assert_eq!(storage.used(), used);
+    }
+
+    #[test]
+    fn test_dump_all() {
+        let (_dir, db) = open_test_database(
+            "CREATE TABLE b (x INTEGER PRIMARY KEY AUTOINCREMENT, v TEXT);
+             CREATE TABLE a (v);
+             CREATE TABLE empty (v);
+             INSERT INTO b VALUES (5, 'b5'), (7, 'b7');
+             INSERT INTO a VALUES (1), (2), (3);",
+        );
+        let text = |v: &str| OwnedValue::Text(Rc::new(v.to_string()));
+        let rows = db
+            .dump_all(false)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            vec![
+                ("a".to_string(), 1, vec![OwnedValue::Integer(1)]),
+                ("a".to_string(), 2, vec![OwnedValue::Integer(2)]),
+                ("a".to_string(), 3, vec![OwnedValue::Integer(3)]),
+                ("b".to_string(), 5, vec![OwnedValue::Null, text("b5")]),
+                ("b".to_string(), 7, vec![OwnedValue::Null, text("b7")]),
+            ]
+        );
+
+        let tables = db
+            .dump_all(true)
+            .unwrap()
+            .map(|row| row.unwrap().0)
+            .collect::<Vec<_>>();
+        assert!(tables.contains(&"sqlite_schema".to_string()));
+        assert!(tables.contains(&"sqlite_sequence".to_string()));
+    }
+
+    #[test]
+    fn test_scan_detects_change() {
+        let (dir, db) = open_test_database(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO t SELECT i FROM n;",
+        );
+        let mut scan = scan_with_page(table_cursor(&db, "t"))
+            .check_changes(2)
+            .unwrap();
+        for _ in 0..3 {
+            scan.next().unwrap().unwrap();
+        }
+        // Bump the change counter behind the pager's back, as another connection
+        // committing would.
+        let path = dir.path().join("test.db");
+        let mut file = std::fs::read(&path).unwrap();
+        let change_counter = u32::from_be_bytes(file[24..28].try_into().unwrap());
+        file[24..28].copy_from_slice(&(change_counter + 1).to_be_bytes());
+        std::fs::write(&path, file).unwrap();
+        assert!(matches!(
+            scan.next(),
+            Some(Err(LimboError::DatabaseChangedDuringScan))
+        ));
+
+        let scan = scan_with_page(table_cursor(&db, "t"))
+            .check_changes(1)
+            .unwrap();
+        assert_eq!(scan.filter(|row| row.is_ok()).count(), 10);
+    }
+
+    #[test]
+    fn test_first_leaf() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 512;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+             INSERT INTO t SELECT i, zeroblob(100) FROM n;",
+        );
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let leaf = first_leaf(&db.pager, root_page).unwrap();
+        {
+            let page = db.pager.read_page_blocking(leaf).unwrap();
+            let page = page.borrow();
+            assert_eq!(
+                page.contents.as_ref().unwrap().page_type(),
+                PageType::TableLeaf
+            );
+        }
+        // The left-most leaf is the one holding the smallest rowid.
+        let (page_idx, rowid, _) = scan_with_page(table_cursor(&db, "t"))
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(rowid, 1);
+        assert_eq!(page_idx, leaf);
+        assert_ne!(leaf, root_page);
+    }
+
+    #[rstest]
+    #[case::cell_pointer_past_page(true, "Offset 65535 is past the end of the page")]
+    #[case::left_child_cycle(false, "B-tree rooted at page 2 is deeper than 20 levels")]
+    fn test_first_leaf_corrupt(#[case] corrupt_cell_pointer: bool, #[case] message: &str) {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 512;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+             INSERT INTO t SELECT i, zeroblob(100) FROM n;",
+        );
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        assert_eq!(root_page, 2);
+        let page = db.pager.read_page_blocking(root_page).unwrap();
+        {
+            let page = page.borrow();
+            let contents = page.contents.as_ref().unwrap();
+            let (cell_pointers, _) = contents.cell_get_raw_pointer_region();
+            if corrupt_cell_pointer {
+                contents.write_u16(cell_pointers, 0xffff);
+            } else {
+                // Point the left child of the first cell back at the root.
+                let first_cell = contents.read_u16(cell_pointers) as usize;
+                contents.write_u32(first_cell, root_page as u32);
+            }
+        }
+        let err = first_leaf(&db.pager, root_page).unwrap_err();
+        assert_eq!(err.to_string(), format!("Corrupt database: {}", message));
+    }
+
+    #[test]
+    fn test_scan_skips_empty_leaf() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 20)
+             INSERT INTO t SELECT i, zeroblob(1000) FROM n;",
+        );
+        let rows = scan_with_page(table_cursor(&db, "t"))
+            .map(|row| row.unwrap())
+            .collect::<Vec<_>>();
+        let (empty_leaf, _, _) = rows[rows.len() / 2];
+        let expected = rows
+            .iter()
+            .filter(|(page_idx, _, _)| *page_idx != empty_leaf)
+            .map(|(_, rowid, _)| *rowid)
+            .collect::<Vec<_>>();
+        {
+            let page = db.pager.read_page_blocking(empty_leaf).unwrap();
+            let page = page.borrow();
+            let contents = page.contents.as_ref().unwrap();
+            contents.write_u16(BTREE_HEADER_OFFSET_CELL_COUNT, 0);
+            assert!(contents.is_empty_leaf());
+        }
+
+        let mut cursor = table_cursor(&db, "t");
+        let mut forward = Vec::new();
+        cursor.run_to_completion(|c| c.rewind()).unwrap();
+        while let Some(rowid) = cursor.rowid().unwrap() {
+            forward.push(rowid);
+            cursor.run_to_completion(|c| c.next()).unwrap();
+        }
+        assert_eq!(forward, expected);
+
+        let mut backward = Vec::new();
+        cursor.run_to_completion(|c| c.last()).unwrap();
+        while let Some(rowid) = cursor.rowid().unwrap() {
+            backward.push(rowid);
+            cursor.run_to_completion(|c| c.prev()).unwrap();
+        }
+        backward.reverse();
+        assert_eq!(backward, expected);
+    }
+
+    #[test]
+    fn test_all_rowids() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 7 FROM n WHERE i < 300)
+             INSERT INTO t SELECT i, zeroblob(500) FROM n;",
+        );
+        let cursor = table_cursor(&db, "t");
+        let rows = scan_with_page(table_cursor(&db, "t"))
+            .map(|row| row.unwrap())
+            .collect::<Vec<_>>();
+        let scanned = rows
+            .iter()
+            .map(|(_, rowid, _)| *rowid as i64)
+            .collect::<Vec<_>>();
+        assert_eq!(cursor.all_rowids().unwrap(), scanned);
+
+        // Corrupt the record header of every row on the first leaf: decoding the
+        // payloads now fails, but collecting rowids never looks at them.
+        let page = db.pager.read_page_blocking(rows[0].0).unwrap();
+        {
+            let page = page.borrow();
+            let contents = page.contents.as_ref().unwrap();
+            let buf = contents.as_ptr();
+            for cell_idx in 0..contents.cell_count() {
+                let pos = contents.read_u16(8 + cell_idx * 2) as usize;
+                let (_, n1) = read_varint(&buf[pos..]).unwrap();
+                let (_, n2) = read_varint(&buf[pos + n1..]).unwrap();
+                // first serial type of the record header, 10 is reserved
+                buf[pos + n1 + n2 + 1] = 10;
+            }
+        }
+        assert!(scan_with_page(table_cursor(&db, "t")).any(|row| row.is_err()));
+        assert_eq!(cursor.all_rowids().unwrap(), scanned);
+    }
+
+    #[test]
+    fn test_all_rowids_negative() {
+        let (_dir, db) = open_test_database(&format!(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY);
+             INSERT INTO t VALUES ({}), (-1), (0), ({});",
+            i64::MIN,
+            i64::MAX
+        ));
+        assert_eq!(
+            table_cursor(&db, "t").all_rowids().unwrap(),
+            vec![i64::MIN, -1, 0, i64::MAX]
+        );
+    }
+
+    #[test]
+    fn test_all_rowids_cycle() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO t SELECT i, zeroblob(500) FROM n;",
+        );
+        let cursor = table_cursor(&db, "t");
+        let page = db.pager.read_page_blocking(cursor.root_page).unwrap();
+        {
+            let page = page.borrow();
+            let contents = page.contents.as_ref().unwrap();
+            assert_eq!(contents.page_type(), PageType::TableInterior);
+            // Point the right-most child of the root back at the root.
+            contents.write_u32(8, cursor.root_page as u32);
+        }
+        let err = cursor.all_rowids().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Corrupt database: Page {0} is referenced more than once in the b-tree rooted at page {0}",
+                cursor.root_page
+            )
+        );
+    }
+
+    #[rstest]
+    #[case::pointer_past_page(0xffff)]
+    #[case::varint_past_page(4095)]
+    fn test_all_rowids_corrupt_cell_pointer(#[case] cell_pointer: u16) {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 5)
+             INSERT INTO t SELECT i, zeroblob(500) FROM n;",
+        );
+        let cursor = table_cursor(&db, "t");
+        let page = db.pager.read_page_blocking(cursor.root_page).unwrap();
+        {
+            let page = page.borrow();
+            let contents = page.contents.as_ref().unwrap();
+            contents.write_u16(8, cell_pointer);
+            // A cell in the last byte has room for its payload size, but not for
+            // the rowid varint that follows.
+            contents.as_ptr()[4095] = 0x01;
+        }
+        let result = cursor.all_rowids();
+        assert!(
+            matches!(result, Err(LimboError::Corrupt(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 40)
+             INSERT INTO t SELECT i, zeroblob(i * 20) FROM n;",
+        );
+        let mut cursor = table_cursor(&db, "t");
+        cursor.filter(Box::new(
+            |record| matches!(&record.values[1], OwnedValue::Blob(b) if b.len() % 300 == 0),
+        ));
+        let mut forward = Vec::new();
+        cursor.run_to_completion(|c| c.rewind()).unwrap();
+        while let Some(rowid) = cursor.rowid().unwrap() {
+            forward.push(rowid);
+            cursor.run_to_completion(|c| c.next()).unwrap();
+        }
+        assert_eq!(forward, vec![15, 30]);
+
+        let mut backward = Vec::new();
+        cursor.run_to_completion(|c| c.last()).unwrap();
+        while let Some(rowid) = cursor.rowid().unwrap() {
+            backward.push(rowid);
+            cursor.run_to_completion(|c| c.prev()).unwrap();
+        }
+        assert_eq!(backward, vec![30, 15]);
+    }
+}
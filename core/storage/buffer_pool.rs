@@ -24,6 +24,10 @@ impl BufferPool {
         }
     }
 
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
     pub fn put(&self, buffer: BufferData) {
         let mut free_buffers = self.free_buffers.borrow_mut();
         free_buffers.push(buffer);
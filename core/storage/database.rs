@@ -1,5 +1,33 @@
+#[cfg(feature = "fs")]
+use crate::storage::sqlite3_ondisk::is_valid_page_size;
+use crate::storage::sqlite3_ondisk::MAX_PAGE_SIZE;
 use crate::{error::LimboError, io::Completion, Buffer, Result};
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::Arc,
+};
+
+/// The largest database file that can be read: SQLite's limit of 4294967294 pages
+/// of the largest page size, about 256 TiB. On 32-bit targets, pages starting past
+/// 4 GiB cannot be addressed and fail to read instead.
+pub const MAX_DATABASE_FILE_SIZE: u64 = 4294967294 * MAX_PAGE_SIZE as u64;
+
+/// Returns the byte offset of page `page_idx` in a database file of `page_size`
+/// byte pages.
+///
+/// The offset is computed in 64 bits, so that it fails rather than wraps when it
+/// does not fit a `usize`.
+pub fn page_offset(page_idx: usize, page_size: usize) -> Result<usize> {
+    assert!(page_idx > 0);
+    let offset = (page_idx as u64 - 1) * page_size as u64;
+    usize::try_from(offset).map_err(|_| {
+        LimboError::InternalError(format!(
+            "page {} starts at offset {}, past the largest offset of this platform",
+            page_idx, offset
+        ))
+    })
+}
 
 /// DatabaseStorage is an interface a database file that consists of pages.
 ///
@@ -15,6 +43,12 @@ pub trait DatabaseStorage {
         c: Rc<Completion>,
     ) -> Result<()>;
     fn sync(&self, c: Rc<Completion>) -> Result<()>;
+
+    /// Returns the size of the database in bytes, or `None` if the storage does
+    /// not know it.
+    fn size(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
 }
 
 #[cfg(feature = "fs")]
@@ -30,11 +64,13 @@ impl DatabaseStorage for FileStorage {
             _ => unreachable!(),
         };
         let size = r.buf().len();
-        assert!(page_idx > 0);
-        if !(512..=65536).contains(&size) || size & (size - 1) != 0 {
-            return Err(LimboError::NotADB);
+        if !is_valid_page_size(size) {
+            return Err(LimboError::InternalError(format!(
+                "cannot read page {}: {} bytes is not a valid page size",
+                page_idx, size
+            )));
         }
-        let pos = (page_idx - 1) * size;
+        let pos = page_offset(page_idx, size)?;
         self.file.pread(pos, c)?;
         Ok(())
     }
@@ -46,10 +82,13 @@ impl DatabaseStorage for FileStorage {
         c: Rc<Completion>,
     ) -> Result<()> {
         let buffer_size = buffer.borrow().len();
-        assert!(buffer_size >= 512);
-        assert!(buffer_size <= 65536);
-        assert!((buffer_size & (buffer_size - 1)) == 0);
-        let pos = (page_idx - 1) * buffer_size;
+        if !is_valid_page_size(buffer_size) {
+            return Err(LimboError::InternalError(format!(
+                "cannot write page {}: {} bytes is not a valid page size",
+                page_idx, buffer_size
+            )));
+        }
+        let pos = page_offset(page_idx, buffer_size)?;
         self.file.pwrite(pos, buffer, c)?;
         Ok(())
     }
@@ -57,6 +96,10 @@ impl DatabaseStorage for FileStorage {
     fn sync(&self, c: Rc<Completion>) -> Result<()> {
         self.file.sync(c)
     }
+
+    fn size(&self) -> Result<Option<u64>> {
+        Ok(Some(self.file.size()?))
+    }
 }
 
 #[cfg(feature = "fs")]
@@ -65,3 +108,297 @@ impl FileStorage {
         Self { file }
     }
 }
+
+/// A read-only `DatabaseStorage` over the bytes of a database file held in memory.
+///
+/// The bytes are shared rather than copied; each page read copies just that page
+/// into the page cache. Writes fail with `LimboError::ReadOnly`.
+pub struct MemoryStorage {
+    data: Arc<[u8]>,
+}
+
+impl MemoryStorage {
+    pub fn new(data: Arc<[u8]>) -> Self {
+        Self { data }
+    }
+}
+
+impl DatabaseStorage for MemoryStorage {
+    fn read_page(&self, page_idx: usize, c: Rc<Completion>) -> Result<()> {
+        let r = match &(*c) {
+            Completion::Read(r) => r,
+            _ => unreachable!(),
+        };
+        let bytes_read = {
+            let mut buf = r.buf_mut();
+            let buf = buf.as_mut_slice();
+            let pos = page_offset(page_idx, buf.len())?;
+            let page = self.data.get(pos..).unwrap_or_default();
+            let n = buf.len().min(page.len());
+            buf[..n].copy_from_slice(&page[..n]);
+            n
+        };
+        c.complete(bytes_read as i32);
+        Ok(())
+    }
+
+    fn write_page(
+        &self,
+        _page_idx: usize,
+        _buffer: Rc<RefCell<Buffer>>,
+        _c: Rc<Completion>,
+    ) -> Result<()> {
+        Err(LimboError::ReadOnly)
+    }
+
+    fn sync(&self, c: Rc<Completion>) -> Result<()> {
+        c.complete(0);
+        Ok(())
+    }
+
+    fn size(&self) -> Result<Option<u64>> {
+        Ok(Some(self.data.len() as u64))
+    }
+}
+
+/// A `DatabaseStorage` that serves at most `limit` bytes of page reads from the
+/// storage it wraps, for reading untrusted database files.
+///
+/// A read that would exceed the quota fails with `LimboError::QuotaExceeded`
+/// before reaching the wrapped storage, so that a file crafted to look huge
+/// cannot cause unbounded reads. Writes are not counted.
+pub struct ReadQuota {
+    storage: Rc<dyn DatabaseStorage>,
+    limit: usize,
+    used: Cell<usize>,
+}
+
+impl ReadQuota {
+    pub fn new(storage: Rc<dyn DatabaseStorage>, limit: usize) -> Self {
+        Self {
+            storage,
+            limit,
+            used: Cell::new(0),
+        }
+    }
+
+    /// Returns the number of bytes read so far.
+    pub fn used(&self) -> usize {
+        self.used.get()
+    }
+}
+
+impl DatabaseStorage for ReadQuota {
+    fn read_page(&self, page_idx: usize, c: Rc<Completion>) -> Result<()> {
+        let size = match &(*c) {
+            Completion::Read(r) => r.buf().len(),
+            _ => unreachable!(),
+        };
+        let used = self.used.get() + size;
+        if used > self.limit {
+            return Err(LimboError::QuotaExceeded(self.limit));
+        }
+        self.used.set(used);
+        self.storage.read_page(page_idx, c)
+    }
+
+    fn write_page(
+        &self,
+        page_idx: usize,
+        buffer: Rc<RefCell<Buffer>>,
+        c: Rc<Completion>,
+    ) -> Result<()> {
+        self.storage.write_page(page_idx, buffer, c)
+    }
+
+    fn sync(&self, c: Rc<Completion>) -> Result<()> {
+        self.storage.sync(c)
+    }
+
+    fn size(&self) -> Result<Option<u64>> {
+        self.storage.size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::btree::tests::{open_test_database, test_io};
+    use crate::storage::btree::{scan_with_page, BTreeCursor};
+    use crate::storage::wal::WalFile;
+    use crate::{Database, IO};
+
+    #[test]
+    fn test_read_quota_exceeded() {
+        let (dir, _db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 50)
+             INSERT INTO t SELECT i, zeroblob(500) FROM n;",
+        );
+        let path = dir.path().join("test.db");
+        let io = test_io();
+        let file = io
+            .open_file(path.to_str().unwrap(), crate::io::OpenFlags::None, false)
+            .unwrap();
+        // Enough for the header, the schema and a few pages of the table.
+        let quota = Rc::new(ReadQuota::new(Rc::new(FileStorage::new(file)), 8 * 1024));
+        let wal = Rc::new(RefCell::new(WalFile::new(
+            io.clone(),
+            format!("{}-wal", path.to_str().unwrap()),
+            1024,
+        )));
+        let db = Database::open(io.clone(), quota.clone(), wal).unwrap();
+
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let cursor = BTreeCursor::new(db.pager.clone(), root_page, db.header.clone());
+        let mut rows = 0;
+        let mut scan = scan_with_page(cursor);
+        let err = loop {
+            match scan.next() {
+                Some(Ok(_)) => rows += 1,
+                Some(Err(err)) => break err,
+                None => panic!("scanned all rows within the quota"),
+            }
+        };
+        assert!(matches!(err, LimboError::QuotaExceeded(8192)));
+        assert!(rows > 0);
+        assert!(quota.used() <= 8 * 1024);
+    }
+
+    /// Records the index of every page read.
+    struct RecordingStorage {
+        storage: FileStorage,
+        reads: RefCell<Vec<usize>>,
+    }
+
+    impl DatabaseStorage for RecordingStorage {
+        fn read_page(&self, page_idx: usize, c: Rc<Completion>) -> Result<()> {
+            self.reads.borrow_mut().push(page_idx);
+            self.storage.read_page(page_idx, c)
+        }
+
+        fn write_page(
+            &self,
+            page_idx: usize,
+            buffer: Rc<RefCell<Buffer>>,
+            c: Rc<Completion>,
+        ) -> Result<()> {
+            self.storage.write_page(page_idx, buffer, c)
+        }
+
+        fn sync(&self, c: Rc<Completion>) -> Result<()> {
+            self.storage.sync(c)
+        }
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_page_offset_past_4gib() {
+        // The first page past 4 GiB, which wraps in 32-bit arithmetic.
+        assert_eq!(page_offset(65537, 65536).unwrap(), 1 << 32);
+        assert_eq!(
+            page_offset(4294967294, 65536).unwrap() as u64 + 65536,
+            MAX_DATABASE_FILE_SIZE
+        );
+        assert_eq!(page_offset(1, 4096).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_open_bytes() {
+        let (dir, _db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 20)
+             INSERT INTO t SELECT i, zeroblob(300) FROM n;",
+        );
+        let data: Arc<[u8]> = std::fs::read(dir.path().join("test.db")).unwrap().into();
+        drop(dir);
+
+        let db = Database::open_bytes(data).unwrap();
+        assert_eq!(db.header.borrow().page_size(), 1024);
+        let rowids = db
+            .scan_table("t")
+            .unwrap()
+            .map(|row| row.map(|(rowid, _)| rowid))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rowids, (1..=20).collect::<Vec<_>>());
+
+        let page = db.pager.read_page_blocking(2).unwrap();
+        let c = Rc::new(Completion::Write(crate::io::WriteCompletion::new(
+            Box::new(|_| {}),
+        )));
+        let buffer = page.borrow().contents.as_ref().unwrap().buffer.clone();
+        let storage = MemoryStorage::new(Arc::from(vec![0; 1024]));
+        assert!(matches!(
+            storage.write_page(2, buffer, c),
+            Err(LimboError::ReadOnly)
+        ));
+    }
+
+    #[test]
+    fn test_file_storage_write_page() {
+        let io = crate::io::MemoryIO::new();
+        let file = io
+            .open_file("test.db", crate::io::OpenFlags::Create, false)
+            .unwrap();
+        let storage = FileStorage::new(file);
+        let write = |page_idx: usize, data: Vec<u8>| {
+            let drop_fn = Rc::new(|_buf| {});
+            let mut buffer = Buffer::allocate(data.len(), drop_fn);
+            buffer.as_mut_slice().copy_from_slice(&data);
+            let c = Rc::new(Completion::Write(crate::io::WriteCompletion::new(
+                Box::new(|_| {}),
+            )));
+            storage.write_page(page_idx, Rc::new(RefCell::new(buffer)), c)
+        };
+
+        write(1, vec![1; 1024]).unwrap();
+        write(3, vec![3; 1024]).unwrap();
+        let contents = io.file_contents("test.db").unwrap();
+        assert_eq!(contents.len(), 3 * 1024);
+        assert!(contents[..1024].iter().all(|&b| b == 1));
+        assert!(contents[1024..2048].iter().all(|&b| b == 0));
+        assert!(contents[2048..].iter().all(|&b| b == 3));
+
+        let err = write(2, vec![2; 1000]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Internal error: cannot write page 2: 1000 bytes is not a valid page size"
+        );
+        assert_eq!(io.file_contents("test.db").unwrap(), contents);
+    }
+
+    #[test]
+    fn test_open_schema_only() {
+        let sql = (0..50)
+            .map(|i| format!("CREATE TABLE table_with_a_long_name_{i} (x INTEGER PRIMARY KEY, v TEXT); INSERT INTO table_with_a_long_name_{i} VALUES (1, 'a');"))
+            .collect::<String>();
+        let (dir, db) = open_test_database(&format!("PRAGMA page_size = 1024; {sql}"));
+        let schema_pages = db.schema_pages().unwrap();
+        assert!(schema_pages.len() > 1);
+
+        let path = dir.path().join("test.db");
+        let io = test_io();
+        let file = io
+            .open_file(path.to_str().unwrap(), crate::io::OpenFlags::None, false)
+            .unwrap();
+        let storage = Rc::new(RecordingStorage {
+            storage: FileStorage::new(file),
+            reads: RefCell::new(Vec::new()),
+        });
+        let wal = Rc::new(RefCell::new(WalFile::new(
+            io.clone(),
+            format!("{}-wal", path.to_str().unwrap()),
+            1024,
+        )));
+        let schema = Database::open_schema_only(io, storage.clone(), wal).unwrap();
+        assert_eq!(schema.tables.len(), 51);
+        let reads = storage.reads.borrow();
+        assert!(!reads.is_empty());
+        for page_idx in reads.iter() {
+            assert!(schema_pages.contains(page_idx), "read page {}", page_idx);
+        }
+    }
+}
@@ -0,0 +1,709 @@
+//! Walks over all the pages of a database file: consistency checks, see
+//! `PRAGMA integrity_check`, and layout reports, see the `dbstat` virtual table.
+//!
+//! The problems `integrity_check` looks for, such as child pages out of range,
+//! pages in use twice and duplicate rowids, are reported as messages and checking
+//! continues, so that a single run reports as much as possible. A page that cannot
+//! be read, or whose cells cannot be parsed, still fails the walk with its error,
+//! and [`freelist`] fails on a malformed freelist.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::storage::btree::BTreeCursor;
+use crate::storage::pager::Pager;
+use crate::storage::sqlite3_ondisk::{parse_btree_page, DatabaseHeader, PageType};
+use crate::Result;
+
+/// Checks the database and returns a description of each problem found; an empty
+/// list means the database is consistent.
+///
+/// `root_pages` are the root pages of every b-tree in the database, as listed in
+/// the schema.
+pub fn integrity_check(
+    pager: &Rc<Pager>,
+    header: &DatabaseHeader,
+    root_pages: &[usize],
+) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let mut in_use = HashMap::new();
+    for &root_page in root_pages {
+        let problems_before = problems.len();
+        collect_btree_pages(pager, header, root_page, &mut in_use, &mut problems)?;
+        // Scanning a tree with bad child pointers would fail, so only trees whose
+        // structure checks out are scanned.
+        if problems.len() == problems_before {
+            check_unique_rowids(pager, header, root_page, &mut problems)?;
+        }
+    }
+    for page_idx in ptrmap_pages(header, pager.usable_size()) {
+        in_use.insert(page_idx, "ptrmap");
+    }
+    for page_idx in freelist_pages(pager, header)? {
+        if in_use.contains_key(&page_idx) {
+            problems.push(format!(
+                "Page {} is on the freelist but is also in use",
+                page_idx
+            ));
+        }
+    }
+    Ok(problems)
+}
+
+/// Counts the pages of the database by kind: `table-interior`, `table-leaf`,
+/// `index-interior`, `index-leaf`, `overflow`, `freelist` and `ptrmap`.
+///
+/// Each page is classified once, by walking the b-trees rooted at `root_pages`,
+/// the freelist and the pointer map. Pages reached by none of them are not
+/// counted.
+pub fn page_type_histogram(
+    pager: &Rc<Pager>,
+    header: &DatabaseHeader,
+    root_pages: &[usize],
+) -> Result<HashMap<&'static str, u64>> {
+    let mut pages = HashMap::new();
+    for &root_page in root_pages {
+        collect_btree_pages(pager, header, root_page, &mut pages, &mut Vec::new())?;
+    }
+    for page_idx in ptrmap_pages(header, pager.usable_size()) {
+        pages.insert(page_idx, "ptrmap");
+    }
+    for page_idx in freelist_pages(pager, header)? {
+        pages.entry(page_idx).or_insert("freelist");
+    }
+    let mut histogram = HashMap::new();
+    for kind in pages.into_values() {
+        *histogram.entry(kind).or_insert(0) += 1;
+    }
+    Ok(histogram)
+}
+
+/// Returns the pages of the b-tree rooted at `root_page` in ascending order,
+/// including the overflow pages of its cells.
+pub fn btree_pages(
+    pager: &Rc<Pager>,
+    header: &DatabaseHeader,
+    root_page: usize,
+) -> Result<Vec<usize>> {
+    let mut pages = HashMap::new();
+    collect_btree_pages(pager, header, root_page, &mut pages, &mut Vec::new())?;
+    let mut pages = pages.into_keys().collect::<Vec<_>>();
+    pages.sort_unstable();
+    Ok(pages)
+}
+
+/// Returns how many pages `PRAGMA incremental_vacuum` would truncate from the end
+/// of the database file, or 0 if the database is not in auto-vacuum mode.
+///
+/// Every freelist page is reclaimed, by moving the pages in use towards the
+/// start of the file, along with the pointer map pages no longer needed to map
+/// the remaining pages. The pending byte page is not accounted for, as databases
+/// smaller than 1 GiB do not have one.
+pub fn reclaimable_pages(header: &DatabaseHeader, usable_size: usize) -> Result<u32> {
+    if !header.is_auto_vacuum() {
+        return Ok(0);
+    }
+    let database_size = header.database_size as i64;
+    let free_pages = header.freelist_pages() as i64;
+    if free_pages >= database_size {
+        crate::bail_corrupt_error!(
+            "Freelist has {} pages, but the database has only {}",
+            free_pages,
+            database_size
+        );
+    }
+    // Mirrors finalDbSize() in SQLite's btree.c.
+    let entries = (usable_size / 5) as i64;
+    let pages_per_ptrmap = entries + 1;
+    let is_ptrmap = |page: i64| page >= 2 && (page - 2) % pages_per_ptrmap == 0;
+    let last_ptrmap = (database_size - 2) / pages_per_ptrmap * pages_per_ptrmap + 2;
+    let ptrmap_pages = (free_pages - database_size + last_ptrmap + entries) / entries;
+    let mut final_size = database_size - free_pages - ptrmap_pages;
+    while is_ptrmap(final_size) {
+        final_size -= 1;
+    }
+    Ok((database_size - final_size) as u32)
+}
+
+/// Adds the pages of the b-tree rooted at `page_idx` to `pages` along with their
+/// kind, including the overflow pages of its cells.
+///
+/// Child and overflow page pointers past the end of the database, or to the
+/// lock-byte page, are reported to `problems` and not followed, as are pages
+/// reached a second time.
+fn collect_btree_pages(
+    pager: &Rc<Pager>,
+    header: &DatabaseHeader,
+    page_idx: usize,
+    pages: &mut HashMap<usize, &'static str>,
+    problems: &mut Vec<String>,
+) -> Result<()> {
+    if pages.contains_key(&page_idx) {
+        problems.push(format!("Page {} is referenced more than once", page_idx));
+        return Ok(());
+    }
+    let page = pager.read_page_blocking(page_idx)?;
+    let page = page.borrow();
+    let contents = page.contents.as_ref().unwrap();
+    let parsed = parse_btree_page(contents, pager.usable_size())?;
+    let kind = match parsed.page_type {
+        PageType::TableInterior => "table-interior",
+        PageType::TableLeaf => "table-leaf",
+        PageType::IndexInterior => "index-interior",
+        PageType::IndexLeaf => "index-leaf",
+    };
+    pages.insert(page_idx, kind);
+    let page_count = header.database_size as usize;
+    let lock_byte_page = lock_byte_page(header.page_size() as usize);
+    // Returns the problem with a pointer to page `target`, if any.
+    let check_pointer = |target: u32| {
+        let target = target as usize;
+        if target == 0 || target > page_count {
+            Some(format!("{} is out of range 1..={}", target, page_count))
+        } else if target == lock_byte_page {
+            Some(format!("{} is the lock-byte page", target))
+        } else {
+            None
+        }
+    };
+    let mut children = Vec::new();
+    let mut check_child =
+        |child: u32, pointer: String, problems: &mut Vec<String>| match check_pointer(child) {
+            Some(problem) => problems.push(format!(
+                "Page {} {}: child page {}",
+                page_idx, pointer, problem
+            )),
+            None => children.push(child as usize),
+        };
+    for (cell_idx, cell) in parsed.cells.iter().enumerate() {
+        if let Some(child) = cell.left_child_page {
+            check_child(child, format!("cell {}", cell_idx), problems);
+        }
+        let mut pointer = (page_idx, format!("cell {}", cell_idx));
+        let mut overflow_page = cell.first_overflow_page.unwrap_or(0);
+        while overflow_page != 0 {
+            if let Some(problem) = check_pointer(overflow_page) {
+                problems.push(format!(
+                    "Page {} {}: overflow page {}",
+                    pointer.0, pointer.1, problem
+                ));
+                break;
+            }
+            let overflow_idx = overflow_page as usize;
+            if pages.contains_key(&overflow_idx) {
+                problems.push(format!(
+                    "Page {} is referenced more than once",
+                    overflow_idx
+                ));
+                break;
+            }
+            pages.insert(overflow_idx, "overflow");
+            let page = pager.read_page_blocking(overflow_idx)?;
+            let page = page.borrow();
+            overflow_page = page.contents.as_ref().unwrap().read_u32(0);
+            pointer = (overflow_idx, "next pointer".to_string());
+        }
+    }
+    if let Some(child) = parsed.rightmost_pointer {
+        check_child(child, "right-most pointer".to_string(), problems);
+    }
+    drop(parsed);
+    drop(page);
+    for child in children {
+        collect_btree_pages(pager, header, child, pages, problems)?;
+    }
+    Ok(())
+}
+
+/// Reports the rowids that appear more than once in the table b-tree rooted at
+/// `root_page`, as when the rowid of a cell has been overwritten. Index b-trees
+/// are skipped.
+fn check_unique_rowids(
+    pager: &Rc<Pager>,
+    header: &DatabaseHeader,
+    root_page: usize,
+    problems: &mut Vec<String>,
+) -> Result<()> {
+    let page = pager.read_page_blocking(root_page)?;
+    let page_type = page.borrow().contents.as_ref().unwrap().page_type();
+    if !matches!(page_type, PageType::TableInterior | PageType::TableLeaf) {
+        return Ok(());
+    }
+    let cursor = BTreeCursor::new(
+        pager.clone(),
+        root_page,
+        Rc::new(RefCell::new(header.clone())),
+    );
+    let rowids = match cursor.all_rowids() {
+        Ok(rowids) => rowids,
+        Err(err) => {
+            problems.push(format!("Table with root page {}: {}", root_page, err));
+            return Ok(());
+        }
+    };
+    // Rowids come out in key order, so duplicates are next to each other.
+    let mut last_reported = None;
+    for pair in rowids.windows(2) {
+        if pair[0] == pair[1] && last_reported != Some(pair[0]) {
+            problems.push(format!(
+                "Table with root page {} has duplicate rowid {}",
+                root_page, pair[0]
+            ));
+            last_reported = Some(pair[0]);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the page holding the lock bytes at offset 1 GiB, which is never used.
+fn lock_byte_page(page_size: usize) -> usize {
+    0x40000000 / page_size + 1
+}
+
+/// Returns the pointer map pages of an auto-vacuum database.
+fn ptrmap_pages(header: &DatabaseHeader, usable_size: usize) -> Vec<usize> {
+    if !header.is_auto_vacuum() {
+        return Vec::new();
+    }
+    // The first pointer map page is page 2, and each is followed by the pages it
+    // maps: one 5-byte entry per page.
+    let stride = usable_size / 5 + 1;
+    (2..=header.database_size as usize)
+        .step_by(stride)
+        .collect()
+}
+
+/// Returns true if every freelist leaf page is filled with zeros, as they are when
+/// pages are freed with `PRAGMA secure_delete` on. This also holds when the
+/// freelist has no leaf pages.
+///
+/// Trunk pages are not checked, as they hold the freelist itself.
+pub fn freelist_pages_zeroed(pager: &Pager, header: &DatabaseHeader) -> Result<bool> {
+    let trunks = freelist_trunk_and_leaf_pages(pager, header, false)?;
+    for page_idx in trunks.into_iter().flat_map(|(_, leaf_pages)| leaf_pages) {
+        let page = pager.read_page_blocking(page_idx)?;
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        if contents.as_ptr().iter().any(|&b| b != 0) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Returns every page on the freelist: each trunk page, followed by the leaf
+/// pages it lists, in freelist order.
+///
+/// Unlike the checks above, a malformed freelist is an error, see
+/// [`freelist_trunk_and_leaf_pages`] in strict mode.
+pub fn freelist(pager: &Pager, header: &DatabaseHeader) -> Result<Vec<u32>> {
+    let trunks = freelist_trunk_and_leaf_pages(pager, header, true)?;
+    Ok(trunks
+        .into_iter()
+        .flat_map(|(trunk_page, leaf_pages)| std::iter::once(trunk_page).chain(leaf_pages))
+        .map(|page_idx| page_idx as u32)
+        .collect())
+}
+
+/// Returns the freelist trunk and leaf pages.
+fn freelist_pages(pager: &Pager, header: &DatabaseHeader) -> Result<Vec<usize>> {
+    let trunks = freelist_trunk_and_leaf_pages(pager, header, false)?;
+    Ok(trunks
+        .into_iter()
+        .flat_map(|(trunk_page, leaf_pages)| std::iter::once(trunk_page).chain(leaf_pages))
+        .collect())
+}
+
+/// Returns each freelist trunk page with the leaf pages it lists, in freelist
+/// order.
+///
+/// A trunk page holds the next trunk page, the number of leaf pages it lists, and
+/// the leaf page numbers, each as a 4-byte integer.
+///
+/// By default, the walk stops at the first trunk page seen twice and reads no
+/// more leaf pages from a trunk page than fit. In `strict` mode these are errors
+/// instead, as are pages that are 0 or past the end of the database, pages listed
+/// twice or as both a trunk and a leaf page, and a page count that does not match
+/// the header.
+fn freelist_trunk_and_leaf_pages(
+    pager: &Pager,
+    header: &DatabaseHeader,
+    strict: bool,
+) -> Result<Vec<(usize, Vec<usize>)>> {
+    let mut trunks = Vec::new();
+    let mut visited = HashSet::new();
+    let max_leaf_count = pager.usable_size() / 4 - 2;
+    let mut trunk_page = header.freelist_trunk_page();
+    while trunk_page != 0 {
+        if strict && trunk_page > header.database_size {
+            crate::bail_corrupt_error!(
+                "Freelist trunk page {} is past the end of the database",
+                trunk_page
+            );
+        }
+        if !visited.insert(trunk_page) {
+            if strict {
+                crate::bail_corrupt_error!("Freelist trunk page {} is listed twice", trunk_page);
+            }
+            break;
+        }
+        let page = pager.read_page_blocking(trunk_page as usize)?;
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        let leaf_count = contents.read_u32(4) as usize;
+        if strict && leaf_count > max_leaf_count {
+            crate::bail_corrupt_error!(
+                "Freelist trunk page {} lists {} leaf pages, at most {} fit",
+                trunk_page,
+                leaf_count,
+                max_leaf_count
+            );
+        }
+        let leaf_pages = (0..leaf_count.min(max_leaf_count))
+            .map(|leaf_idx| contents.read_u32(8 + leaf_idx * 4) as usize)
+            .collect();
+        trunks.push((trunk_page as usize, leaf_pages));
+        trunk_page = contents.read_u32(0);
+    }
+    if strict {
+        let mut leaves = HashSet::new();
+        for &leaf_page in trunks.iter().flat_map(|(_, leaf_pages)| leaf_pages) {
+            if leaf_page == 0 || leaf_page > header.database_size as usize {
+                crate::bail_corrupt_error!("Freelist leaf page {} is out of range", leaf_page);
+            }
+            if visited.contains(&(leaf_page as u32)) {
+                crate::bail_corrupt_error!("Freelist leaf page {} is also a trunk page", leaf_page);
+            }
+            if !leaves.insert(leaf_page) {
+                crate::bail_corrupt_error!("Freelist leaf page {} is listed twice", leaf_page);
+            }
+        }
+        let page_count = visited.len() + leaves.len();
+        if page_count != header.freelist_pages() as usize {
+            crate::bail_corrupt_error!(
+                "Freelist has {} pages, but the header says {}",
+                page_count,
+                header.freelist_pages()
+            );
+        }
+    }
+    Ok(trunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::btree::tests::open_test_database;
+    use crate::storage::sqlite3_ondisk::{parse_btree_page, PageType};
+    use crate::LimboError;
+    use rstest::rstest;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_schema_pages() {
+        let (_dir, db) = open_test_database("CREATE TABLE t (x INTEGER PRIMARY KEY);");
+        assert_eq!(db.schema_pages().unwrap(), vec![1]);
+
+        // Enough tables to split the schema table.
+        let sql = (0..50)
+            .map(|i| {
+                format!("CREATE TABLE table_with_a_long_name_{i} (x INTEGER PRIMARY KEY, v TEXT);")
+            })
+            .collect::<String>();
+        let (_dir, db) = open_test_database(&format!("PRAGMA page_size = 1024; {sql}"));
+        let pages = db.schema_pages().unwrap();
+        assert!(pages.len() > 1);
+        assert_eq!(pages[0], 1);
+        assert!(!pages.contains(
+            &db.schema
+                .borrow()
+                .get_table("table_with_a_long_name_0")
+                .unwrap()
+                .root_page
+        ));
+    }
+
+    #[test]
+    fn test_reclaimable_pages() {
+        let (dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             PRAGMA auto_vacuum = INCREMENTAL;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 300)
+             INSERT INTO t SELECT i, zeroblob(600) FROM n;
+             DELETE FROM t WHERE x % 3 != 0 OR x > 200;",
+        );
+        let reclaimable = db.reclaimable_pages().unwrap();
+        assert!(reclaimable > 0);
+
+        let conn = rusqlite::Connection::open(dir.path().join("test.db")).unwrap();
+        let page_count = || -> u32 {
+            conn.query_row("PRAGMA page_count", [], |row| row.get(0))
+                .unwrap()
+        };
+        let before = page_count();
+        // The pragma frees one page per step.
+        let mut stmt = conn.prepare("PRAGMA incremental_vacuum").unwrap();
+        let mut rows = stmt.query([]).unwrap();
+        while rows.next().unwrap().is_some() {}
+        assert_eq!(before - page_count(), reclaimable);
+
+        let (_dir, db) = open_test_database(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             INSERT INTO t VALUES (1, zeroblob(10000));
+             DELETE FROM t;",
+        );
+        assert!(db.header.borrow().freelist_pages() > 0);
+        assert_eq!(db.reclaimable_pages().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_page_type_histogram() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             CREATE INDEX t_v ON t (v);
+             CREATE TABLE tmp (x INTEGER PRIMARY KEY);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 4)
+             INSERT INTO t SELECT i, zeroblob(600) FROM n;
+             INSERT INTO t VALUES (5, zeroblob(2000));
+             DROP TABLE tmp;",
+        );
+        let histogram = db.page_type_histogram().unwrap();
+        let total: u64 = histogram.values().sum();
+        assert_eq!(total, db.header.borrow().database_size as u64);
+        // The table has one row per leaf, next to the schema leaf on page 1. Only the
+        // 2000 byte row overflows the table, onto one page, while each index entry
+        // overflows: onto one page for the 600 byte keys and two for the last one.
+        assert_eq!(
+            histogram,
+            HashMap::from([
+                ("table-interior", 1),
+                ("table-leaf", 6),
+                ("index-leaf", 1),
+                ("overflow", 7),
+                ("freelist", 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_child_page_out_of_range() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO t SELECT i, zeroblob(500) FROM n;",
+        );
+        assert_eq!(db.integrity_check().unwrap(), Vec::<String>::new());
+
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let page_count = db.header.borrow().database_size;
+        let page = db.pager.read_page_blocking(root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        let (cell_pointers, _) = contents.cell_get_raw_pointer_region();
+        let cell = contents.read_u16(cell_pointers) as usize;
+        contents.write_u32(cell, 0);
+        contents.write_u32(8, page_count + 1);
+        assert_eq!(
+            db.integrity_check().unwrap(),
+            vec![
+                format!(
+                    "Page {} cell 0: child page 0 is out of range 1..={}",
+                    root_page, page_count
+                ),
+                format!(
+                    "Page {} right-most pointer: child page {} is out of range 1..={}",
+                    root_page,
+                    page_count + 1,
+                    page_count
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_page_referenced_twice() {
+        // The users table of testing.db is rooted at interior page 2. Pointing its
+        // right-most pointer back at itself makes the b-tree cyclic.
+        let mut image = include_bytes!("../../testing/testing.db").to_vec();
+        let page_size = u16::from_be_bytes([image[16], image[17]]) as usize;
+        assert_eq!(image[page_size], 0x05);
+        image[page_size + 8..page_size + 12].copy_from_slice(&2u32.to_be_bytes());
+        let db = crate::Database::open_bytes(image.into()).unwrap();
+        assert_eq!(
+            db.integrity_check().unwrap(),
+            vec!["Page 2 is referenced more than once".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_overflow_page_out_of_range() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, printf('%.3000c', 'o'));",
+        );
+        assert_eq!(db.integrity_check().unwrap(), Vec::<String>::new());
+
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let page_count = db.header.borrow().database_size;
+        let page = db.pager.read_page_blocking(root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        // The first overflow page number follows the local payload of the cell.
+        let pointer_offset = {
+            let parsed = parse_btree_page(contents, db.pager.usable_size()).unwrap();
+            let payload = parsed.cells[0].local_payload;
+            payload.as_ptr() as usize - contents.as_ptr().as_ptr() as usize + payload.len()
+        };
+        contents.write_u32(pointer_offset, page_count + 1);
+        assert_eq!(
+            db.integrity_check().unwrap(),
+            vec![format!(
+                "Page {} cell 0: overflow page {} is out of range 1..={}",
+                root_page,
+                page_count + 1,
+                page_count
+            )]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_rowid() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO t SELECT i, zeroblob(10) FROM n;",
+        );
+        assert_eq!(db.integrity_check().unwrap(), Vec::<String>::new());
+
+        // All rows are on the root leaf. Each cell starts with one-byte payload
+        // size and rowid varints, so rewriting the second rowid from 2 to 1 makes
+        // rowid 1 appear twice.
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let page = db.pager.read_page_blocking(root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        assert_eq!(contents.page_type(), PageType::TableLeaf);
+        let (cell_pointers, _) = contents.cell_get_raw_pointer_region();
+        let second_cell = contents.read_u16(cell_pointers + 2) as usize;
+        assert_eq!(contents.as_ptr()[second_cell + 1], 2);
+        contents.write_u8(second_cell + 1, 1);
+        assert_eq!(
+            db.integrity_check().unwrap(),
+            vec![format!(
+                "Table with root page {} has duplicate rowid 1",
+                root_page
+            )]
+        );
+    }
+
+    #[rstest]
+    #[case::secure_delete("ON", true)]
+    #[case::no_secure_delete("OFF", false)]
+    fn test_freelist_pages_zeroed(#[case] secure_delete: &str, #[case] zeroed: bool) {
+        let (_dir, db) = open_test_database(&format!(
+            "PRAGMA page_size = 1024;
+             PRAGMA secure_delete = {secure_delete};
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO t SELECT i, randomblob(500) FROM n;
+             DROP TABLE t;"
+        ));
+        assert!(db.header.borrow().freelist_pages() > 1);
+        assert_eq!(db.freelist_pages_zeroed().unwrap(), zeroed);
+    }
+
+    #[test]
+    fn test_freelist() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO t SELECT i, zeroblob(500) FROM n;
+             DROP TABLE t;",
+        );
+        let trunk_page = db.header.borrow().freelist_trunk_page();
+        let pages = db.freelist().unwrap();
+        assert_eq!(pages.len(), db.header.borrow().freelist_pages() as usize);
+        assert_eq!(pages[0], trunk_page);
+        let unique = pages.iter().collect::<std::collections::HashSet<_>>();
+        assert_eq!(unique.len(), pages.len());
+        assert!(pages.iter().all(|&page| page > 1));
+
+        let page = db.pager.read_page_blocking(trunk_page as usize).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        let leaf_count = contents.read_u32(4);
+        contents.write_u32(4, leaf_count - 1);
+        assert!(db.freelist().is_err());
+        contents.write_u32(4, leaf_count);
+        contents.write_u32(0, trunk_page);
+        assert!(db.freelist().is_err());
+    }
+
+    #[rstest]
+    #[case::zero("zero", "out of range")]
+    #[case::past_end("past_end", "out of range")]
+    #[case::duplicate("duplicate", "listed twice")]
+    #[case::trunk("trunk", "also a trunk page")]
+    fn test_freelist_invalid_leaf(#[case] leaf: &str, #[case] message: &str) {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO t SELECT i, zeroblob(500) FROM n;
+             DROP TABLE t;",
+        );
+        let trunk_page = db.header.borrow().freelist_trunk_page();
+        let page = db.pager.read_page_blocking(trunk_page as usize).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        assert!(contents.read_u32(4) >= 2);
+        let leaf_page = match leaf {
+            "zero" => 0,
+            "past_end" => db.header.borrow().database_size + 1,
+            "duplicate" => contents.read_u32(12),
+            "trunk" => trunk_page,
+            _ => unreachable!(),
+        };
+        contents.write_u32(8, leaf_page);
+        match db.freelist() {
+            Err(LimboError::Corrupt(msg)) => assert!(msg.contains(message), "{}", msg),
+            other => panic!("expected Corrupt, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_freelist_page_in_use() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             CREATE TABLE tmp (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO tmp SELECT i, zeroblob(500) FROM n;
+             INSERT INTO t VALUES (1, zeroblob(2000));
+             DROP TABLE tmp;",
+        );
+        assert_eq!(db.integrity_check().unwrap(), Vec::<String>::new());
+
+        // List the root page of `t` as a leaf of the freelist trunk page.
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let trunk_page = db.header.borrow().freelist_trunk_page() as usize;
+        let page = db.pager.read_page_blocking(trunk_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        assert!(contents.read_u32(4) > 0);
+        contents.write_u32(8, root_page as u32);
+        assert_eq!(
+            db.integrity_check().unwrap(),
+            vec![format!(
+                "Page {} is on the freelist but is also in use",
+                root_page
+            )]
+        );
+    }
+}
@@ -14,6 +14,8 @@
 pub(crate) mod btree;
 pub(crate) mod buffer_pool;
 pub(crate) mod database;
+pub(crate) mod integrity;
 pub(crate) mod pager;
 pub(crate) mod sqlite3_ondisk;
+pub(crate) mod transaction;
 pub(crate) mod wal;
@@ -1,11 +1,12 @@
+use crate::io::{Completion, ReadCompletion};
 use crate::storage::buffer_pool::BufferPool;
 use crate::storage::database::DatabaseStorage;
 use crate::storage::sqlite3_ondisk::{self, DatabaseHeader, PageContent};
 use crate::storage::wal::Wal;
-use crate::{Buffer, Result};
+use crate::{Buffer, LimboError, Result};
 use log::{debug, trace};
 use sieve_cache::SieveCache;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
 use std::ptr::{drop_in_place, NonNull};
@@ -15,6 +16,9 @@ use std::sync::Arc;
 
 use super::wal::CheckpointStatus;
 
+/// Default maximum length in bytes of a string or blob, see `SQLITE_MAX_LENGTH`.
+pub const DEFAULT_MAX_LENGTH: usize = 1_000_000_000;
+
 pub struct Page {
     pub flags: AtomicUsize,
     pub contents: Option<PageContent>,
@@ -337,6 +341,12 @@ pub struct Pager {
     checkpoint_state: RefCell<CheckpointState>,
     checkpoint_inflight: Rc<RefCell<usize>>,
     syncing: Rc<RefCell<bool>>,
+    /// Number of pages following a cache miss to prefetch into the page cache.
+    /// Read-ahead lives here rather than in the IO layer because only the pager
+    /// knows about pages and the page cache they are prefetched into.
+    read_ahead: RefCell<usize>,
+    /// Maximum length in bytes of a string or blob that can be written.
+    max_length: RefCell<usize>,
 }
 
 impl Pager {
@@ -345,6 +355,19 @@ impl Pager {
         sqlite3_ondisk::begin_read_database_header(page_io)
     }
 
+    /// Reads the database header, running `io` until the read has completed.
+    ///
+    /// As in SQLite, a file too short to hold the header is not a database.
+    pub fn read_header(
+        io: &Arc<dyn crate::io::IO>,
+        page_io: Rc<dyn DatabaseStorage>,
+    ) -> Result<Rc<RefCell<DatabaseHeader>>> {
+        match sqlite3_ondisk::read_database_header(io.as_ref(), page_io) {
+            Err(LimboError::ShortRead(..)) => Err(LimboError::NotADB),
+            result => result,
+        }
+    }
+
     /// Completes opening a database by initializing the Pager with the database header.
     pub fn finish_open(
         db_header_ref: Rc<RefCell<DatabaseHeader>>,
@@ -353,7 +376,7 @@ impl Pager {
         io: Arc<dyn crate::io::IO>,
     ) -> Result<Self> {
         let db_header = RefCell::borrow(&db_header_ref);
-        let page_size = db_header.page_size as usize;
+        let page_size = db_header.page_size() as usize;
         let buffer_pool = Rc::new(BufferPool::new(page_size));
         let page_cache = RefCell::new(DumbLruPageCache::new(10));
         Ok(Self {
@@ -371,6 +394,8 @@ impl Pager {
             syncing: Rc::new(RefCell::new(false)),
             checkpoint_state: RefCell::new(CheckpointState::Checkpoint),
             checkpoint_inflight: Rc::new(RefCell::new(0)),
+            read_ahead: RefCell::new(0),
+            max_length: RefCell::new(DEFAULT_MAX_LENGTH),
         })
     }
 
@@ -401,6 +426,17 @@ impl Pager {
             trace!("read_page(page_idx = {}) = cached", page_idx);
             return Ok(page.clone());
         }
+        let page = self.begin_read_uncached(&mut page_cache, page_idx)?;
+        self.begin_read_ahead(&mut page_cache, page_idx);
+        Ok(page)
+    }
+
+    /// Starts reading a page that is not in the page cache and inserts it into the cache.
+    fn begin_read_uncached(
+        &self,
+        page_cache: &mut DumbLruPageCache,
+        page_idx: usize,
+    ) -> Result<Rc<RefCell<Page>>> {
         let page = Rc::new(RefCell::new(Page::new(page_idx)));
         RefCell::borrow(&page).set_locked();
         if let Some(frame_id) = self.wal.borrow().find_frame(page_idx as u64)? {
@@ -427,6 +463,81 @@ impl Pager {
         Ok(page)
     }
 
+    /// Starts reading the pages following `page_idx` into the page cache so that
+    /// sequential reads find them there. Prefetching is best effort: a page that
+    /// cannot be read is left for the read that needs it to report.
+    fn begin_read_ahead(&self, page_cache: &mut DumbLruPageCache, page_idx: usize) {
+        let window = *self.read_ahead.borrow();
+        if window == 0 {
+            return;
+        }
+        // The header is mutably borrowed while a page is being allocated.
+        let Ok(db_header) = self.db_header.try_borrow() else {
+            return;
+        };
+        let last_page_idx = (page_idx + window).min(db_header.database_size as usize);
+        drop(db_header);
+        for next_page_idx in page_idx + 1..=last_page_idx {
+            if page_cache.contains_key(next_page_idx) {
+                continue;
+            }
+            if let Err(err) = self.begin_read_uncached(page_cache, next_page_idx) {
+                debug!("read_ahead(page_idx = {}) failed: {}", next_page_idx, err);
+                break;
+            }
+        }
+    }
+
+    /// Sets the number of pages to prefetch after a page cache miss. Zero disables
+    /// read-ahead.
+    ///
+    /// The window is clamped to half the capacity of the page cache, so that
+    /// prefetched pages never evict more than half of the pages in use.
+    pub fn set_read_ahead(&self, window: usize) {
+        let max_window = self.page_cache.borrow().capacity / 2;
+        self.read_ahead.replace(window.min(max_window));
+    }
+
+    /// Returns the number of pages prefetched after a page cache miss.
+    pub fn read_ahead(&self) -> usize {
+        *self.read_ahead.borrow()
+    }
+
+    /// Sets the maximum length in bytes of a string or blob that can be written.
+    pub fn set_max_length(&self, max_length: usize) {
+        self.max_length.replace(max_length);
+    }
+
+    /// Returns the maximum length in bytes of a string or blob that can be written.
+    pub fn max_length(&self) -> usize {
+        *self.max_length.borrow()
+    }
+
+    /// Reads a page from the database, running I/O until the page is loaded.
+    pub fn read_page_blocking(&self, page_idx: usize) -> Result<Rc<RefCell<Page>>> {
+        let page = self.read_page(page_idx)?;
+        loop {
+            {
+                let p = page.borrow();
+                if p.is_error() {
+                    return Err(LimboError::Corrupt(format!(
+                        "Failed to read page {}",
+                        page_idx
+                    )));
+                }
+                if !p.is_locked() && p.is_loaded() {
+                    break;
+                }
+                if !p.is_locked() {
+                    drop(p);
+                    self.load_page(page.clone())?;
+                }
+            }
+            self.io.run_once()?;
+        }
+        Ok(page)
+    }
+
     /// Loads pages if not loaded
     pub fn load_page(&self, page: Rc<RefCell<Page>>) -> Result<()> {
         let id = page.borrow().id;
@@ -476,6 +587,11 @@ impl Pager {
         dirty_pages.insert(page_id);
     }
 
+    /// Takes `page_id` off the list of pages the next cache flush writes.
+    pub fn remove_dirty(&self, page_id: usize) {
+        self.dirty_pages.borrow_mut().remove(&page_id);
+    }
+
     pub fn cacheflush(&self) -> Result<CheckpointStatus> {
         loop {
             let state = self.flush_info.borrow().state.clone();
@@ -640,9 +756,41 @@ impl Pager {
         page.borrow_mut().set_loaded();
     }
 
+    pub fn page_size(&self) -> usize {
+        self.db_header.borrow().page_size() as usize
+    }
+
     pub fn usable_size(&self) -> usize {
-        let db_header = self.db_header.borrow();
-        (db_header.page_size - db_header.unused_space as u16) as usize
+        self.db_header.borrow().usable_size()
+    }
+
+    /// Reads the file change counter from the database file, bypassing the page
+    /// cache and the in-memory header, so that writes by other connections show.
+    ///
+    /// In WAL mode, commits only reach the change counter of the database file once
+    /// they are checkpointed.
+    pub fn read_change_counter(&self) -> Result<u32> {
+        let drop_fn = Rc::new(|_buf| {});
+        let buf = Rc::new(RefCell::new(Buffer::allocate(512, drop_fn)));
+        let change_counter = Rc::new(Cell::new(None));
+        let result = change_counter.clone();
+        let complete = Box::new(move |buf: Rc<RefCell<Buffer>>, bytes_read: i32| {
+            let buf = buf.borrow();
+            let buf = buf.as_slice();
+            result.set(Some(if bytes_read < 28 {
+                Err(LimboError::ShortRead(bytes_read.max(0) as usize, 28))
+            } else {
+                Ok(u32::from_be_bytes([buf[24], buf[25], buf[26], buf[27]]))
+            }));
+        });
+        let c = Rc::new(Completion::Read(ReadCompletion::new(buf, complete)));
+        self.page_io.read_page(1, c)?;
+        loop {
+            if let Some(change_counter) = change_counter.take() {
+                return change_counter;
+            }
+            self.io.run_once()?;
+        }
     }
 }
 
@@ -669,3 +817,183 @@ pub fn allocate_page(
     }
     page_ref
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::btree::tests::{open_test_database, test_io};
+    use crate::storage::btree::{scan_with_page, BTreeCursor};
+    use crate::Database;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(512)]
+    #[case(1024)]
+    #[case(8192)]
+    #[case(65536)]
+    fn test_page_buffers_match_page_size(#[case] page_size: usize) {
+        let (_dir, db) = open_test_database(&format!(
+            "PRAGMA page_size = {page_size};
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO t SELECT i, zeroblob(400) FROM n;"
+        ));
+        let database_size = db.header.borrow().database_size as usize;
+        for page_idx in 1..=database_size {
+            let page = db.pager.read_page_blocking(page_idx).unwrap();
+            let page = page.borrow();
+            assert_eq!(page.contents.as_ref().unwrap().as_ptr().len(), page_size);
+        }
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let cursor = BTreeCursor::new(db.pager.clone(), root_page, db.header.clone());
+        assert_eq!(scan_with_page(cursor).count(), 10);
+    }
+
+    #[test]
+    fn test_stream_to() {
+        let (dir, _db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             CREATE INDEX t_v ON t (v);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 50)
+             INSERT INTO t SELECT i, randomblob(300) FROM n;",
+        );
+        let path = dir.path().join("test.db");
+        let original = std::fs::read(&path).unwrap();
+        let mut file = original.clone();
+        file.extend([0xab; 3000]);
+        std::fs::write(&path, file).unwrap();
+        let db = Database::open_file(test_io(), path.to_str().unwrap()).unwrap();
+
+        let mut out = Vec::new();
+        let written = db.stream_to(&mut out).unwrap();
+        assert_eq!(written, out.len() as u64);
+        assert_eq!(out, original);
+
+        let copy = dir.path().join("copy.db");
+        std::fs::write(&copy, &out).unwrap();
+        let conn = rusqlite::Connection::open(&copy).unwrap();
+        let check: String = conn
+            .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(check, "ok");
+        let rows: i64 = conn
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 50);
+    }
+
+    #[rstest]
+    #[case::zero_size(0, None)]
+    #[case::stale_size(2, Some(12345))]
+    fn test_database_size_from_file(
+        #[case] database_size: u32,
+        #[case] version_valid_for: Option<u32>,
+    ) {
+        let (dir, _db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 20)
+             INSERT INTO t SELECT i, zeroblob(3000) FROM n;",
+        );
+        let path = dir.path().join("test.db");
+        let mut file = std::fs::read(&path).unwrap();
+        let page_count = file.len() / 4096;
+        file[28..32].copy_from_slice(&database_size.to_be_bytes());
+        if let Some(version_valid_for) = version_valid_for {
+            file[92..96].copy_from_slice(&version_valid_for.to_be_bytes());
+        }
+        std::fs::write(&path, file).unwrap();
+
+        let db = Database::open_file(test_io(), path.to_str().unwrap()).unwrap();
+        assert_eq!(db.header.borrow().database_size as usize, page_count);
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let cursor = BTreeCursor::new(db.pager.clone(), root_page, db.header.clone());
+        assert_eq!(scan_with_page(cursor).filter(|row| row.is_ok()).count(), 20);
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 20)
+             INSERT INTO t SELECT i, zeroblob(3000) FROM n;",
+        );
+        let pager = &db.pager;
+        pager.set_read_ahead(4);
+        assert_eq!(pager.read_ahead(), 4);
+        // The page cache holds 10 pages, so at most 5 are prefetched.
+        pager.set_read_ahead(100);
+        assert_eq!(pager.read_ahead(), 5);
+        pager.set_read_ahead(4);
+        pager.read_page_blocking(3).unwrap();
+        let mut page_cache = pager.page_cache.borrow_mut();
+        for page_idx in 4..=7 {
+            assert!(page_cache.contains_key(page_idx), "page {}", page_idx);
+        }
+        assert!(!page_cache.contains_key(8));
+    }
+
+    #[test]
+    fn test_read_page_detects_short_read() {
+        let (dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 5)
+             INSERT INTO t SELECT i, zeroblob(3000) FROM n;",
+        );
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(dir.path().join("test.db"))
+            .unwrap();
+        let len = file.metadata().unwrap().len();
+        let last_page = (len / 4096) as usize;
+        file.set_len(len - 100).unwrap();
+        assert!(db.pager.read_page_blocking(last_page - 1).is_ok());
+        assert!(db.pager.read_page_blocking(last_page).is_err());
+    }
+
+    #[test]
+    fn test_read_ahead_ignores_errors() {
+        use crate::storage::database::{FileStorage, ReadQuota};
+        use crate::storage::wal::WalFile;
+        use crate::LimboError;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let (dir, _db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 20)
+             INSERT INTO t SELECT i, zeroblob(3000) FROM n;",
+        );
+        let path = dir.path().to_str().unwrap().to_owned() + "/test.db";
+        let open = |limit| {
+            let io = test_io();
+            let file = io
+                .open_file(&path, crate::io::OpenFlags::None, false)
+                .unwrap();
+            let quota = Rc::new(ReadQuota::new(Rc::new(FileStorage::new(file)), limit));
+            let wal = Rc::new(RefCell::new(WalFile::new(
+                io.clone(),
+                format!("{}-wal", path),
+                4096,
+            )));
+            (
+                Database::open(io.clone(), quota.clone(), wal).unwrap(),
+                quota,
+            )
+        };
+        let opened = open(usize::MAX).1.used();
+        // Enough quota for one more page, so prefetching the pages after it fails.
+        let (db, quota) = open(opened + 4096);
+        db.pager.set_read_ahead(4);
+        assert!(db.pager.read_page_blocking(3).is_ok());
+        assert!(!db.pager.page_cache.borrow_mut().contains_key(4));
+        assert!(matches!(
+            db.pager.read_page_blocking(4),
+            Err(LimboError::QuotaExceeded(_))
+        ));
+        assert_eq!(quota.used(), opened + 4096);
+    }
+}
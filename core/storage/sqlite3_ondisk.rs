@@ -43,24 +43,44 @@
 
 use crate::error::LimboError;
 use crate::io::{Buffer, Completion, ReadCompletion, SyncCompletion, WriteCompletion};
+use crate::schema::{Column, Type};
 use crate::storage::buffer_pool::BufferPool;
 use crate::storage::database::DatabaseStorage;
 use crate::storage::pager::{Page, Pager};
 use crate::types::{OwnedRecord, OwnedValue};
-use crate::{File, Result};
-use log::trace;
+use crate::{File, Result, IO};
+use log::{debug, trace, warn};
 use std::cell::RefCell;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::sync::Arc;
 
 /// The size of the database header in bytes.
 pub const DATABASE_HEADER_SIZE: usize = 100;
+/// The smallest page size, and so the smallest read `DatabaseStorage` serves. The
+/// database header is read with a read of this size, as the page size is unknown
+/// until the header is decoded.
+pub const MIN_PAGE_SIZE: usize = 512;
+pub const MAX_PAGE_SIZE: usize = 65536;
+
+/// Returns true if `size` is a valid page size: a power of two between
+/// [`MIN_PAGE_SIZE`] and [`MAX_PAGE_SIZE`].
+pub fn is_valid_page_size(size: usize) -> bool {
+    (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&size) && size.is_power_of_two()
+}
 // DEFAULT_CACHE_SIZE negative values mean that we store the amount of pages a XKiB of memory can hold.
 // We can calculate "real" cache size by diving by page size.
 const DEFAULT_CACHE_SIZE: i32 = -2000;
 // Minimum number of pages that cache can hold.
 pub const MIN_PAGE_CACHE_SIZE: usize = 10;
 
+/// The header string every SQLite database file starts with.
+pub const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+/// The most values a [`RecordReader`] accepts by default in a record header. SQLite tables have
+/// at most 32767 columns, and index records add the rowid to those.
+pub const DEFAULT_MAX_RECORD_COLUMNS: usize = 32768;
+
 #[derive(Debug, Clone)]
 pub struct DatabaseHeader {
     magic: [u8; 16],
@@ -95,7 +115,7 @@ pub const WAL_FRAME_HEADER_SIZE: usize = 24;
 pub const WAL_MAGIC_LE: u32 = 0x377f0682;
 pub const WAL_MAGIC_BE: u32 = 0x377f0683;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 #[repr(C)] // This helps with encoding because rust does not respect the order in structs, so in
            // this case we want to keep the order
 pub struct WalHeader {
@@ -123,7 +143,7 @@ pub struct WalFrameHeader {
 impl Default for DatabaseHeader {
     fn default() -> Self {
         Self {
-            magic: *b"SQLite format 3\0",
+            magic: *SQLITE_MAGIC,
             page_size: 4096,
             write_version: 2,
             read_version: 2,
@@ -150,29 +170,384 @@ impl Default for DatabaseHeader {
     }
 }
 
+/// File format version stored in the `write_version` and `read_version` header fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormatVersion {
+    /// Rollback journal mode.
+    Legacy,
+    /// Write-ahead log mode.
+    Wal,
+    /// A version this build does not know about.
+    Unknown(u8),
+}
+
+impl From<u8> for FileFormatVersion {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Legacy,
+            2 => Self::Wal,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+/// Encoding of the text values of a database, stored in the `text_encoding` header
+/// field. Schema SQL is stored in the same encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16le,
+    Utf16be,
+}
+
+impl TextEncoding {
+    /// Decodes text stored in this encoding. Invalid sequences are replaced, and a
+    /// trailing odd byte of UTF-16 text is ignored.
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        let units = bytes.chunks_exact(2);
+        match self {
+            Self::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Self::Utf16le => String::from_utf16_lossy(
+                &units
+                    .map(|u| u16::from_le_bytes([u[0], u[1]]))
+                    .collect::<Vec<_>>(),
+            ),
+            Self::Utf16be => String::from_utf16_lossy(
+                &units
+                    .map(|u| u16::from_be_bytes([u[0], u[1]]))
+                    .collect::<Vec<_>>(),
+            ),
+        }
+    }
+
+    /// Decodes text stored in this encoding, failing on invalid UTF-8, or on a
+    /// UTF-16 value with an unpaired surrogate or an odd number of bytes.
+    pub fn try_decode(&self, bytes: &[u8]) -> Result<String> {
+        if *self == Self::Utf8 {
+            return String::from_utf8(bytes.to_vec()).map_err(|e| {
+                LimboError::Corrupt(format!(
+                    "UTF-8 text is invalid at byte {}",
+                    e.utf8_error().valid_up_to()
+                ))
+            });
+        }
+        if !bytes.len().is_multiple_of(2) {
+            crate::bail_corrupt_error!("UTF-16 text has an odd length of {} bytes", bytes.len());
+        }
+        let units = bytes.chunks_exact(2).map(|u| match self {
+            Self::Utf16le => u16::from_le_bytes([u[0], u[1]]),
+            _ => u16::from_be_bytes([u[0], u[1]]),
+        });
+        char::decode_utf16(units)
+            .collect::<std::result::Result<String, _>>()
+            .map_err(|e| {
+                LimboError::Corrupt(format!(
+                    "UTF-16 text has an unpaired surrogate 0x{:04x}",
+                    e.unpaired_surrogate()
+                ))
+            })
+    }
+}
+
+impl DatabaseHeader {
+    /// Returns the text encoding of the database. As in SQLite, a zero encoding
+    /// means UTF-8.
+    pub fn text_encoding(&self) -> Result<TextEncoding> {
+        match self.text_encoding {
+            0 | 1 => Ok(TextEncoding::Utf8),
+            2 => Ok(TextEncoding::Utf16le),
+            3 => Ok(TextEncoding::Utf16be),
+            n => crate::bail_corrupt_error!("Invalid text encoding: {}", n),
+        }
+    }
+
+    /// Returns the page size in bytes. A page size of 65536 does not fit the header
+    /// field, in which it is stored as 1.
+    pub fn page_size(&self) -> u32 {
+        match self.page_size {
+            1 => 65536,
+            n => n as u32,
+        }
+    }
+
+    /// Returns the usable size of a page, i.e. its size minus the bytes reserved at
+    /// the end of each page.
+    pub fn usable_size(&self) -> usize {
+        self.page_size() as usize - self.unused_space as usize
+    }
+
+    pub fn read_version(&self) -> FileFormatVersion {
+        self.read_version.into()
+    }
+
+    pub fn write_version(&self) -> FileFormatVersion {
+        self.write_version.into()
+    }
+
+    /// Returns false when the database must be treated as read-only: its write
+    /// version is from a newer file format, its pages have 65536 usable bytes,
+    /// more than the b-tree write path addresses with 16-bit offsets, or its text
+    /// is not UTF-8, the only encoding records are written in.
+    pub fn can_write(&self) -> bool {
+        self.write_version <= 2
+            && self.usable_size() <= u16::MAX as usize
+            && matches!(self.text_encoding(), Ok(TextEncoding::Utf8))
+    }
+
+    /// Returns true if the database is in WAL mode, where readers do not block the
+    /// writer nor the writer the readers. In rollback journal mode the writer needs
+    /// an exclusive lock.
+    pub fn supports_concurrent_readers(&self) -> bool {
+        self.read_version() == FileFormatVersion::Wal
+            && self.write_version() == FileFormatVersion::Wal
+    }
+
+    pub fn change_counter(&self) -> u32 {
+        self.change_counter
+    }
+
+    /// Increments the file change counter, as done on every committed write.
+    ///
+    /// The version-valid-for number is kept in sync, so that the SQLite version
+    /// number stays valid.
+    pub fn bump_change_counter(&mut self) {
+        self.change_counter = self.change_counter.wrapping_add(1);
+        self.version_valid_for = self.change_counter;
+    }
+
+    /// Returns the first freelist trunk page, or 0 if the freelist is empty.
+    pub fn freelist_trunk_page(&self) -> u32 {
+        self.freelist_trunk_page
+    }
+
+    /// Returns the total number of freelist pages, trunks included.
+    pub fn freelist_pages(&self) -> u32 {
+        self.freelist_pages
+    }
+
+    /// Returns true for auto-vacuum and incremental-vacuum databases, which keep
+    /// pointer map pages.
+    pub fn is_auto_vacuum(&self) -> bool {
+        self.vacuum != 0
+    }
+
+    /// Returns true if the database size and the SQLite version number were
+    /// written along with the last change counter. Versions of SQLite that do not
+    /// maintain them leave the version-valid-for number behind.
+    pub fn in_header_size_trustworthy(&self) -> bool {
+        self.version_valid_for == self.change_counter
+    }
+
+    /// Returns false if the in-header database size cannot be trusted: it is zero,
+    /// or it is stale, see [`DatabaseHeader::in_header_size_trustworthy`].
+    pub fn is_database_size_valid(&self) -> bool {
+        self.database_size != 0 && self.in_header_size_trustworthy()
+    }
+
+    /// Returns the schema cookie, incremented whenever the schema changes.
+    pub fn schema_cookie(&self) -> u32 {
+        self.schema_cookie
+    }
+
+    /// Returns the schema format number, from 1 to 4.
+    pub fn schema_format(&self) -> u32 {
+        self.schema_format
+    }
+}
+
+/// Returns a copy of `header` upgraded to schema format `new_schema_format`.
+///
+/// Formats 1 to 4 are supported. Moving to a newer format bumps the schema
+/// cookie, so that connections reload the schema. Downgrades are rejected: a
+/// database may already use features that older formats cannot describe, such
+/// as descending indexes in format 4.
+pub fn migrate_header(header: &DatabaseHeader, new_schema_format: u32) -> Result<DatabaseHeader> {
+    if !(1..=4).contains(&new_schema_format) {
+        return Err(LimboError::InternalError(format!(
+            "unsupported schema format {}",
+            new_schema_format
+        )));
+    }
+    if new_schema_format < header.schema_format {
+        return Err(LimboError::InternalError(format!(
+            "cannot downgrade schema format {} to {}",
+            header.schema_format, new_schema_format
+        )));
+    }
+    let mut header = header.clone();
+    if new_schema_format != header.schema_format {
+        header.schema_format = new_schema_format;
+        header.schema_cookie = header.schema_cookie.wrapping_add(1);
+    }
+    Ok(header)
+}
+
 pub fn begin_read_database_header(
     page_io: Rc<dyn DatabaseStorage>,
+) -> Result<Rc<RefCell<DatabaseHeader>>> {
+    begin_read_database_header_tracked(page_io, Rc::new(RefCell::new(None)))
+}
+
+/// Reads the database header, running `io` until its read has completed, and
+/// returns the error of a short read.
+///
+/// A single run of the IO is not enough: it may complete another pending read
+/// first, leaving the default header in place.
+pub fn read_database_header(
+    io: &dyn IO,
+    page_io: Rc<dyn DatabaseStorage>,
+) -> Result<Rc<RefCell<DatabaseHeader>>> {
+    let outcome = Rc::new(RefCell::new(None));
+    let header = begin_read_database_header_tracked(page_io, outcome.clone())?;
+    loop {
+        if let Some(result) = outcome.borrow_mut().take() {
+            result?;
+            return Ok(header);
+        }
+        io.run_once()?;
+    }
+}
+
+/// Begins reading the database header, storing the outcome of the read in
+/// `outcome` once it completes.
+fn begin_read_database_header_tracked(
+    page_io: Rc<dyn DatabaseStorage>,
+    outcome: Rc<RefCell<Option<Result<()>>>>,
 ) -> Result<Rc<RefCell<DatabaseHeader>>> {
     let drop_fn = Rc::new(|_buf| {});
-    let buf = Rc::new(RefCell::new(Buffer::allocate(512, drop_fn)));
+    let buf = Rc::new(RefCell::new(Buffer::allocate(MIN_PAGE_SIZE, drop_fn)));
     let result = Rc::new(RefCell::new(DatabaseHeader::default()));
     let header = result.clone();
-    let complete = Box::new(move |buf: Rc<RefCell<Buffer>>| {
-        let header = header.clone();
-        finish_read_database_header(buf, header).unwrap();
+    let storage = page_io.clone();
+    let complete = Box::new(move |buf: Rc<RefCell<Buffer>>, bytes_read: i32| {
+        if let Err(err) = finish_read_database_header(buf, bytes_read, header.clone()) {
+            *outcome.borrow_mut() = Some(Err(err));
+            return;
+        }
+        let mut header = header.borrow_mut();
+        // A header failing validation, e.g. that of a file that is not a database,
+        // is rejected once the read completes, so its page size is not trusted here.
+        if validate_header_invariants(&header).is_ok() && !header.is_database_size_valid() {
+            // As in SQLite, fall back to the size of the file.
+            if let Ok(Some(file_size)) = storage.size() {
+                header.database_size = (file_size / header.page_size() as u64) as u32;
+            }
+        }
+        *outcome.borrow_mut() = Some(Ok(()));
     });
     let c = Rc::new(Completion::Read(ReadCompletion::new(buf, complete)));
     page_io.read_page(1, c.clone())?;
     Ok(result)
 }
 
+/// The page layout of a database file, for sizing buffers and caches before any
+/// b-tree page is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DatabaseGeometry {
+    pub page_size: u32,
+    /// Bytes reserved at the end of every page.
+    pub reserved_bytes: u8,
+    /// The number of pages, from the file size when the in-header size is not
+    /// trustworthy, as on open.
+    pub page_count: u32,
+    pub usable_size: u32,
+}
+
+/// Reads the geometry of the database in `page_io` from a single read of its
+/// header.
+pub fn read_geometry(
+    io: &Arc<dyn IO>,
+    page_io: Rc<dyn DatabaseStorage>,
+) -> Result<DatabaseGeometry> {
+    let header = read_database_header(io.as_ref(), page_io)?;
+    let header = header.borrow();
+    validate_header_invariants(&header)?;
+    Ok(DatabaseGeometry {
+        page_size: header.page_size(),
+        reserved_bytes: header.unused_space,
+        page_count: header.database_size,
+        usable_size: header.usable_size() as u32,
+    })
+}
+
+/// Checks the fields of a database header that have a single valid value, or a
+/// small set of them: the header string, the page size, the payload fractions,
+/// and the file format versions.
+///
+/// A file that does not start with the header string is not a database at all,
+/// which is reported as `LimboError::NotADB`; other violations as corruption.
+pub fn validate_header_invariants(header: &DatabaseHeader) -> Result<()> {
+    if &header.magic != SQLITE_MAGIC {
+        return Err(LimboError::NotADB);
+    }
+    let page_size = header.page_size();
+    if !is_valid_page_size(page_size as usize) {
+        crate::bail_corrupt_error!("Invalid page size: {}", page_size);
+    }
+    if (
+        header.max_embed_frac,
+        header.min_embed_frac,
+        header.min_leaf_frac,
+    ) != (64, 32, 32)
+    {
+        crate::bail_corrupt_error!(
+            "Invalid payload fractions: {}/{}/{}, expected 64/32/32",
+            header.max_embed_frac,
+            header.min_embed_frac,
+            header.min_leaf_frac
+        );
+    }
+    // A newer write version only makes the database read-only, see `can_write`.
+    if header.write_version == 0 {
+        crate::bail_corrupt_error!("Invalid write version: 0");
+    }
+    if let FileFormatVersion::Unknown(version) = header.read_version() {
+        crate::bail_corrupt_error!("Unsupported read version: {}", version);
+    }
+    Ok(())
+}
+
 fn finish_read_database_header(
     buf: Rc<RefCell<Buffer>>,
+    bytes_read: i32,
     header: Rc<RefCell<DatabaseHeader>>,
 ) -> Result<()> {
+    if bytes_read < DATABASE_HEADER_SIZE as i32 {
+        return Err(LimboError::ShortRead(
+            bytes_read as usize,
+            DATABASE_HEADER_SIZE,
+        ));
+    }
     let buf = buf.borrow();
-    let buf = buf.as_slice();
     let mut header = std::cell::RefCell::borrow_mut(&header);
+    read_header_from_buf(&buf.as_slice()[..DATABASE_HEADER_SIZE], &mut header);
+    Ok(())
+}
+
+/// Reads page 1 and decodes both the database header and the b-tree page that
+/// follows it from the same buffer.
+///
+/// Page 1 is the root of the schema table, so this gives a consistent view of the
+/// header and the schema root without reading the page twice. The page is left in
+/// the page cache for subsequent schema reads.
+pub fn read_page1(pager: &Pager) -> Result<(DatabaseHeader, Rc<RefCell<Page>>)> {
+    let page = pager.read_page_blocking(1)?;
+    let mut header = DatabaseHeader::default();
+    {
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        read_header_from_buf(contents.as_ptr(), &mut header);
+        contents.maybe_page_type().ok_or_else(|| {
+            LimboError::Corrupt(format!(
+                "Invalid page type on page 1: {}",
+                contents.read_u8(0)
+            ))
+        })?;
+    }
+    Ok((header, page))
+}
+
+fn read_header_from_buf(buf: &[u8], header: &mut DatabaseHeader) {
     header.magic.copy_from_slice(&buf[0..16]);
     header.page_size = u16::from_be_bytes([buf[16], buf[17]]);
     header.write_version = buf[18];
@@ -199,7 +574,6 @@ fn finish_read_database_header(
     header.reserved.copy_from_slice(&buf[72..92]);
     header.version_valid_for = u32::from_be_bytes([buf[92], buf[93], buf[94], buf[95]]);
     header.version_number = u32::from_be_bytes([buf[96], buf[97], buf[98], buf[99]]);
-    Ok(())
 }
 
 pub fn begin_write_database_header(header: &DatabaseHeader, pager: &Pager) -> Result<()> {
@@ -207,11 +581,11 @@ pub fn begin_write_database_header(header: &DatabaseHeader, pager: &Pager) -> Re
     let page_source = pager.page_io.clone();
 
     let drop_fn = Rc::new(|_buf| {});
-    let buffer_to_copy = Rc::new(RefCell::new(Buffer::allocate(512, drop_fn)));
+    let buffer_to_copy = Rc::new(RefCell::new(Buffer::allocate(MIN_PAGE_SIZE, drop_fn)));
     let buffer_to_copy_in_cb = buffer_to_copy.clone();
 
     let header_cb = header.clone();
-    let complete = Box::new(move |buffer: Rc<RefCell<Buffer>>| {
+    let complete = Box::new(move |buffer: Rc<RefCell<Buffer>>, _bytes_read: i32| {
         let header = header_cb.clone();
         let buffer: Buffer = buffer.borrow().clone();
         let buffer = Rc::new(RefCell::new(buffer));
@@ -227,7 +601,7 @@ pub fn begin_write_database_header(header: &DatabaseHeader, pager: &Pager) -> Re
     });
 
     let drop_fn = Rc::new(|_buf| {});
-    let buf = Rc::new(RefCell::new(Buffer::allocate(512, drop_fn)));
+    let buf = Rc::new(RefCell::new(Buffer::allocate(MIN_PAGE_SIZE, drop_fn)));
     let c = Rc::new(Completion::Read(ReadCompletion::new(buf.clone(), complete)));
     page_source.read_page(1, c.clone())?;
     // run get header block
@@ -356,7 +730,7 @@ impl PageContent {
         u16::from_be_bytes([buf[self.offset + pos], buf[self.offset + pos + 1]])
     }
 
-    fn read_u32(&self, pos: usize) -> u32 {
+    pub fn read_u32(&self, pos: usize) -> u32 {
         let buf = self.as_ptr();
         u32::from_be_bytes([
             buf[self.offset + pos],
@@ -430,6 +804,21 @@ impl PageContent {
         assert!(idx < ncells, "cell_get: idx out of bounds");
         let cell_pointer = cell_start + (idx * 2);
         let cell_pointer = self.read_u16(cell_pointer) as usize;
+        // On page 1 the database header precedes the b-tree page and never holds cells.
+        if cell_pointer < self.offset {
+            crate::bail_corrupt_error!(
+                "Cell {} points into the database header at offset {}",
+                idx,
+                cell_pointer
+            );
+        }
+        if cell_pointer >= usable_size {
+            crate::bail_corrupt_error!(
+                "Cell {} points past the usable space of the page at offset {}",
+                idx,
+                cell_pointer
+            );
+        }
 
         read_btree_cell(
             buf,
@@ -440,6 +829,7 @@ impl PageContent {
             min_local,
             usable_size,
         )
+        .map_err(|e| e.context(|| format!("at offset {}", cell_pointer)))
     }
 
     /// When using this fu
@@ -513,6 +903,11 @@ impl PageContent {
         (start, len)
     }
 
+    /// Returns true for a leaf page without cells, e.g. after all its rows were deleted.
+    pub fn is_empty_leaf(&self) -> bool {
+        self.is_leaf() && self.cell_count() == 0
+    }
+
     pub fn is_leaf(&self) -> bool {
         match self.page_type() {
             PageType::IndexInterior => false,
@@ -536,13 +931,37 @@ pub fn begin_read_page(
 ) -> Result<()> {
     trace!("begin_read_btree_page(page_idx = {})", page_idx);
     let buf = buffer_pool.get();
+    // A buffer of another size would read part of a page, or past its end.
+    if buf.len() != buffer_pool.page_size() {
+        let len = buf.len();
+        buffer_pool.put(buf);
+        return Err(LimboError::InternalError(format!(
+            "buffer of {} bytes for page {}, expected {}",
+            len,
+            page_idx,
+            buffer_pool.page_size()
+        )));
+    }
     let drop_fn = Rc::new(move |buf| {
         let buffer_pool = buffer_pool.clone();
         buffer_pool.put(buf);
     });
     let buf = Rc::new(RefCell::new(Buffer::new(buf, drop_fn)));
-    let complete = Box::new(move |buf: Rc<RefCell<Buffer>>| {
+    let complete = Box::new(move |buf: Rc<RefCell<Buffer>>, bytes_read: i32| {
         let page = page.clone();
+        let expected = buf.borrow().len();
+        if bytes_read < expected as i32 {
+            log::error!(
+                "short read of page {}: got {} of {} bytes",
+                page_idx,
+                bytes_read,
+                expected
+            );
+            let page = page.borrow();
+            page.set_error();
+            page.clear_locked();
+            return;
+        }
         if finish_read_page(page_idx, buf, page.clone()).is_err() {
             page.borrow_mut().set_error();
         }
@@ -557,7 +976,7 @@ fn finish_read_page(
     buffer_ref: Rc<RefCell<Buffer>>,
     page: Rc<RefCell<Page>>,
 ) -> Result<()> {
-    trace!("finish_read_btree_page(page_idx = {})", page_idx);
+    debug!("finish_read_btree_page(page_idx = {})", page_idx);
     let pos = if page_idx == 1 {
         DATABASE_HEADER_SIZE
     } else {
@@ -593,6 +1012,15 @@ pub fn begin_write_btree_page(
         let contents = page.contents.as_ref().unwrap();
         contents.buffer.clone()
     };
+    let buffer_size = buffer.borrow().len();
+    if buffer_size != pager.page_size() {
+        return Err(LimboError::InternalError(format!(
+            "cannot write page {}: {} bytes, the page size is {}",
+            page_id,
+            buffer_size,
+            pager.page_size()
+        )));
+    }
 
     *write_counter.borrow_mut() += 1;
     let write_complete = {
@@ -643,11 +1071,21 @@ pub struct TableInteriorCell {
 
 #[derive(Debug, Clone)]
 pub struct TableLeafCell {
+    /// The rowid varint as stored. Negative rowids are valid and are stored as
+    /// their two's complement, so values above `i64::MAX` are not corrupt.
     pub _rowid: u64,
     pub _payload: Vec<u8>,
     pub first_overflow_page: Option<u32>,
 }
 
+impl TableLeafCell {
+    /// Decodes the row stored in the cell. Cells keep their payload undecoded, so
+    /// callers that only need the rowid do not pay for decoding the row.
+    pub fn record(&self, options: RecordOptions) -> Result<OwnedRecord> {
+        RecordReader::new(&self._payload, options)?.record()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct IndexInteriorCell {
     pub left_child_page: u32,
@@ -661,6 +1099,39 @@ pub struct IndexLeafCell {
     pub first_overflow_page: Option<u32>,
 }
 
+/// Returns the declared payload size of the cell at `cell_pointer`, including the
+/// part stored on overflow pages, without reading the payload itself.
+///
+/// Table interior cells have no payload, so their size is 0.
+pub fn cell_payload_size(page: &[u8], page_type: &PageType, cell_pointer: usize) -> Result<u64> {
+    let pos = match page_type {
+        PageType::TableInterior => return Ok(0),
+        PageType::IndexInterior => cell_pointer + 4,
+        PageType::TableLeaf | PageType::IndexLeaf => cell_pointer,
+    };
+    if pos >= page.len() {
+        crate::bail_corrupt_error!(
+            "Cell at offset {} is past the end of the page",
+            cell_pointer
+        );
+    }
+    let (payload_size, _) = read_varint(&page[pos..])?;
+    Ok(payload_size)
+}
+
+/// Returns whether the payload of the cell at `cell_pointer` spills onto overflow
+/// pages, without reading the payload.
+pub fn cell_has_overflow(
+    page: &[u8],
+    page_type: &PageType,
+    cell_pointer: usize,
+    usable_size: usize,
+) -> Result<bool> {
+    let payload_size = cell_payload_size(page, page_type, cell_pointer)?;
+    let (max_local, _) = payload_thresholds(page_type, usable_size);
+    Ok(payload_size as usize > max_local)
+}
+
 pub fn read_btree_cell(
     page: &[u8],
     page_type: &PageType,
@@ -670,86 +1141,253 @@ pub fn read_btree_cell(
     min_local: usize,
     usable_size: usize,
 ) -> Result<BTreeCell> {
-    match page_type {
-        PageType::IndexInterior => {
-            let mut pos = pos;
-            let left_child_page =
-                u32::from_be_bytes([page[pos], page[pos + 1], page[pos + 2], page[pos + 3]]);
+    parse_btree_cell(page, page_type, pos, max_local, min_local, usable_size)?
+        .to_owned(page_type, pager)
+}
+
+/// Reads the 4-byte big-endian integer at `pos` of `buf`.
+pub(crate) fn read_u32_at(buf: &[u8], pos: usize) -> Result<u32> {
+    match buf.get(pos..pos + 4) {
+        Some(bytes) => Ok(u32::from_be_bytes(bytes.try_into().unwrap())),
+        None => crate::bail_corrupt_error!("Offset {} is past the end of the page", pos),
+    }
+}
+
+/// Reads the varint at `pos` of `buf`.
+pub(crate) fn read_varint_at(buf: &[u8], pos: usize) -> Result<(u64, usize)> {
+    match buf.get(pos..) {
+        Some(bytes) => read_varint(bytes),
+        None => crate::bail_corrupt_error!("Offset {} is past the end of the page", pos),
+    }
+}
+
+/// Parses the cell at `pos` of a `page_type` page, borrowing the part of its
+/// payload stored on the page. Every other cell reader is built on this one.
+fn parse_btree_cell<'a>(
+    page: &'a [u8],
+    page_type: &PageType,
+    pos: usize,
+    max_local: usize,
+    min_local: usize,
+    usable_size: usize,
+) -> Result<ParsedCell<'a>> {
+    let mut pos = pos;
+    let left_child_page = match page_type {
+        PageType::TableInterior | PageType::IndexInterior => {
+            let child = read_u32_at(page, pos)?;
             pos += 4;
-            let (payload_size, nr) = read_varint(&page[pos..])?;
-            pos += nr;
+            Some(child)
+        }
+        PageType::TableLeaf | PageType::IndexLeaf => None,
+    };
+    if *page_type == PageType::TableInterior {
+        let (rowid, _) = read_varint_at(page, pos)?;
+        return Ok(ParsedCell {
+            left_child_page,
+            rowid: Some(rowid),
+            payload_size: 0,
+            local_payload: &[],
+            first_overflow_page: None,
+        });
+    }
+    let (payload_size, nr) = read_varint_at(page, pos)?;
+    pos += nr;
+    let rowid = if *page_type == PageType::TableLeaf {
+        let (rowid, nr) = read_varint_at(page, pos)?;
+        pos += nr;
+        Some(rowid)
+    } else {
+        None
+    };
+    let (overflows, to_read) =
+        payload_overflows(payload_size as usize, max_local, min_local, usable_size);
+    let local_size = if overflows {
+        to_read - 4
+    } else {
+        payload_size as usize
+    };
+    let Some(local_payload) = page.get(pos..pos + local_size) else {
+        crate::bail_corrupt_error!(
+            "Payload of {} bytes at offset {} runs past the end of the page",
+            local_size,
+            pos
+        );
+    };
+    let first_overflow_page = if overflows {
+        Some(read_u32_at(page, pos + local_size)?)
+    } else {
+        None
+    };
+    Ok(ParsedCell {
+        left_child_page,
+        rowid,
+        payload_size,
+        local_payload,
+        first_overflow_page,
+    })
+}
 
-            let (overflows, to_read) =
-                payload_overflows(payload_size as usize, max_local, min_local, usable_size);
-            let to_read = if overflows { to_read } else { page.len() - pos };
+/// A b-tree page whose cells borrow from the page buffer, see [`parse_btree_page`].
+#[derive(Debug)]
+pub struct ParsedPage<'a> {
+    pub page_type: PageType,
+    pub cells: Vec<ParsedCell<'a>>,
+    pub rightmost_pointer: Option<u32>,
+}
 
-            let (payload, first_overflow_page) =
-                read_payload(&page[pos..pos + to_read], payload_size as usize, pager);
-            Ok(BTreeCell::IndexInteriorCell(IndexInteriorCell {
-                left_child_page,
-                payload,
+/// A cell of a [`ParsedPage`]. Only the part of the payload stored on the page is
+/// borrowed; the rest is on the overflow pages from `first_overflow_page`.
+#[derive(Debug)]
+pub struct ParsedCell<'a> {
+    pub left_child_page: Option<u32>,
+    /// The rowid of a table cell.
+    pub rowid: Option<u64>,
+    pub payload_size: u64,
+    pub local_payload: &'a [u8],
+    pub first_overflow_page: Option<u32>,
+}
+
+/// Parses the cells of a b-tree page without copying their payloads, for scans
+/// that only look at the page itself.
+pub fn parse_btree_page(page: &PageContent, usable_size: usize) -> Result<ParsedPage<'_>> {
+    let page_type = page.page_type();
+    let (max_local, min_local) = payload_thresholds(&page_type, usable_size);
+    let buf: &[u8] = page.as_ptr();
+    let (cell_pointers, _) = page.cell_get_raw_pointer_region();
+    let mut cells = Vec::with_capacity(page.cell_count());
+    for cell_idx in 0..page.cell_count() {
+        let Some(pointer) = buf.get(cell_pointers + cell_idx * 2..cell_pointers + cell_idx * 2 + 2)
+        else {
+            crate::bail_corrupt_error!("Cell pointer {} is past the end of the page", cell_idx);
+        };
+        let pos = u16::from_be_bytes([pointer[0], pointer[1]]) as usize;
+        let cell = parse_btree_cell(buf, &page_type, pos, max_local, min_local, usable_size)
+            .map_err(|e| e.context(|| format!("in cell {} at offset {}", cell_idx, pos)))?;
+        cells.push(cell);
+    }
+    Ok(ParsedPage {
+        page_type,
+        cells,
+        rightmost_pointer: page.rightmost_pointer(),
+    })
+}
+
+impl ParsedCell<'_> {
+    /// Copies the cell of a `page_type` page into an owned [`BTreeCell`], reading
+    /// the overflow pages of a payload that spills.
+    pub fn to_owned(&self, page_type: &PageType, pager: Rc<Pager>) -> Result<BTreeCell> {
+        let (payload, first_overflow_page) = match self.first_overflow_page {
+            Some(first_overflow_page) => {
+                let mut stored = self.local_payload.to_vec();
+                stored.extend_from_slice(&first_overflow_page.to_be_bytes());
+                read_payload(&stored, self.payload_size as usize, pager)?
+            }
+            None => (self.local_payload.to_vec(), None),
+        };
+        Ok(match page_type {
+            PageType::TableInterior => BTreeCell::TableInteriorCell(TableInteriorCell {
+                _left_child_page: self.left_child_page.unwrap(),
+                _rowid: self.rowid.unwrap(),
+            }),
+            PageType::TableLeaf => BTreeCell::TableLeafCell(TableLeafCell {
+                _rowid: self.rowid.unwrap(),
+                _payload: payload,
                 first_overflow_page,
-            }))
-        }
-        PageType::TableInterior => {
-            let mut pos = pos;
-            let left_child_page =
-                u32::from_be_bytes([page[pos], page[pos + 1], page[pos + 2], page[pos + 3]]);
-            pos += 4;
-            let (rowid, _) = read_varint(&page[pos..])?;
-            Ok(BTreeCell::TableInteriorCell(TableInteriorCell {
-                _left_child_page: left_child_page,
-                _rowid: rowid,
-            }))
-        }
-        PageType::IndexLeaf => {
-            let mut pos = pos;
-            let (payload_size, nr) = read_varint(&page[pos..])?;
-            pos += nr;
-
-            let (overflows, to_read) =
-                payload_overflows(payload_size as usize, max_local, min_local, usable_size);
-            let to_read = if overflows { to_read } else { page.len() - pos };
-
-            let (payload, first_overflow_page) =
-                read_payload(&page[pos..pos + to_read], payload_size as usize, pager);
-            Ok(BTreeCell::IndexLeafCell(IndexLeafCell {
+            }),
+            PageType::IndexInterior => BTreeCell::IndexInteriorCell(IndexInteriorCell {
+                left_child_page: self.left_child_page.unwrap(),
                 payload,
                 first_overflow_page,
-            }))
-        }
-        PageType::TableLeaf => {
-            let mut pos = pos;
-            let (payload_size, nr) = read_varint(&page[pos..])?;
-            pos += nr;
-            let (rowid, nr) = read_varint(&page[pos..])?;
-            pos += nr;
-
-            let (overflows, to_read) =
-                payload_overflows(payload_size as usize, max_local, min_local, usable_size);
-            let to_read = if overflows { to_read } else { page.len() - pos };
-
-            let (payload, first_overflow_page) =
-                read_payload(&page[pos..pos + to_read], payload_size as usize, pager);
-            Ok(BTreeCell::TableLeafCell(TableLeafCell {
-                _rowid: rowid,
-                _payload: payload,
+            }),
+            PageType::IndexLeaf => BTreeCell::IndexLeafCell(IndexLeafCell {
+                payload,
                 first_overflow_page,
-            }))
+            }),
+        })
+    }
+}
+
+impl ParsedPage<'_> {
+    /// Copies the cells into owned [`BTreeCell`]s, reading the overflow pages of
+    /// payloads that spill.
+    pub fn to_owned(&self, pager: Rc<Pager>) -> Result<Vec<BTreeCell>> {
+        self.cells
+            .iter()
+            .map(|cell| cell.to_owned(&self.page_type, pager.clone()))
+            .collect()
+    }
+}
+
+/// Returns the full key payload of cell `idx` of an index page, including the part
+/// stored on overflow pages, ready for a [`RecordReader`].
+pub fn read_index_payload(
+    page: &PageContent,
+    idx: usize,
+    pager: Rc<Pager>,
+    usable_size: usize,
+) -> Result<Vec<u8>> {
+    let page_type = page.page_type();
+    let (max_local, min_local) = payload_thresholds(&page_type, usable_size);
+    match page.cell_get(idx, pager, max_local, min_local, usable_size)? {
+        BTreeCell::IndexInteriorCell(IndexInteriorCell { payload, .. })
+        | BTreeCell::IndexLeafCell(IndexLeafCell { payload, .. }) => Ok(payload),
+        _ => Err(LimboError::InternalError(format!(
+            "{:?} page has no index payloads",
+            page_type
+        ))),
+    }
+}
+
+/// Returns the bytes a defragmentation of `page` would reclaim from within the
+/// cell content area: the sizes of every freeblock in the chain plus the
+/// fragmented free bytes. Unlike the free space of a page, this leaves out the
+/// unallocated gap between the cell pointer array and the cell content area.
+pub fn total_freeblock_bytes(page: &PageContent) -> Result<usize> {
+    let buf = page.as_ptr();
+    let mut total = page.num_frag_free_bytes() as usize;
+    let mut pc = page.first_freeblock() as usize;
+    // Freeblocks are kept in ascending order, so each one must start past the end of
+    // the previous one. This also rules out loops in the chain.
+    let mut min_pc = page.cell_content_area() as usize;
+    while pc != 0 {
+        if pc < min_pc || pc + 4 > buf.len() {
+            crate::bail_corrupt_error!(
+                "Freeblock at offset {} is out of order or out of bounds",
+                pc
+            );
         }
+        let next = u16::from_be_bytes([buf[pc], buf[pc + 1]]) as usize;
+        let size = u16::from_be_bytes([buf[pc + 2], buf[pc + 3]]) as usize;
+        if size < 4 || pc + size > buf.len() {
+            crate::bail_corrupt_error!("Freeblock at offset {} has invalid size {}", pc, size);
+        }
+        total += size;
+        min_pc = pc + size;
+        pc = next;
     }
+    Ok(total)
 }
 
 /// read_payload takes in the unread bytearray with the payload size
 /// and returns the payload on the page, and optionally the first overflow page number.
 #[allow(clippy::readonly_write_lock)]
-fn read_payload(unread: &[u8], payload_size: usize, pager: Rc<Pager>) -> (Vec<u8>, Option<u32>) {
+fn read_payload(
+    unread: &[u8],
+    payload_size: usize,
+    pager: Rc<Pager>,
+) -> Result<(Vec<u8>, Option<u32>)> {
     let cell_len = unread.len();
     if payload_size <= cell_len {
         // fit within 1 page
-        (unread[..payload_size].to_vec(), None)
+        Ok((unread[..payload_size].to_vec(), None))
     } else {
         // overflow
+        if cell_len < 4 {
+            crate::bail_corrupt_error!(
+                "Overflowing cell of {} bytes has no room for an overflow page number",
+                cell_len
+            );
+        }
         let first_overflow_page = u32::from_be_bytes([
             unread[cell_len - 4],
             unread[cell_len - 3],
@@ -761,15 +1399,16 @@ fn read_payload(unread: &[u8], payload_size: usize, pager: Rc<Pager>) -> (Vec<u8
         let mut payload = unread[..cell_len - 4].to_vec();
         let mut left_to_read = payload_size - (cell_len - 4); // minus four because last for bytes of a payload cell are the overflow pointer
         while next_overflow != 0 {
-            assert!(left_to_read > 0);
-            let page;
-            loop {
-                let page_ref = pager.read_page(next_overflow as usize);
-                if let Ok(p) = page_ref {
-                    page = p;
-                    break;
-                }
+            // Every page in the chain holds part of the payload, so this bounds the
+            // pages followed and stops a chain that loops back on itself.
+            if left_to_read == 0 {
+                crate::bail_corrupt_error!(
+                    "Overflow chain from page {} is longer than its payload",
+                    first_overflow_page
+                );
             }
+            debug!("read_overflow_page(page_idx = {})", next_overflow);
+            let page = pager.read_page_blocking(next_overflow as usize)?;
             let mut page = page.borrow_mut();
             let contents = page.contents.as_mut().unwrap();
 
@@ -780,13 +1419,139 @@ fn read_payload(unread: &[u8], payload_size: usize, pager: Rc<Pager>) -> (Vec<u8
             next_overflow = contents.read_u32(0);
             left_to_read -= to_read;
         }
-        assert_eq!(left_to_read, 0);
+        if left_to_read != 0 {
+            crate::bail_corrupt_error!(
+                "Overflow chain from page {} ends {} bytes short of its payload",
+                first_overflow_page,
+                left_to_read
+            );
+        }
+
+        Ok((payload, Some(first_overflow_page)))
+    }
+}
+
+/// Lays out a b-tree page holding `cells` into a `page_size` buffer: the page
+/// header, the cell pointer array, and the cell content area growing down from
+/// `usable_size`. This is the inverse of parsing the page with
+/// [`PageContent::cell_get`]. On page 1 the first 100 bytes are left for the
+/// database header.
+pub fn serialize_btree_page(
+    page_type: PageType,
+    cells: &[BTreeCell],
+    rightmost_pointer: Option<u32>,
+    page_size: usize,
+    usable_size: usize,
+    page_idx: usize,
+) -> Result<Vec<u8>> {
+    let offset = if page_idx == 1 {
+        DATABASE_HEADER_SIZE
+    } else {
+        0
+    };
+    let header_size = match page_type {
+        PageType::IndexInterior | PageType::TableInterior => 12,
+        PageType::IndexLeaf | PageType::TableLeaf => 8,
+    };
+    let (max_local, min_local) = payload_thresholds(&page_type, usable_size);
+    let mut buf = vec![0; page_size];
+    let mut cell_pointer = offset + header_size;
+    let mut cell_content_area = usable_size;
+    let mut cell_offsets = Vec::with_capacity(cells.len());
+    for cell in cells {
+        let cell = serialize_btree_cell(&page_type, cell, max_local, min_local, usable_size)?;
+        if cell_pointer + 2 + cell.len() > cell_content_area {
+            return Err(LimboError::InternalError(format!(
+                "{} cells do not fit in a page of {} bytes",
+                cells.len(),
+                page_size
+            )));
+        }
+        cell_content_area -= cell.len();
+        buf[cell_content_area..cell_content_area + cell.len()].copy_from_slice(&cell);
+        cell_offsets.push(cell_content_area as u16);
+        cell_pointer += 2;
+    }
+    let cell_pointer_array = build_cell_pointer_array(&cell_offsets);
+    buf[offset + header_size..cell_pointer].copy_from_slice(&cell_pointer_array);
+    buf[offset] = page_type.clone() as u8;
+    buf[offset + 3..offset + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+    // A cell content area starting at 65536 is stored as zero.
+    buf[offset + 5..offset + 7].copy_from_slice(&(cell_content_area as u16).to_be_bytes());
+    if header_size == 12 {
+        let Some(rightmost_pointer) = rightmost_pointer else {
+            return Err(LimboError::InternalError(format!(
+                "{:?} page requires a rightmost pointer",
+                page_type
+            )));
+        };
+        buf[offset + 8..offset + 12].copy_from_slice(&rightmost_pointer.to_be_bytes());
+    }
+    Ok(buf)
+}
+
+/// Builds the cell pointer array of a page: the offset of each cell's content, in
+/// order, as 2-byte big-endian integers.
+pub fn build_cell_pointer_array(cell_offsets: &[u16]) -> Vec<u8> {
+    cell_offsets
+        .iter()
+        .flat_map(|offset| offset.to_be_bytes())
+        .collect()
+}
 
-        (payload, Some(first_overflow_page))
+fn serialize_btree_cell(
+    page_type: &PageType,
+    cell: &BTreeCell,
+    max_local: usize,
+    min_local: usize,
+    usable_size: usize,
+) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let (payload, first_overflow_page) = match (page_type, cell) {
+        (PageType::TableInterior, BTreeCell::TableInteriorCell(cell)) => {
+            buf.extend_from_slice(&cell._left_child_page.to_be_bytes());
+            write_varint_to_vec(cell._rowid, &mut buf);
+            return Ok(buf);
+        }
+        (PageType::TableLeaf, BTreeCell::TableLeafCell(cell)) => {
+            write_varint_to_vec(cell._payload.len() as u64, &mut buf);
+            write_varint_to_vec(cell._rowid, &mut buf);
+            (&cell._payload, cell.first_overflow_page)
+        }
+        (PageType::IndexInterior, BTreeCell::IndexInteriorCell(cell)) => {
+            buf.extend_from_slice(&cell.left_child_page.to_be_bytes());
+            write_varint_to_vec(cell.payload.len() as u64, &mut buf);
+            (&cell.payload, cell.first_overflow_page)
+        }
+        (PageType::IndexLeaf, BTreeCell::IndexLeafCell(cell)) => {
+            write_varint_to_vec(cell.payload.len() as u64, &mut buf);
+            (&cell.payload, cell.first_overflow_page)
+        }
+        _ => {
+            return Err(LimboError::InternalError(format!(
+                "cell does not belong on a {:?} page",
+                page_type
+            )))
+        }
+    };
+    let (overflows, local_size) =
+        payload_overflows(payload.len(), max_local, min_local, usable_size);
+    if overflows {
+        let Some(first_overflow_page) = first_overflow_page else {
+            return Err(LimboError::InternalError(
+                "overflowing cell has no overflow page".to_string(),
+            ));
+        };
+        // The local size includes the 4 byte pointer to the first overflow page.
+        buf.extend_from_slice(&payload[..local_size - 4]);
+        buf.extend_from_slice(&first_overflow_page.to_be_bytes());
+    } else {
+        buf.extend_from_slice(payload);
     }
+    Ok(buf)
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SerialType {
     Null,
     UInt8,
@@ -802,6 +1567,79 @@ pub enum SerialType {
     String(usize),
 }
 
+impl SerialType {
+    /// Returns the number of bytes a value of this serial type takes in a record body.
+    pub fn content_size(&self) -> usize {
+        match self {
+            SerialType::Null | SerialType::ConstInt0 | SerialType::ConstInt1 => 0,
+            SerialType::UInt8 => 1,
+            SerialType::BEInt16 => 2,
+            SerialType::BEInt24 => 3,
+            SerialType::BEInt32 => 4,
+            SerialType::BEInt48 => 6,
+            SerialType::BEInt64 | SerialType::BEFloat64 => 8,
+            SerialType::Blob(n) | SerialType::String(n) => *n,
+        }
+    }
+}
+
+impl From<SerialType> for u64 {
+    fn from(serial_type: SerialType) -> Self {
+        match serial_type {
+            SerialType::Null => 0,
+            SerialType::UInt8 => 1,
+            SerialType::BEInt16 => 2,
+            SerialType::BEInt24 => 3,
+            SerialType::BEInt32 => 4,
+            SerialType::BEInt48 => 5,
+            SerialType::BEInt64 => 6,
+            SerialType::BEFloat64 => 7,
+            SerialType::ConstInt0 => 8,
+            SerialType::ConstInt1 => 9,
+            SerialType::Blob(n) => (n * 2 + 12) as u64,
+            SerialType::String(n) => (n * 2 + 13) as u64,
+        }
+    }
+}
+
+/// Returns the serial type [`OwnedRecord::serialize_with_schema_format`] encodes
+/// `value` with in a database of schema format `schema_format`.
+///
+/// Integers take the narrowest signed serial type that holds them, as in SQLite.
+/// From schema format 4, 0 and 1 are stored in the serial type alone; older
+/// formats do not know those serial types.
+pub fn value_serial_type(value: &OwnedValue, schema_format: u32) -> SerialType {
+    match value {
+        OwnedValue::Null => SerialType::Null,
+        OwnedValue::Integer(0) if schema_format >= 4 => SerialType::ConstInt0,
+        OwnedValue::Integer(1) if schema_format >= 4 => SerialType::ConstInt1,
+        OwnedValue::Integer(i) => {
+            let fits = |bits: u32| *i >= -(1 << (bits - 1)) && *i < 1 << (bits - 1);
+            if fits(8) {
+                SerialType::UInt8
+            } else if fits(16) {
+                SerialType::BEInt16
+            } else if fits(24) {
+                SerialType::BEInt24
+            } else if fits(32) {
+                SerialType::BEInt32
+            } else if fits(48) {
+                SerialType::BEInt48
+            } else {
+                SerialType::BEInt64
+            }
+        }
+        // Always BEFloat64, even for integral values: the storage class of a
+        // value must survive the round trip.
+        OwnedValue::Float(_) => SerialType::BEFloat64,
+        OwnedValue::Text(t) => SerialType::String(t.len()),
+        OwnedValue::Blob(b) => SerialType::Blob(b.len()),
+        // not serializable values
+        OwnedValue::Agg(_) => unreachable!(),
+        OwnedValue::Record(_) => unreachable!(),
+    }
+}
+
 impl TryFrom<u64> for SerialType {
     type Error = crate::error::LimboError;
 
@@ -824,38 +1662,305 @@ impl TryFrom<u64> for SerialType {
     }
 }
 
-pub fn read_record(payload: &[u8]) -> Result<OwnedRecord> {
-    let mut pos = 0;
-    let (header_size, nr) = read_varint(payload)?;
-    assert!((header_size as usize) >= nr);
-    let mut header_size = (header_size as usize) - nr;
-    pos += nr;
-    let mut serial_types = Vec::with_capacity(header_size);
-    while header_size > 0 {
-        let (serial_type, nr) = read_varint(&payload[pos..])?;
-        let serial_type = SerialType::try_from(serial_type)?;
-        serial_types.push(serial_type);
-        pos += nr;
-        assert!(header_size >= nr);
-        header_size -= nr;
+/// How a [`RecordReader`] decodes a record.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordOptions {
+    /// The encoding text values are stored in.
+    pub encoding: TextEncoding,
+    /// The most values the record header may list; a longer header is corrupt.
+    pub max_columns: usize,
+    /// Treat bytes left over after the last value as corruption. They are
+    /// ignored otherwise.
+    pub strict: bool,
+    /// Decode a text value running past the end of the payload, as in a damaged
+    /// file, to the bytes that remain. Text values after the first truncated one
+    /// decode as empty, while other values still fail.
+    pub tolerant: bool,
+}
+
+impl Default for RecordOptions {
+    fn default() -> Self {
+        Self {
+            encoding: TextEncoding::Utf8,
+            max_columns: DEFAULT_MAX_RECORD_COLUMNS,
+            strict: false,
+            tolerant: false,
+        }
     }
-    let mut values = Vec::with_capacity(serial_types.len());
-    for serial_type in &serial_types {
-        let (value, n) = read_value(&payload[pos..], serial_type)?;
-        pos += n;
-        values.push(value);
+}
+
+impl RecordOptions {
+    /// Returns the default options for records of the database with `header`,
+    /// whose text is stored in the encoding of the header.
+    pub fn for_header(header: &DatabaseHeader) -> Result<Self> {
+        Ok(Self {
+            encoding: header.text_encoding()?,
+            ..Self::default()
+        })
     }
-    Ok(OwnedRecord::new(values))
 }
 
-pub fn read_value(buf: &[u8], serial_type: &SerialType) -> Result<(OwnedValue, usize)> {
-    match *serial_type {
-        SerialType::Null => Ok((OwnedValue::Null, 0)),
-        SerialType::UInt8 => {
+/// Reads a record. The header is read up front, and values are decoded on
+/// demand, either one at a time or all together.
+pub struct RecordReader<'a> {
+    payload: &'a [u8],
+    options: RecordOptions,
+    header_size: usize,
+    columns: Vec<(SerialType, usize)>,
+}
+
+impl<'a> RecordReader<'a> {
+    /// Reads the header of the record in `payload`.
+    pub fn new(payload: &'a [u8], options: RecordOptions) -> Result<Self> {
+        let (serial_types, header_size) = read_record_header(payload, options.max_columns)?;
+        let mut pos = header_size;
+        let mut columns = Vec::with_capacity(serial_types.len());
+        for serial_type in serial_types {
+            columns.push((serial_type, pos));
+            pos += serial_type.content_size();
+        }
+        Ok(Self {
+            payload,
+            options,
+            header_size,
+            columns,
+        })
+    }
+
+    /// Returns the serial type of each value together with the offset of the
+    /// value within the payload.
+    pub fn columns(&self) -> &[(SerialType, usize)] {
+        &self.columns
+    }
+
+    /// Returns the raw bytes of the record header.
+    pub fn header(&self) -> &'a [u8] {
+        &self.payload[..self.header_size]
+    }
+
+    /// Returns the raw bytes of the values. Writing the header and the values back
+    /// to back reproduces the record byte for byte, which re-encoding the decoded
+    /// values does not guarantee.
+    pub fn raw_values(&self) -> Result<&'a [u8]> {
+        let end = self.end();
+        match self.payload.get(self.header_size..end) {
+            Some(values) => Ok(values),
+            None => crate::bail_corrupt_error!(
+                "Record values end at offset {}, past the end of the {}-byte record",
+                end,
+                self.payload.len()
+            ),
+        }
+    }
+
+    /// Decodes value `column` without decoding the others.
+    pub fn value(&self, column: usize) -> Result<OwnedValue> {
+        let Some((serial_type, offset)) = self.columns.get(column) else {
+            return Err(LimboError::InternalError(format!(
+                "column {} is out of range, the record has {} columns",
+                column,
+                self.columns.len()
+            )));
+        };
+        let Some(buf) = self.payload.get(*offset..) else {
+            crate::bail_corrupt_error!(
+                "Value offset {} is past the end of the {}-byte record",
+                offset,
+                self.payload.len()
+            );
+        };
+        Ok(read_value(buf, serial_type, self.options.encoding)?.0)
+    }
+
+    /// Decodes every value of the record.
+    pub fn record(&self) -> Result<OwnedRecord> {
+        Ok(self.record_with_truncated()?.0)
+    }
+
+    /// Decodes every value of the record, also returning the text values cut
+    /// short by the end of the payload when the options are tolerant. Truncated
+    /// values are logged.
+    pub fn record_with_truncated(&self) -> Result<(OwnedRecord, Vec<TruncatedValue>)> {
+        let mut values = Vec::with_capacity(self.columns.len());
+        let mut truncated = Vec::new();
+        for (idx, (serial_type, offset)) in self.columns.iter().enumerate() {
+            let buf = self.payload.get(*offset..).unwrap_or_default();
+            match *serial_type {
+                SerialType::String(n) if self.options.tolerant && buf.len() < n => {
+                    warn!(
+                        "column {} is truncated to {} of {} bytes",
+                        idx,
+                        buf.len(),
+                        n
+                    );
+                    truncated.push(TruncatedValue {
+                        column: idx,
+                        expected: n,
+                        actual: buf.len(),
+                    });
+                    // The cut may fall inside a character.
+                    let value = self.options.encoding.decode(buf);
+                    values.push(OwnedValue::Text(value.into()));
+                }
+                _ => {
+                    let (value, _) = read_value(buf, serial_type, self.options.encoding)?;
+                    values.push(value);
+                }
+            }
+        }
+        let end = self.end();
+        if self.options.strict && end < self.payload.len() {
+            crate::bail_corrupt_error!(
+                "Record has {} trailing bytes after its last value",
+                self.payload.len() - end
+            );
+        }
+        Ok((OwnedRecord::new(values), truncated))
+    }
+
+    /// Decodes the record and renders each of its values for display.
+    ///
+    /// With `booleans` set, values of `BOOLEAN` columns stored with the `ConstInt0`
+    /// and `ConstInt1` serial types render as `false` and `true`. Every other value,
+    /// including 0 and 1 in non-boolean columns, renders as usual.
+    pub fn format(&self, columns: &[Column], booleans: bool) -> Result<Vec<String>> {
+        let record = self.record()?;
+        Ok(record
+            .values
+            .iter()
+            .zip(&self.columns)
+            .enumerate()
+            .map(|(idx, (value, (serial_type, _)))| {
+                let is_boolean = booleans && columns.get(idx).is_some_and(|c| c.is_boolean);
+                match serial_type {
+                    SerialType::ConstInt0 if is_boolean => "false".to_string(),
+                    SerialType::ConstInt1 if is_boolean => "true".to_string(),
+                    _ => value.to_string(),
+                }
+            })
+            .collect())
+    }
+
+    /// Decodes the record and checks each value against the affinity of its
+    /// column.
+    ///
+    /// SQLite is dynamically typed, so mismatches are not errors: they are logged
+    /// and returned alongside the record.
+    pub fn check_affinity(
+        &self,
+        columns: &[Column],
+    ) -> Result<(OwnedRecord, Vec<AffinityMismatch>)> {
+        let record = self.record()?;
+        let mut mismatches = Vec::new();
+        for (idx, (value, column)) in record.values.iter().zip(columns).enumerate() {
+            let compatible = match (column.ty, value) {
+                (_, OwnedValue::Null) | (Type::Null | Type::Blob, _) => true,
+                (Type::Integer | Type::Real | Type::Numeric, v) => {
+                    matches!(v, OwnedValue::Integer(_) | OwnedValue::Float(_))
+                }
+                (Type::Text, v) => matches!(v, OwnedValue::Text(_)),
+            };
+            if !compatible {
+                warn!(
+                    "column {} ({}) with {} affinity holds {:?}",
+                    idx, column.name, column.ty, value
+                );
+                mismatches.push(AffinityMismatch {
+                    column: idx,
+                    affinity: column.ty,
+                    value: value.clone(),
+                });
+            }
+        }
+        Ok((record, mismatches))
+    }
+
+    /// Returns the offset just past the last value.
+    fn end(&self) -> usize {
+        self.columns
+            .last()
+            .map_or(self.header_size, |(serial_type, offset)| {
+                offset + serial_type.content_size()
+            })
+    }
+}
+
+/// Reads the header of a record, returning the serial types of its values and the
+/// offset at which the values start.
+fn read_record_header(payload: &[u8], max_columns: usize) -> Result<(Vec<SerialType>, usize)> {
+    let mut pos = 0;
+    let (header_size, nr) = read_varint(payload)?;
+    let Some(mut header_size) = (header_size as usize).checked_sub(nr) else {
+        crate::bail_corrupt_error!("Record header size {} is too small", header_size);
+    };
+    pos += nr;
+    // Serial types below 128 fit in a single byte, which covers every value but
+    // strings and blobs longer than 57 bytes, so most headers need no varint
+    // decoding.
+    if let Some(header) = payload.get(pos..pos + header_size) {
+        if header.len() <= max_columns && header.iter().all(|&b| b < 0x80) {
+            let serial_types = header
+                .iter()
+                .map(|&b| SerialType::try_from(b as u64))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok((serial_types, pos + header_size));
+        }
+    }
+    let mut serial_types = Vec::with_capacity(header_size.min(max_columns));
+    while header_size > 0 {
+        if serial_types.len() == max_columns {
+            crate::bail_corrupt_error!("Record has more than {} columns", max_columns);
+        }
+        let (serial_type, nr) = read_varint(&payload[pos..])?;
+        let serial_type = SerialType::try_from(serial_type)?;
+        serial_types.push(serial_type);
+        pos += nr;
+        if header_size < nr {
+            crate::bail_corrupt_error!("Record header serial type runs past the header");
+        }
+        header_size -= nr;
+    }
+    Ok((serial_types, pos))
+}
+
+/// A record value whose storage class is incompatible with the affinity of its
+/// column.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AffinityMismatch {
+    /// Index of the column in the record.
+    pub column: usize,
+    /// Declared affinity of the column.
+    pub affinity: Type,
+    /// The value stored in the column.
+    pub value: OwnedValue,
+}
+
+/// A text value cut short by the end of its record, see [`RecordOptions::tolerant`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TruncatedValue {
+    /// Index of the column in the record.
+    pub column: usize,
+    /// Length in bytes declared by the serial type.
+    pub expected: usize,
+    /// Length in bytes decoded.
+    pub actual: usize,
+}
+
+/// Reads a value of `serial_type` from the start of `buf`, decoding text stored in
+/// `encoding`, and returns it with the number of bytes it takes.
+pub fn read_value(
+    buf: &[u8],
+    serial_type: &SerialType,
+    encoding: TextEncoding,
+) -> Result<(OwnedValue, usize)> {
+    match *serial_type {
+        SerialType::Null => Ok((OwnedValue::Null, 0)),
+        SerialType::UInt8 => {
             if buf.is_empty() {
                 crate::bail_corrupt_error!("Invalid UInt8 value");
             }
-            Ok((OwnedValue::Integer(buf[0] as i64), 1))
+            // Despite its name, serial type 1 is a signed 8-bit integer.
+            Ok((OwnedValue::Integer(buf[0] as i8 as i64), 1))
         }
         SerialType::BEInt16 => {
             if buf.len() < 2 {
@@ -870,10 +1975,9 @@ pub fn read_value(buf: &[u8], serial_type: &SerialType) -> Result<(OwnedValue, u
             if buf.len() < 3 {
                 crate::bail_corrupt_error!("Invalid BEInt24 value");
             }
-            Ok((
-                OwnedValue::Integer(i32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as i64),
-                3,
-            ))
+            // Shifting the value down from the top of an i32 sign-extends it.
+            let value = i32::from_be_bytes([buf[0], buf[1], buf[2], 0]) >> 8;
+            Ok((OwnedValue::Integer(value as i64), 3))
         }
         SerialType::BEInt32 => {
             if buf.len() < 4 {
@@ -888,12 +1992,9 @@ pub fn read_value(buf: &[u8], serial_type: &SerialType) -> Result<(OwnedValue, u
             if buf.len() < 6 {
                 crate::bail_corrupt_error!("Invalid BEInt48 value");
             }
-            Ok((
-                OwnedValue::Integer(i64::from_be_bytes([
-                    0, 0, buf[0], buf[1], buf[2], buf[3], buf[4], buf[5],
-                ])),
-                6,
-            ))
+            let value =
+                i64::from_be_bytes([buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], 0, 0]) >> 16;
+            Ok((OwnedValue::Integer(value), 6))
         }
         SerialType::BEInt64 => {
             if buf.len() < 8 {
@@ -933,8 +2034,7 @@ pub fn read_value(buf: &[u8], serial_type: &SerialType) -> Result<(OwnedValue, u
                     n
                 );
             }
-            let bytes = buf[0..n].to_vec();
-            let value = unsafe { String::from_utf8_unchecked(bytes) };
+            let value = encoding.try_decode(&buf[0..n])?;
             Ok((OwnedValue::Text(value.into()), n))
         }
     }
@@ -955,8 +2055,11 @@ pub fn read_varint(buf: &[u8]) -> Result<(u64, usize)> {
             }
         }
     }
-    v = (v << 8) + buf[8] as u64;
-    Ok((v, 9))
+    // The ninth byte contributes all of its eight bits.
+    match buf.get(8) {
+        Some(&c) => Ok(((v << 8) | c as u64, 9)),
+        None => crate::bail_corrupt_error!("Invalid varint"),
+    }
 }
 
 pub fn write_varint(buf: &mut [u8], value: u64) -> usize {
@@ -1011,7 +2114,7 @@ pub fn begin_read_wal_header(io: &Rc<dyn File>) -> Result<Rc<RefCell<WalHeader>>
     let buf = Rc::new(RefCell::new(Buffer::allocate(512, drop_fn)));
     let result = Rc::new(RefCell::new(WalHeader::default()));
     let header = result.clone();
-    let complete = Box::new(move |buf: Rc<RefCell<Buffer>>| {
+    let complete = Box::new(move |buf: Rc<RefCell<Buffer>>, _bytes_read: i32| {
         let header = header.clone();
         finish_read_wal_header(buf, header).unwrap();
     });
@@ -1035,22 +2138,70 @@ fn finish_read_wal_header(buf: Rc<RefCell<Buffer>>, header: Rc<RefCell<WalHeader
     Ok(())
 }
 
+/// Reads the WAL frame at `offset` into `page`.
+///
+/// The frame checksum is recomputed from `checksums`, the cumulative checksum of
+/// the frames preceding it. A frame that does not match, e.g. because of a torn
+/// write, is not trusted: the page is flagged with an error instead.
 pub fn begin_read_wal_frame(
     io: &Rc<dyn File>,
     offset: usize,
     buffer_pool: Rc<BufferPool>,
     page: Rc<RefCell<Page>>,
+    wal_header: &WalHeader,
+    checksums: (u32, u32),
 ) -> Result<()> {
-    let buf = buffer_pool.get();
-    let drop_fn = Rc::new(move |buf| {
-        let buffer_pool = buffer_pool.clone();
-        buffer_pool.put(buf);
-    });
-    let buf = Rc::new(RefCell::new(Buffer::new(buf, drop_fn)));
+    let drop_fn = Rc::new(|_buf| {});
+    let buf = Rc::new(RefCell::new(Buffer::allocate(
+        wal_header.page_size as usize + WAL_FRAME_HEADER_SIZE,
+        drop_fn,
+    )));
+    let wal_header = wal_header.clone();
+    let expects_be = wal_header.magic & 1; // LSB is set on big endian checksums
+    let use_native_endian = cfg!(target_endian = "big") as u32 == expects_be;
     let frame = page.clone();
-    let complete = Box::new(move |buf: Rc<RefCell<Buffer>>| {
-        let frame = frame.clone();
-        finish_read_page(2, buf, frame).unwrap();
+    let complete = Box::new(move |buf: Rc<RefCell<Buffer>>, bytes_read: i32| {
+        let buf = buf.borrow();
+        let page_idx = frame.borrow().id;
+        if bytes_read < buf.len() as i32 {
+            log::error!(
+                "short read of WAL frame at offset {} for page {}: got {} of {} bytes",
+                offset,
+                page_idx,
+                bytes_read,
+                buf.len()
+            );
+            let frame = frame.borrow();
+            frame.set_error();
+            frame.clear_locked();
+            return;
+        }
+        let (header, contents) = buf.as_slice().split_at(WAL_FRAME_HEADER_SIZE);
+        let expected = checksum_wal(&header[0..8], &wal_header, checksums, use_native_endian);
+        let expected = checksum_wal(contents, &wal_header, expected, use_native_endian);
+        let stored = (
+            u32::from_be_bytes([header[16], header[17], header[18], header[19]]),
+            u32::from_be_bytes([header[20], header[21], header[22], header[23]]),
+        );
+        if expected != stored {
+            log::error!(
+                "WAL frame at offset {} for page {} has an invalid checksum",
+                offset,
+                page_idx
+            );
+            let frame = frame.borrow();
+            frame.set_error();
+            frame.clear_locked();
+            return;
+        }
+        let mut page_buf = buffer_pool.get();
+        page_buf.copy_from_slice(contents);
+        let buffer_pool = buffer_pool.clone();
+        let drop_fn = Rc::new(move |buf| {
+            buffer_pool.put(buf);
+        });
+        let page_buf = Rc::new(RefCell::new(Buffer::new(page_buf, drop_fn)));
+        finish_read_page(page_idx, page_buf, frame.clone()).unwrap();
     });
     let c = Rc::new(Completion::Read(ReadCompletion::new(buf, complete)));
     io.pread(offset, c)?;
@@ -1188,6 +2339,117 @@ pub fn payload_overflows(
     (true, space_left + 4)
 }
 
+/// Returns the `(max_local, min_local)` payload thresholds of cells on a page of
+/// `page_type`. Payloads larger than `max_local` spill onto overflow pages,
+/// keeping at least `min_local` bytes on the b-tree page.
+pub fn payload_thresholds(page_type: &PageType, usable_size: usize) -> (usize, usize) {
+    // Index leaves keep as little on the page as index interior pages do, so that
+    // every page holds at least four cells.
+    let max_local = match page_type {
+        PageType::IndexInterior | PageType::IndexLeaf | PageType::TableInterior => {
+            (usable_size - 12) * 64 / 255 - 23
+        }
+        PageType::TableLeaf => usable_size - 35,
+    };
+    let min_local = (usable_size - 12) * 32 / 255 - 23;
+    (max_local, min_local)
+}
+
+/// Returns the smallest valid page size at which a table leaf cell holding a
+/// record of `values` is stored without overflow pages, or 65536 if the record
+/// overflows at every page size. `reserved_bytes` is the space reserved at the
+/// end of each page.
+pub fn min_page_size_for_row(values: &[OwnedValue], reserved_bytes: usize) -> u32 {
+    let mut payload = Vec::new();
+    OwnedRecord::new(values.to_vec()).serialize(&mut payload);
+    (9..=16)
+        .map(|shift| 1u32 << shift)
+        .find(|&page_size| {
+            // SQLite requires at least 480 usable bytes per page.
+            let Some(usable_size) = (page_size as usize)
+                .checked_sub(reserved_bytes)
+                .filter(|&usable_size| usable_size >= 480)
+            else {
+                return false;
+            };
+            let (max_local, min_local) = payload_thresholds(&PageType::TableLeaf, usable_size);
+            let (overflows, _) =
+                payload_overflows(payload.len(), max_local, min_local, usable_size);
+            !overflows
+        })
+        .unwrap_or(65536)
+}
+
+/// The kind of page a pointer map entry describes, which determines what its
+/// parent page is.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PtrmapType {
+    /// A b-tree root page. It has no parent.
+    RootPage = 1,
+    /// A freelist page. It has no parent.
+    FreePage = 2,
+    /// The first overflow page of a cell, whose parent is the b-tree page holding
+    /// the cell.
+    Overflow1 = 3,
+    /// A later overflow page, whose parent is the previous overflow page.
+    Overflow2 = 4,
+    /// A non-root b-tree page, whose parent is its parent b-tree page.
+    BTreeNode = 5,
+}
+
+impl TryFrom<u8> for PtrmapType {
+    type Error = LimboError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(Self::RootPage),
+            2 => Ok(Self::FreePage),
+            3 => Ok(Self::Overflow1),
+            4 => Ok(Self::Overflow2),
+            5 => Ok(Self::BTreeNode),
+            _ => Err(LimboError::Corrupt(format!(
+                "Invalid pointer map entry type: {}",
+                value
+            ))),
+        }
+    }
+}
+
+/// Returns the pointer map page holding the entry of page `page_idx` in an
+/// auto-vacuum database, or `None` for page 1 and for pointer map pages, which
+/// have no entry.
+///
+/// The first pointer map page is page 2, and each is followed by the pages it
+/// maps: one 5-byte entry per page.
+pub fn ptrmap_page_for(page_idx: usize, usable_size: usize) -> Option<usize> {
+    if page_idx < 2 {
+        return None;
+    }
+    let pages_per_ptrmap = usable_size / 5 + 1;
+    let ptrmap_page = (page_idx - 2) / pages_per_ptrmap * pages_per_ptrmap + 2;
+    (ptrmap_page != page_idx).then_some(ptrmap_page)
+}
+
+/// Reads the `(entry_type, parent_page)` entries of a pointer map page, one for
+/// each of the pages following it. The entries of pages past the end of the
+/// database are zero, and end the list.
+pub fn read_ptrmap_entries(
+    page: &PageContent,
+    usable_size: usize,
+) -> Result<Vec<(PtrmapType, u32)>> {
+    let buf = page.as_ptr();
+    let mut entries = Vec::new();
+    for entry in buf[..usable_size.min(buf.len())].chunks_exact(5) {
+        if entry[0] == 0 {
+            break;
+        }
+        let parent_page = u32::from_be_bytes([entry[1], entry[2], entry[3], entry[4]]);
+        entries.push((PtrmapType::try_from(entry[0])?, parent_page));
+    }
+    Ok(entries)
+}
+
 pub fn checksum_wal(
     buf: &[u8],
     _wal_header: &WalHeader,
@@ -1227,8 +2489,153 @@ impl WalHeader {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::btree::tests::{open_test_database, test_io};
     use rstest::rstest;
 
+    #[test]
+    fn test_read_database_header_short_read() {
+        let drop_fn = Rc::new(|_buf| {});
+        let buf = Rc::new(RefCell::new(Buffer::allocate(512, drop_fn)));
+        let header = Rc::new(RefCell::new(DatabaseHeader::default()));
+        let result = finish_read_database_header(buf, 50, header);
+        assert!(matches!(result, Err(LimboError::ShortRead(50, 100))));
+
+        let io = crate::io::MemoryIO::new();
+        let storage = Rc::new(crate::MemoryStorage::new(Arc::from(vec![0; 50])));
+        let result = read_database_header(&io, storage);
+        assert!(matches!(result, Err(LimboError::ShortRead(50, 100))));
+        let result = crate::Database::open_bytes(Arc::from(vec![0; 50]));
+        assert!(matches!(result, Err(LimboError::NotADB)));
+    }
+
+    /// Reads every cell of page `page_idx` of `db`.
+    fn page_cells(db: &crate::Database, page_idx: usize) -> Vec<BTreeCell> {
+        let page = db.pager.read_page_blocking(page_idx).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        let usable_size = db.pager.usable_size();
+        let (max_local, min_local) = payload_thresholds(&contents.page_type(), usable_size);
+        (0..contents.cell_count())
+            .map(|idx| {
+                contents
+                    .cell_get(idx, db.pager.clone(), max_local, min_local, usable_size)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    /// Reads the cells of `table`, whose root page must be its only page.
+    fn table_leaf_cells(db: &crate::Database, table: &str) -> Vec<TableLeafCell> {
+        let root_page = db.schema.borrow().get_table(table).unwrap().root_page;
+        page_cells(db, root_page)
+            .into_iter()
+            .map(|cell| match cell {
+                BTreeCell::TableLeafCell(cell) => cell,
+                cell => panic!("expected a table leaf cell, got {:?}", cell),
+            })
+            .collect()
+    }
+
+    /// Reads the first cell of `table`, whose root page must be its only page.
+    fn first_table_leaf_cell(db: &crate::Database, table: &str) -> TableLeafCell {
+        table_leaf_cells(db, table).remove(0)
+    }
+
+    /// Builds a 1024-byte page database whose only row overflows onto pages 3
+    /// and 4, and returns its image with the next page of `page` set to `next`.
+    fn overflow_chain_image(page: usize, next: u32) -> Vec<u8> {
+        let (dir, _db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, y TEXT);
+             INSERT INTO t VALUES (1, printf('%.3000c', 'a'));",
+        );
+        let mut image = std::fs::read(dir.path().join("test.db")).unwrap();
+        assert_eq!(image.len(), 4 * 1024);
+        assert_eq!(&image[2 * 1024..2 * 1024 + 4], &4u32.to_be_bytes());
+        assert_eq!(&image[3 * 1024..3 * 1024 + 4], &0u32.to_be_bytes());
+        let offset = (page - 1) * 1024;
+        image[offset..offset + 4].copy_from_slice(&next.to_be_bytes());
+        image
+    }
+
+    #[rstest]
+    #[case::cyclic(4, 3, "longer than its payload")]
+    #[case::truncated(3, 0, "short of its payload")]
+    fn test_read_payload_corrupt_overflow_chain(
+        #[case] page: usize,
+        #[case] next: u32,
+        #[case] message: &str,
+    ) {
+        let image = overflow_chain_image(page, next);
+        let db = crate::Database::open_bytes(Arc::from(image)).unwrap();
+        let result = db.scan_table("t").unwrap().collect::<Result<Vec<_>>>();
+        match result {
+            Err(LimboError::Corrupt(msg)) => assert!(msg.contains(message), "{}", msg),
+            other => panic!("expected Corrupt, got {:?}", other.map(|rows| rows.len())),
+        }
+    }
+
+    #[rstest]
+    #[case(256, false)]
+    #[case(512, true)]
+    #[case(1000, false)]
+    #[case(4096, true)]
+    #[case(65536, true)]
+    #[case(131072, false)]
+    fn test_is_valid_page_size(#[case] size: usize, #[case] valid: bool) {
+        assert_eq!(is_valid_page_size(size), valid);
+    }
+
+    #[test]
+    fn test_begin_write_btree_page_buffer_size() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY);",
+        );
+        let drop_fn = Rc::new(|_buf| {});
+        let page = Rc::new(RefCell::new(Page::new(2)));
+        page.borrow_mut().contents = Some(PageContent {
+            offset: 0,
+            buffer: Rc::new(RefCell::new(Buffer::allocate(512, drop_fn))),
+            overflow_cells: Vec::new(),
+        });
+        let write_counter = Rc::new(RefCell::new(0));
+        let result = begin_write_btree_page(&db.pager, &page, write_counter.clone());
+        let err = result.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Internal error: cannot write page 2: 512 bytes, the page size is 1024"
+        );
+        assert_eq!(*write_counter.borrow(), 0);
+    }
+
+    #[test]
+    fn test_begin_read_page_buffer_size() {
+        let storage = Rc::new(crate::MemoryStorage::new(Arc::from(vec![0; 1024])));
+        let buffer_pool = Rc::new(BufferPool::new(512));
+        buffer_pool.put(Pin::new(vec![0; 1024]));
+        let page = Rc::new(RefCell::new(Page::new(1)));
+        let result = begin_read_page(storage, buffer_pool, page, 1);
+        assert!(matches!(result, Err(LimboError::InternalError(_))));
+    }
+
+    #[test]
+    fn test_read_page1() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, y TEXT);
+             CREATE INDEX t_y ON t (y);",
+        );
+        let (header, page) = read_page1(&db.pager).unwrap();
+        assert_eq!(&header.magic, b"SQLite format 3\0");
+        assert_eq!(header.page_size, 1024);
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        assert_eq!(contents.offset, DATABASE_HEADER_SIZE);
+        assert_eq!(contents.page_type(), PageType::TableLeaf);
+        assert_eq!(contents.cell_count(), 2);
+    }
+
     #[rstest]
     #[case(0, SerialType::Null)]
     #[case(1, SerialType::UInt8)]
@@ -1249,6 +2656,47 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[rstest]
+    #[case(OwnedValue::Null, 4, SerialType::Null)]
+    #[case(OwnedValue::Integer(0), 4, SerialType::ConstInt0)]
+    #[case(OwnedValue::Integer(1), 4, SerialType::ConstInt1)]
+    #[case(OwnedValue::Integer(0), 3, SerialType::UInt8)]
+    #[case(OwnedValue::Integer(1), 1, SerialType::UInt8)]
+    #[case(OwnedValue::Integer(100), 4, SerialType::UInt8)]
+    #[case(OwnedValue::Integer(200), 4, SerialType::BEInt16)]
+    #[case(OwnedValue::Integer(-5), 4, SerialType::UInt8)]
+    #[case(OwnedValue::Integer(-128), 4, SerialType::UInt8)]
+    #[case(OwnedValue::Integer(-129), 4, SerialType::BEInt16)]
+    #[case(OwnedValue::Integer(100_000), 4, SerialType::BEInt24)]
+    #[case(OwnedValue::Integer(-100_000), 4, SerialType::BEInt24)]
+    #[case(OwnedValue::Integer(1 << 40), 4, SerialType::BEInt48)]
+    #[case(OwnedValue::Integer(-(1 << 40)), 4, SerialType::BEInt48)]
+    #[case(OwnedValue::Integer(i64::MIN), 4, SerialType::BEInt64)]
+    #[case(OwnedValue::Float(2.0), 4, SerialType::BEFloat64)]
+    #[case(OwnedValue::Text(Rc::new("abc".to_string())), 4, SerialType::String(3))]
+    #[case(OwnedValue::Blob(Rc::new(vec![1, 2, 3])), 4, SerialType::Blob(3))]
+    fn test_value_serial_type(
+        #[case] value: OwnedValue,
+        #[case] schema_format: u32,
+        #[case] expected: SerialType,
+    ) {
+        let serial_type = value_serial_type(&value, schema_format);
+        assert_eq!(serial_type, expected);
+        // The encoder agrees, and the value reads back unchanged.
+        let record = OwnedRecord::new(vec![value.clone()]);
+        let mut payload = Vec::new();
+        record.serialize_with_schema_format(&mut payload, schema_format);
+        assert_eq!(payload[1] as u64, u64::from(serial_type));
+        assert_eq!(
+            RecordReader::new(&payload, RecordOptions::default())
+                .unwrap()
+                .record()
+                .unwrap()
+                .values,
+            vec![value]
+        );
+    }
+
     #[test]
     fn test_read_invalid_serial_type() {
         let result = SerialType::try_from(10);
@@ -1257,11 +2705,14 @@ mod tests {
 
     #[rstest]
     #[case(&[], SerialType::Null, OwnedValue::Null)]
-    #[case(&[255], SerialType::UInt8, OwnedValue::Integer(255))]
+    #[case(&[0x7f], SerialType::UInt8, OwnedValue::Integer(127))]
+    #[case(&[0xfb], SerialType::UInt8, OwnedValue::Integer(-5))]
     #[case(&[0x12, 0x34], SerialType::BEInt16, OwnedValue::Integer(0x1234))]
     #[case(&[0x12, 0x34, 0x56], SerialType::BEInt24, OwnedValue::Integer(0x123456))]
+    #[case(&[0xfe, 0x79, 0x60], SerialType::BEInt24, OwnedValue::Integer(-100_000))]
     #[case(&[0x12, 0x34, 0x56, 0x78], SerialType::BEInt32, OwnedValue::Integer(0x12345678))]
     #[case(&[0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC], SerialType::BEInt48, OwnedValue::Integer(0x123456789ABC))]
+    #[case(&[0xff, 0x00, 0x00, 0x00, 0x00, 0x00], SerialType::BEInt48, OwnedValue::Integer(-(1 << 40)))]
     #[case(&[0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xFF], SerialType::BEInt64, OwnedValue::Integer(0x123456789ABCDEFF))]
     #[case(&[64, 9, 33, 251, 84, 68, 45, 24], SerialType::BEFloat64, OwnedValue::Float(std::f64::consts::PI))]
     #[case(&[], SerialType::ConstInt0, OwnedValue::Integer(0))]
@@ -1273,10 +2724,30 @@ mod tests {
         #[case] serial_type: SerialType,
         #[case] expected: OwnedValue,
     ) {
-        let result = read_value(buf, &serial_type).unwrap();
+        let result = read_value(buf, &serial_type, TextEncoding::Utf8).unwrap();
         assert_eq!(result, (expected, buf.len()));
     }
 
+    #[test]
+    fn test_read_negative_integers() {
+        // SQLite stores each value with the narrowest signed serial type: 1, 3, 5
+        // and 6.
+        let values = [-5, -100_000, -(1i64 << 40), i64::MIN];
+        let (_dir, db) = open_test_database(&format!(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY, v INTEGER);
+             INSERT INTO t VALUES (1, {}), (2, {}), (3, {}), (4, {});",
+            values[0], values[1], values[2], values[3]
+        ));
+        let cells = table_leaf_cells(&db, "t");
+        let mut read = Vec::new();
+        for (cell, serial_type) in cells.iter().zip([1, 3, 5, 6]) {
+            let reader = RecordReader::new(&cell._payload, RecordOptions::default()).unwrap();
+            assert_eq!(u64::from(reader.columns()[1].0), serial_type);
+            read.push(reader.record().unwrap().values[1].clone());
+        }
+        assert_eq!(read, values.map(OwnedValue::Integer).to_vec());
+    }
+
     #[rstest]
     #[case(&[], SerialType::UInt8)]
     #[case(&[0x12], SerialType::BEInt16)]
@@ -1289,7 +2760,7 @@ mod tests {
     #[case(&[65, 66], SerialType::String(3))]
     // TODO: UTF-8 validation is disabled #[case(&[192], SerialType::String(1))] // invalid UTF-8 sequence
     fn test_read_invalid_value(#[case] buf: &[u8], #[case] serial_type: SerialType) {
-        let result = read_value(buf, &serial_type);
+        let result = read_value(buf, &serial_type, TextEncoding::Utf8);
         assert!(result.is_err());
     }
 
@@ -1303,6 +2774,8 @@ mod tests {
     #[case(&[0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x01], (4432676798593, 7))]
     #[case(&[0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x01], (567382630219905, 8))]
     #[case(&[0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x01], (145249953336295681, 9))]
+    #[case(&[0xbf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], (i64::MAX as u64, 9))]
+    #[case(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01], (u64::MAX, 9))]
     fn read_varint_test(#[case] input: &[u8], #[case] expected: (u64, usize)) {
         let result = read_varint(input).unwrap();
         assert_eq!(result, expected);
@@ -1313,6 +2786,9 @@ mod tests {
         let buf = [0b11111110];
         let result = read_varint(&buf);
         assert!(result.is_err());
+        // A nine-byte varint cut short before its last byte.
+        let buf = [0xff; 8];
+        assert!(read_varint(&buf).is_err());
     }
 
     // **    0x00                      becomes  0x00000000
@@ -1341,4 +2817,1210 @@ mod tests {
             assert_eq!(buf[i], output[i]);
         }
     }
+
+    #[test]
+    fn test_varint_round_trip() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+        let mut values = vec![0, 127, 128, u64::MAX];
+        for bits in 1..64 {
+            values.extend([(1 << bits) - 1, 1 << bits, (1 << bits) + 1]);
+        }
+        // A fixed seed, so that a failure reproduces.
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        values.extend((0..1000).map(|_| rng.gen::<u64>() >> rng.gen_range(0..64)));
+        for value in values {
+            let mut buf = [0; 9];
+            let n = write_varint(&mut buf, value);
+            // Seven bits per byte, except that the ninth byte holds eight.
+            let bits = 64 - value.leading_zeros() as usize;
+            let expected = if bits > 56 {
+                9
+            } else {
+                bits.div_ceil(7).max(1)
+            };
+            assert_eq!(n, expected, "length of {}", value);
+            assert_eq!(read_varint(&buf[..n]).unwrap(), (value, n));
+        }
+    }
+
+    #[rstest]
+    #[case::small_row(vec![OwnedValue::Integer(1), OwnedValue::Text(Rc::new("a".to_string()))], 0, 512)]
+    #[case::two_kb_row(vec![OwnedValue::Blob(Rc::new(vec![0; 2048]))], 0, 4096)]
+    #[case::reserved_bytes(vec![OwnedValue::Blob(Rc::new(vec![0; 2000]))], 32, 4096)]
+    #[case::always_overflows(vec![OwnedValue::Blob(Rc::new(vec![0; 70000]))], 0, 65536)]
+    fn test_min_page_size_for_row(
+        #[case] values: Vec<OwnedValue>,
+        #[case] reserved_bytes: usize,
+        #[case] expected: u32,
+    ) {
+        assert_eq!(min_page_size_for_row(&values, reserved_bytes), expected);
+    }
+
+    #[test]
+    fn test_serialize_btree_page_round_trip() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, 'a'), (2, NULL), (3, 'ccc');",
+        );
+        let pager = db.pager.clone();
+        let usable_size = pager.usable_size();
+        let cells_of = |contents: &PageContent| {
+            let (max_local, min_local) = payload_thresholds(&contents.page_type(), usable_size);
+            (0..contents.cell_count())
+                .map(|idx| {
+                    contents
+                        .cell_get(idx, pager.clone(), max_local, min_local, usable_size)
+                        .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        let page = pager.read_page_blocking(2).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        let cells = cells_of(contents);
+
+        let buf =
+            serialize_btree_page(contents.page_type(), &cells, None, 4096, usable_size, 2).unwrap();
+        let reparsed = PageContent {
+            offset: 0,
+            buffer: Rc::new(RefCell::new(Buffer::new(Pin::new(buf), Rc::new(|_| {})))),
+            overflow_cells: Vec::new(),
+        };
+        assert_eq!(reparsed.page_type(), PageType::TableLeaf);
+        assert_eq!(format!("{:?}", cells_of(&reparsed)), format!("{:?}", cells));
+    }
+
+    #[test]
+    fn test_read_record_checked_reports_mismatch() {
+        let (_dir, db) = open_test_database(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY, n INTEGER, v TEXT);
+             INSERT INTO t VALUES (1, 'not a number', 'a');",
+        );
+        let table = db.schema.borrow().get_table("t").unwrap();
+        let cell = first_table_leaf_cell(&db, "t");
+        let (record, mismatches) = RecordReader::new(&cell._payload, RecordOptions::default())
+            .unwrap()
+            .check_affinity(&table.columns)
+            .unwrap();
+        assert_eq!(record.values.len(), 3);
+        assert_eq!(
+            mismatches,
+            vec![AffinityMismatch {
+                column: 1,
+                affinity: Type::Integer,
+                value: OwnedValue::Text(Rc::new("not a number".to_string())),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_cell_payload_size() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             INSERT INTO t VALUES (1, zeroblob(10)), (2, zeroblob(3000));",
+        );
+        let table = db.schema.borrow().get_table("t").unwrap();
+        let page = db.pager.read_page_blocking(table.root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        for (idx, cell) in table_leaf_cells(&db, "t").into_iter().enumerate() {
+            let cell_pointer = contents.read_u16(8 + idx * 2) as usize;
+            let size =
+                cell_payload_size(contents.as_ptr(), &contents.page_type(), cell_pointer).unwrap();
+            assert_eq!(size, cell._payload.len() as u64);
+            assert_eq!(cell.first_overflow_page.is_some(), idx == 1);
+        }
+    }
+
+    #[test]
+    fn test_cell_has_overflow() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             INSERT INTO t VALUES (1, zeroblob(10)), (2, zeroblob(3000));",
+        );
+        let table = db.schema.borrow().get_table("t").unwrap();
+        let page = db.pager.read_page_blocking(table.root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        let usable_size = db.pager.usable_size();
+        let overflows = (0..contents.cell_count())
+            .map(|idx| {
+                let cell_pointer = contents.read_u16(8 + idx * 2) as usize;
+                cell_has_overflow(
+                    contents.as_ptr(),
+                    &contents.page_type(),
+                    cell_pointer,
+                    usable_size,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(overflows, vec![false, true]);
+    }
+
+    #[test]
+    fn test_read_record_multi_byte_serial_types() {
+        let record = OwnedRecord::new(vec![
+            OwnedValue::Integer(7),
+            OwnedValue::Text(Rc::new("a".repeat(100))),
+            OwnedValue::Null,
+            OwnedValue::Blob(Rc::new(vec![1; 300])),
+            OwnedValue::Float(1.5),
+        ]);
+        let mut payload = Vec::new();
+        record.serialize(&mut payload);
+        // The text and blob serial types each take two bytes.
+        assert_eq!(payload[0], 8);
+        assert_eq!(
+            RecordReader::new(&payload, RecordOptions::default())
+                .unwrap()
+                .record()
+                .unwrap(),
+            record
+        );
+
+        let record = OwnedRecord::new(vec![OwnedValue::Integer(7), OwnedValue::Null]);
+        let mut payload = Vec::new();
+        record.serialize(&mut payload);
+        assert_eq!(
+            RecordReader::new(&payload, RecordOptions::default())
+                .unwrap()
+                .record()
+                .unwrap(),
+            record
+        );
+    }
+
+    #[test]
+    fn test_read_record_raw() {
+        let (_dir, db) = open_test_database(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY, n INTEGER, f REAL, v TEXT, b BLOB);
+             INSERT INTO t VALUES (1, 300, 2.5, 'abc', x'0102');",
+        );
+        let cell = first_table_leaf_cell(&db, "t");
+        let reader = RecordReader::new(&cell._payload, RecordOptions::default()).unwrap();
+        let (header, values) = (reader.header(), reader.raw_values().unwrap());
+        assert_eq!(
+            reader.record().unwrap(),
+            RecordReader::new(&cell._payload, RecordOptions::default())
+                .unwrap()
+                .record()
+                .unwrap()
+        );
+        assert_eq!([header, values].concat(), cell._payload);
+        assert_eq!(header.len(), header[0] as usize);
+    }
+
+    #[test]
+    fn test_format_record_booleans() {
+        let (_dir, db) = open_test_database(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY, flag BOOLEAN, n INTEGER);
+             INSERT INTO t VALUES (1, 1, 1), (2, 0, 0);",
+        );
+        let table = db.schema.borrow().get_table("t").unwrap();
+        let rows = table_leaf_cells(&db, "t")
+            .iter()
+            .map(|cell| {
+                let reader = RecordReader::new(&cell._payload, RecordOptions::default()).unwrap();
+                (
+                    reader.format(&table.columns, true).unwrap(),
+                    reader.format(&table.columns, false).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(rows[0].0, vec!["NULL", "true", "1"]);
+        assert_eq!(rows[1].0, vec!["NULL", "false", "0"]);
+        assert_eq!(rows[0].1, vec!["NULL", "1", "1"]);
+        assert_eq!(rows[1].1, vec!["NULL", "0", "0"]);
+    }
+
+    #[test]
+    fn test_index_leaf_payload_thresholds() {
+        assert_eq!(
+            payload_thresholds(&PageType::IndexLeaf, 1024),
+            payload_thresholds(&PageType::IndexInterior, 1024)
+        );
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             CREATE INDEX t_v ON t (v);
+             INSERT INTO t VALUES (1, zeroblob(600));",
+        );
+        let root_page = db.schema.borrow().indexes["t"][0].root_page;
+        let page = db.pager.read_page_blocking(root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        assert_eq!(contents.page_type(), PageType::IndexLeaf);
+        let usable_size = db.pager.usable_size();
+        let (max_local, min_local) = payload_thresholds(&contents.page_type(), usable_size);
+        let BTreeCell::IndexLeafCell(cell) = contents
+            .cell_get(0, db.pager.clone(), max_local, min_local, usable_size)
+            .unwrap()
+        else {
+            panic!("expected an index leaf cell");
+        };
+        // The 600 byte key overflows an index leaf of a 1024 byte page.
+        assert!(cell.first_overflow_page.is_some());
+        let record = RecordReader::new(&cell.payload, RecordOptions::default())
+            .unwrap()
+            .record()
+            .unwrap();
+        assert_eq!(
+            record.values,
+            vec![
+                OwnedValue::Blob(Rc::new(vec![0; 600])),
+                OwnedValue::Integer(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_page1_cell_pointer_into_header() {
+        let (_dir, db) = open_test_database("CREATE TABLE t (x INTEGER PRIMARY KEY);");
+        let page = db.pager.read_page_blocking(1).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        let (cell_pointers, _) = contents.cell_get_raw_pointer_region();
+        contents.write_u16(cell_pointers - contents.offset, 50);
+        let usable_size = db.pager.usable_size();
+        let (max_local, min_local) = payload_thresholds(&contents.page_type(), usable_size);
+        let err = contents
+            .cell_get(0, db.pager.clone(), max_local, min_local, usable_size)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Corrupt database: Cell 0 points into the database header at offset 50"
+        );
+    }
+
+    #[rstest]
+    #[case(TextEncoding::Utf8, &[0xc3, 0xa9, b'a'], "éa")]
+    #[case(TextEncoding::Utf16le, &[0xe9, 0x00, b'a', 0x00], "éa")]
+    #[case(TextEncoding::Utf16be, &[0x00, 0xe9, 0x00, b'a'], "éa")]
+    #[case(TextEncoding::Utf16le, &[b'a', 0x00, b'b'], "a")]
+    #[case(TextEncoding::Utf8, &[b'a', 0xff, b'b'], "a\u{fffd}b")]
+    fn test_text_encoding_decode(
+        #[case] encoding: TextEncoding,
+        #[case] bytes: &[u8],
+        #[case] expected: &str,
+    ) {
+        assert_eq!(encoding.decode(bytes), expected);
+    }
+
+    #[rstest]
+    #[case(TextEncoding::Utf8, &[0xc3, 0xa9, b'a'], Ok("\u{e9}a"))]
+    #[case(
+        TextEncoding::Utf8,
+        &[b'a', 0xc3, b'b'],
+        Err("Corrupt database: UTF-8 text is invalid at byte 1")
+    )]
+    #[case(TextEncoding::Utf16le, &[0x3d, 0xd8, 0x00, 0xde], Ok("\u{1f600}"))]
+    #[case(TextEncoding::Utf16be, &[0xd8, 0x3d, 0xde, 0x00, 0x00, b'a'], Ok("\u{1f600}a"))]
+    #[case(
+        TextEncoding::Utf16le,
+        &[0x3d, 0xd8, b'a', 0x00],
+        Err("Corrupt database: UTF-16 text has an unpaired surrogate 0xd83d")
+    )]
+    #[case(
+        TextEncoding::Utf16be,
+        &[0xde, 0x00],
+        Err("Corrupt database: UTF-16 text has an unpaired surrogate 0xde00")
+    )]
+    #[case(
+        TextEncoding::Utf16le,
+        &[b'a', 0x00, b'b'],
+        Err("Corrupt database: UTF-16 text has an odd length of 3 bytes")
+    )]
+    fn test_text_encoding_try_decode(
+        #[case] encoding: TextEncoding,
+        #[case] bytes: &[u8],
+        #[case] expected: std::result::Result<&str, &str>,
+    ) {
+        let decoded = encoding.try_decode(bytes).map_err(|e| e.to_string());
+        assert_eq!(decoded.as_deref().map_err(|e| e.as_str()), expected);
+    }
+
+    #[test]
+    fn test_value_at_offset() {
+        let record = OwnedRecord::new(vec![
+            OwnedValue::Null,
+            OwnedValue::Integer(1),
+            OwnedValue::Integer(300),
+            OwnedValue::Text(Rc::new("a".repeat(200))),
+            OwnedValue::Float(2.5),
+            OwnedValue::Blob(Rc::new(vec![1, 2, 3])),
+            OwnedValue::Integer(-70000),
+            OwnedValue::Text(Rc::new("last".to_string())),
+        ]);
+        let mut payload = Vec::new();
+        record.serialize(&mut payload);
+
+        let reader = RecordReader::new(&payload, RecordOptions::default()).unwrap();
+        let offsets = reader.columns();
+        assert_eq!(offsets.len(), 8);
+        assert_eq!(offsets[5].0, SerialType::Blob(3));
+        assert_eq!(
+            reader.value(5).unwrap(),
+            OwnedValue::Blob(Rc::new(vec![1, 2, 3]))
+        );
+        for (column, value) in record.values.iter().enumerate() {
+            assert_eq!(&reader.value(column).unwrap(), value);
+        }
+        assert!(reader.value(8).is_err());
+        let truncated =
+            RecordReader::new(&payload[..offsets[7].1], RecordOptions::default()).unwrap();
+        assert!(truncated.value(7).is_err());
+    }
+
+    // With 4096 usable bytes, max_local is 4061 and min_local is 489. A payload
+    // just above max_local would leave 4062 bytes on the page, more than fit, so
+    // only min_local bytes stay; one overflow page later, the remainder fits.
+    #[rstest]
+    #[case::just_above_max_local(4062, 489)]
+    #[case::remainder_fits(489 + 4092 + 100, 589)]
+    fn test_table_leaf_local_payload_size(#[case] payload_size: usize, #[case] local_size: usize) {
+        // A 4-byte record header: its size, NULL for the rowid alias, and the blob.
+        let blob_size = payload_size - 4;
+        let (_dir, db) = open_test_database(&format!(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             INSERT INTO t VALUES (1, zeroblob({blob_size}));"
+        ));
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let usable_size = db.pager.usable_size();
+        let (max_local, min_local) = payload_thresholds(&PageType::TableLeaf, usable_size);
+        assert_eq!((max_local, min_local), (4061, 489));
+        assert_eq!(
+            payload_overflows(payload_size, max_local, min_local, usable_size),
+            (true, local_size + 4)
+        );
+
+        let page = db.pager.read_page_blocking(root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        let parsed = parse_btree_page(contents, usable_size).unwrap();
+        let cell = &parsed.cells[0];
+        assert_eq!(cell.payload_size, payload_size as u64);
+        assert_eq!(cell.local_payload.len(), local_size);
+        // The overflow page number follows the local payload in the cell.
+        let buffer = contents.as_ptr();
+        let pointer_offset =
+            cell.local_payload.as_ptr() as usize - buffer.as_ptr() as usize + local_size;
+        let first_overflow_page = u32::from_be_bytes(
+            buffer[pointer_offset..pointer_offset + 4]
+                .try_into()
+                .unwrap(),
+        );
+        assert_eq!(cell.first_overflow_page, Some(first_overflow_page));
+        assert_eq!(first_table_leaf_cell(&db, "t")._payload.len(), payload_size);
+    }
+
+    thread_local! {
+        static CAPTURED_LOGS: RefCell<Option<Vec<(log::Level, String)>>> = const { RefCell::new(None) };
+    }
+
+    /// Records the log events of the threads that asked for them, so that tests
+    /// running in parallel do not see each other's events.
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED_LOGS.with(|logs| {
+                if let Some(logs) = logs.borrow_mut().as_mut() {
+                    logs.push((record.level(), record.args().to_string()));
+                }
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Runs `f` and returns the log events it emitted.
+    fn capture_logs(f: impl FnOnce()) -> Vec<(log::Level, String)> {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        CAPTURED_LOGS.with(|logs| *logs.borrow_mut() = Some(Vec::new()));
+        f();
+        CAPTURED_LOGS.with(|logs| logs.borrow_mut().take().unwrap())
+    }
+
+    #[test]
+    fn test_parse_events_logged_at_debug() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             INSERT INTO t VALUES (1, zeroblob(3000));",
+        );
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let logs = capture_logs(|| {
+            let rows = db.scan_table("t").unwrap().collect::<Result<Vec<_>>>();
+            assert_eq!(rows.unwrap().len(), 1);
+        });
+        let page_read = format!("finish_read_btree_page(page_idx = {})", root_page);
+        for expected in [page_read.as_str(), "cell_get(idx=0)", "read_overflow_page"] {
+            assert!(
+                logs.iter()
+                    .any(|(level, message)| *level == log::Level::Debug
+                        && message.starts_with(expected)),
+                "no debug event {:?} in {:?}",
+                expected,
+                logs
+            );
+        }
+        assert!(logs.iter().all(|(level, _)| *level > log::Level::Info));
+    }
+
+    #[test]
+    fn test_ptrmap_entries() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             PRAGMA auto_vacuum = FULL;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             CREATE TABLE u (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO t SELECT i, zeroblob(300) FROM n;
+             INSERT INTO u VALUES (1, zeroblob(3000));",
+        );
+        assert!(db.header.borrow().is_auto_vacuum());
+        let usable_size = db.pager.usable_size();
+        assert_eq!(ptrmap_page_for(1, usable_size), None);
+        assert_eq!(ptrmap_page_for(2, usable_size), None);
+        assert_eq!(ptrmap_page_for(3, usable_size), Some(2));
+        assert_eq!(ptrmap_page_for(2 + usable_size / 5, usable_size), Some(2));
+        assert_eq!(ptrmap_page_for(3 + usable_size / 5, usable_size), None);
+
+        let page = db.pager.read_page_blocking(2).unwrap();
+        let page = page.borrow();
+        let entries = read_ptrmap_entries(page.contents.as_ref().unwrap(), usable_size).unwrap();
+        // Pages 3 and up, to the end of the database.
+        assert_eq!(entries.len(), db.header.borrow().database_size as usize - 2);
+        let entry = |page_idx: usize| {
+            assert_eq!(ptrmap_page_for(page_idx, usable_size), Some(2));
+            entries[page_idx - 3]
+        };
+
+        let t_root = db.schema.borrow().get_table("t").unwrap().root_page;
+        assert_eq!(entry(t_root), (PtrmapType::RootPage, 0));
+        let page = db.pager.read_page_blocking(t_root).unwrap();
+        let page = page.borrow();
+        let parsed = parse_btree_page(page.contents.as_ref().unwrap(), usable_size).unwrap();
+        assert_eq!(parsed.page_type, PageType::TableInterior);
+        let children = parsed
+            .cells
+            .iter()
+            .map(|cell| cell.left_child_page.unwrap())
+            .chain(parsed.rightmost_pointer);
+        for child in children {
+            assert_eq!(
+                entry(child as usize),
+                (PtrmapType::BTreeNode, t_root as u32)
+            );
+        }
+
+        let u_root = db.schema.borrow().get_table("u").unwrap().root_page;
+        assert_eq!(entry(u_root), (PtrmapType::RootPage, 0));
+        assert_eq!(
+            db.ptrmap_entry(u_root).unwrap(),
+            Some((PtrmapType::RootPage, 0))
+        );
+        assert_eq!(db.ptrmap_entry(2).unwrap(), None);
+        let page = db.pager.read_page_blocking(u_root).unwrap();
+        let page = page.borrow();
+        let parsed = parse_btree_page(page.contents.as_ref().unwrap(), usable_size).unwrap();
+        let first_overflow_page = parsed.cells[0].first_overflow_page.unwrap();
+        assert_eq!(
+            entry(first_overflow_page as usize),
+            (PtrmapType::Overflow1, u_root as u32)
+        );
+        let overflow = db
+            .pager
+            .read_page_blocking(first_overflow_page as usize)
+            .unwrap();
+        let next_overflow_page = overflow.borrow().contents.as_ref().unwrap().read_u32(0);
+        assert_eq!(
+            entry(next_overflow_page as usize),
+            (PtrmapType::Overflow2, first_overflow_page)
+        );
+    }
+
+    #[test]
+    fn test_parse_btree_page_borrows_payloads() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, 'short');
+             INSERT INTO t VALUES (2, printf('%.3000c', 'o'));",
+        );
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let usable_size = db.pager.usable_size();
+        let page = db.pager.read_page_blocking(root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        let parsed = parse_btree_page(contents, usable_size).unwrap();
+        assert_eq!(parsed.page_type, PageType::TableLeaf);
+        assert_eq!(parsed.cells.len(), 2);
+
+        let buffer = contents.as_ptr().as_ptr_range();
+        for cell in &parsed.cells {
+            let payload = cell.local_payload.as_ptr_range();
+            assert!(buffer.start <= payload.start && payload.end <= buffer.end);
+        }
+        assert_eq!(parsed.cells[0].rowid, Some(1));
+        assert_eq!(parsed.cells[0].first_overflow_page, None);
+        // A 4-byte record header, then the text.
+        assert_eq!(parsed.cells[1].payload_size, 3004);
+        assert!(parsed.cells[1].first_overflow_page.is_some());
+        assert!(parsed.cells[1].local_payload.len() < 1024);
+
+        let owned = parsed.to_owned(db.pager.clone()).unwrap();
+        for (cell, expected) in owned.into_iter().zip(table_leaf_cells(&db, "t")) {
+            let BTreeCell::TableLeafCell(cell) = cell else {
+                panic!("expected a table leaf cell, got {:?}", cell);
+            };
+            assert_eq!(cell._rowid, expected._rowid);
+            assert_eq!(cell._payload, expected._payload);
+            assert_eq!(cell.first_overflow_page, expected.first_overflow_page);
+        }
+    }
+
+    #[rstest]
+    #[case::payload_past_end(1022)]
+    #[case::cell_past_end(2000)]
+    fn test_parse_btree_page_corrupt_cell(#[case] cell_pointer: u16) {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, 'one');",
+        );
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let usable_size = db.pager.usable_size();
+        let page = db.pager.read_page_blocking(root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        contents.write_u16(8, cell_pointer);
+        let result = parse_btree_page(contents, usable_size);
+        assert!(
+            matches!(result, Err(LimboError::Corrupt(_))),
+            "{:?}",
+            result
+        );
+        let (max_local, min_local) = payload_thresholds(&contents.page_type(), usable_size);
+        let result = contents.cell_get(0, db.pager.clone(), max_local, min_local, usable_size);
+        assert!(matches!(result, Err(LimboError::Corrupt(_))));
+    }
+
+    #[test]
+    fn test_table_leaf_cell_record() {
+        let (_dir, db) = open_test_database(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY, a TEXT, b REAL);
+             INSERT INTO t VALUES (7, 'seven', 7.5);",
+        );
+        let cell = first_table_leaf_cell(&db, "t");
+        assert_eq!(cell._rowid, 7);
+        assert_eq!(
+            cell.record(RecordOptions::default()).unwrap().values,
+            vec![
+                OwnedValue::Null,
+                OwnedValue::Text(Rc::new("seven".to_string())),
+                OwnedValue::Float(7.5)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_enumerate_columns() {
+        let record = OwnedRecord::new(vec![
+            OwnedValue::Integer(0),
+            OwnedValue::Integer(-2),
+            OwnedValue::Null,
+            OwnedValue::Float(0.5),
+            OwnedValue::Text(Rc::new("ab".to_string())),
+            OwnedValue::Blob(Rc::new(vec![7])),
+        ]);
+        let mut payload = Vec::new();
+        record.serialize(&mut payload);
+        let reader = RecordReader::new(&payload, RecordOptions::default()).unwrap();
+        let columns = reader
+            .columns()
+            .iter()
+            .zip(reader.record().unwrap().values)
+            .enumerate()
+            .map(|(column, ((serial_type, _), value))| (column, *serial_type, value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            columns,
+            vec![
+                (0, SerialType::ConstInt0, OwnedValue::Integer(0)),
+                (1, SerialType::UInt8, OwnedValue::Integer(-2)),
+                (2, SerialType::Null, OwnedValue::Null),
+                (3, SerialType::BEFloat64, OwnedValue::Float(0.5)),
+                (
+                    4,
+                    SerialType::String(2),
+                    OwnedValue::Text(Rc::new("ab".to_string()))
+                ),
+                (5, SerialType::Blob(1), OwnedValue::Blob(Rc::new(vec![7]))),
+            ]
+        );
+        let reader = RecordReader::new(&payload[..payload.len() - 1], RecordOptions::default());
+        assert!(reader.unwrap().record().is_err());
+    }
+
+    #[test]
+    fn test_read_record_utf16() {
+        // A header of two bytes, then a 4-byte text value holding a surrogate pair.
+        let payload = [0x02, 0x15, 0x3d, 0xd8, 0x00, 0xde];
+        let options = RecordOptions {
+            encoding: TextEncoding::Utf16le,
+            ..RecordOptions::default()
+        };
+        assert_eq!(
+            RecordReader::new(&payload, options)
+                .unwrap()
+                .record()
+                .unwrap()
+                .values,
+            vec![OwnedValue::Text(Rc::new("\u{1f600}".to_string()))]
+        );
+        let lone_surrogate = [0x02, 0x15, 0x3d, 0xd8, b'a', 0x00];
+        let reader = RecordReader::new(&lone_surrogate, options).unwrap();
+        assert!(reader.record().is_err());
+    }
+
+    #[rstest]
+    #[case("UTF-16le")]
+    #[case("UTF-16be")]
+    fn test_scan_utf16_database(#[case] encoding: &str) {
+        let (_dir, db) = open_test_database(&format!(
+            "PRAGMA encoding = '{}';
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, 'caf\u{e9} \u{1f600}');",
+            encoding
+        ));
+        let rows = db
+            .scan_table("t")
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(
+            rows[0].1.values[1],
+            OwnedValue::Text(Rc::new("caf\u{e9} \u{1f600}".to_string()))
+        );
+
+        // Records are only written in UTF-8.
+        assert!(!db.header.borrow().can_write());
+        let err = db
+            .connect()
+            .execute("INSERT INTO t VALUES (2, 'b')")
+            .unwrap_err();
+        assert!(matches!(err, LimboError::ReadOnly), "{:?}", err);
+    }
+
+    #[test]
+    fn test_validate_header_invariants() {
+        assert!(validate_header_invariants(&DatabaseHeader::default()).is_ok());
+        let page_size_65536 = DatabaseHeader {
+            page_size: 1,
+            ..Default::default()
+        };
+        assert!(validate_header_invariants(&page_size_65536).is_ok());
+    }
+
+    #[rstest]
+    #[case::magic(DatabaseHeader { magic: *b"SQLite format 2\0", ..Default::default() }, "File is not a database")]
+    #[case::page_size(DatabaseHeader { page_size: 1000, ..Default::default() }, "Corrupt database: Invalid page size: 1000")]
+    #[case::small_page_size(DatabaseHeader { page_size: 256, ..Default::default() }, "Corrupt database: Invalid page size: 256")]
+    #[case::max_embed_frac(DatabaseHeader { max_embed_frac: 63, ..Default::default() }, "Corrupt database: Invalid payload fractions: 63/32/32, expected 64/32/32")]
+    #[case::min_embed_frac(DatabaseHeader { min_embed_frac: 0, ..Default::default() }, "Corrupt database: Invalid payload fractions: 64/0/32, expected 64/32/32")]
+    #[case::min_leaf_frac(DatabaseHeader { min_leaf_frac: 33, ..Default::default() }, "Corrupt database: Invalid payload fractions: 64/32/33, expected 64/32/32")]
+    #[case::write_version(DatabaseHeader { write_version: 0, ..Default::default() }, "Corrupt database: Invalid write version: 0")]
+    #[case::read_version(DatabaseHeader { read_version: 3, ..Default::default() }, "Corrupt database: Unsupported read version: 3")]
+    fn test_validate_header_invariants_broken(
+        #[case] header: DatabaseHeader,
+        #[case] expected: &str,
+    ) {
+        let err = validate_header_invariants(&header).unwrap_err();
+        assert_eq!(err.to_string(), expected);
+    }
+
+    #[rstest]
+    #[case::text(b"This is a plain text file, not a database.\n".repeat(100))]
+    #[case::zero_page_size(vec![0; 4096])]
+    #[case::header_string_only(SQLITE_MAGIC.to_vec())]
+    #[case::shorter_than_header(vec![0; 50])]
+    fn test_open_not_a_database(#[case] contents: Vec<u8>) {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("test.db");
+        std::fs::write(&path, contents).unwrap();
+        let err = crate::Database::open_file(test_io(), path.to_str().unwrap())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "File is not a database");
+    }
+
+    #[rstest]
+    #[case::size_in_header(false)]
+    #[case::size_from_file(true)]
+    fn test_read_geometry(#[case] stale_size: bool) {
+        let (dir, _db) = open_test_database(
+            "PRAGMA page_size = 2048;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             INSERT INTO t VALUES (1, zeroblob(10000));",
+        );
+        let path = dir.path().join("test.db");
+        let page_count = (std::fs::metadata(&path).unwrap().len() / 2048) as u32;
+        if stale_size {
+            // A stale version-valid-for number makes the in-header size untrusted.
+            let mut bytes = std::fs::read(&path).unwrap();
+            bytes[28..32].copy_from_slice(&1000u32.to_be_bytes());
+            bytes[92..96].copy_from_slice(&0u32.to_be_bytes());
+            std::fs::write(&path, bytes).unwrap();
+        }
+        let io = test_io();
+        let file = io
+            .open_file(path.to_str().unwrap(), crate::io::OpenFlags::None, false)
+            .unwrap();
+        let storage = Rc::new(crate::storage::database::FileStorage::new(file));
+        assert_eq!(
+            read_geometry(&io, storage).unwrap(),
+            DatabaseGeometry {
+                page_size: 2048,
+                reserved_bytes: 0,
+                page_count,
+                usable_size: 2048,
+            }
+        );
+    }
+
+    #[rstest]
+    #[case(512, 512)]
+    #[case(32768, 32768)]
+    #[case(1, 65536)]
+    fn test_page_size(#[case] stored: u16, #[case] expected: u32) {
+        let header = DatabaseHeader {
+            page_size: stored,
+            unused_space: 8,
+            ..Default::default()
+        };
+        assert_eq!(header.page_size(), expected);
+        assert_eq!(header.usable_size(), expected as usize - 8);
+    }
+
+    #[test]
+    fn test_read_64k_pages() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 65536;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             INSERT INTO t VALUES (1, zeroblob(40000)), (2, zeroblob(40000));",
+        );
+        assert_eq!(db.header.borrow().page_size, 1);
+        assert_eq!(db.header.borrow().page_size(), 65536);
+        let rows = db
+            .dump_all(false)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].2[1], OwnedValue::Blob(Rc::new(vec![0; 40000])));
+    }
+
+    #[rstest]
+    #[case(5, 5, 10, true, true)]
+    #[case(5, 4, 10, false, false)]
+    #[case(5, 5, 0, true, false)]
+    fn test_in_header_size_trustworthy(
+        #[case] change_counter: u32,
+        #[case] version_valid_for: u32,
+        #[case] database_size: u32,
+        #[case] trustworthy: bool,
+        #[case] valid: bool,
+    ) {
+        let header = DatabaseHeader {
+            change_counter,
+            version_valid_for,
+            database_size,
+            ..Default::default()
+        };
+        assert_eq!(header.in_header_size_trustworthy(), trustworthy);
+        assert_eq!(header.is_database_size_valid(), valid);
+    }
+
+    #[test]
+    fn test_migrate_header() {
+        let header = DatabaseHeader {
+            schema_format: 1,
+            schema_cookie: 7,
+            ..Default::default()
+        };
+        let migrated = migrate_header(&header, 4).unwrap();
+        assert_eq!(migrated.schema_format(), 4);
+        assert_eq!(migrated.schema_cookie(), 8);
+        assert_eq!(migrated.page_size, header.page_size);
+        assert_eq!(migrated.change_counter(), header.change_counter());
+
+        let unchanged = migrate_header(&migrated, 4).unwrap();
+        assert_eq!(unchanged.schema_cookie(), 8);
+        assert!(migrate_header(&migrated, 1).is_err());
+        assert!(migrate_header(&header, 5).is_err());
+        assert!(migrate_header(&header, 0).is_err());
+    }
+
+    #[test]
+    fn test_build_cell_pointer_array() {
+        assert_eq!(
+            build_cell_pointer_array(&[4090, 0x0102, 13]),
+            vec![0x0f, 0xfa, 0x01, 0x02, 0x00, 0x0d]
+        );
+        assert!(build_cell_pointer_array(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_read_table_interior_cell() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO t SELECT i, zeroblob(500) FROM n;",
+        );
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let BTreeCell::TableInteriorCell(cell) = page_cells(&db, root_page).remove(0) else {
+            panic!("expected a table interior cell");
+        };
+        // The key of an interior cell is the largest rowid of its left child.
+        let Some(BTreeCell::TableLeafCell(last)) =
+            page_cells(&db, cell._left_child_page as usize).pop()
+        else {
+            panic!("expected a table leaf cell");
+        };
+        assert_eq!(cell._rowid, last._rowid);
+    }
+
+    #[test]
+    fn test_read_index_cells() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             CREATE INDEX t_v ON t (v);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+             INSERT INTO t SELECT i, printf('value %05d', i) FROM n;",
+        );
+        let root_page = db.schema.borrow().indexes["t"][0].root_page;
+        let BTreeCell::IndexInteriorCell(interior) = page_cells(&db, root_page).remove(0) else {
+            panic!("expected an index interior cell");
+        };
+        // An index key is the indexed values followed by the rowid.
+        let key = RecordReader::new(&interior.payload, RecordOptions::default())
+            .unwrap()
+            .record()
+            .unwrap();
+        assert_eq!(key.values.len(), 2);
+        let OwnedValue::Integer(rowid) = key.values[1] else {
+            panic!("expected a rowid, got {:?}", key.values[1]);
+        };
+        assert_eq!(
+            key.values[0],
+            OwnedValue::Text(Rc::new(format!("value {:05}", rowid)))
+        );
+
+        let BTreeCell::IndexLeafCell(leaf) =
+            page_cells(&db, interior.left_child_page as usize).remove(0)
+        else {
+            panic!("expected an index leaf cell");
+        };
+        assert_eq!(
+            RecordReader::new(&leaf.payload, RecordOptions::default())
+                .unwrap()
+                .record()
+                .unwrap()
+                .values,
+            vec![
+                OwnedValue::Text(Rc::new("value 00001".to_string())),
+                OwnedValue::Integer(1)
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case::wal("PRAGMA journal_mode = WAL;", true)]
+    #[case::rollback_journal("PRAGMA journal_mode = DELETE;", false)]
+    fn test_supports_concurrent_readers(#[case] journal_mode: &str, #[case] expected: bool) {
+        let (_dir, db) = open_test_database(&format!("{} CREATE TABLE t (x);", journal_mode));
+        assert_eq!(db.supports_concurrent_readers(), expected);
+    }
+
+    #[test]
+    fn test_read_index_payload_overflow() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             CREATE INDEX t_v ON t (v);
+             INSERT INTO t VALUES (1, printf('%.3000c', 'k'));",
+        );
+        let root_page = db.schema.borrow().indexes["t"][0].root_page;
+        let usable_size = db.pager.usable_size();
+        let page = db.pager.read_page_blocking(root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        assert_eq!(contents.page_type(), PageType::IndexLeaf);
+        let (cell_pointers, _) = contents.cell_get_raw_pointer_region();
+        let cell_pointer = contents.read_u16(cell_pointers) as usize;
+        assert!(cell_has_overflow(
+            contents.as_ptr(),
+            &contents.page_type(),
+            cell_pointer,
+            usable_size
+        )
+        .unwrap());
+
+        let payload = read_index_payload(contents, 0, db.pager.clone(), usable_size).unwrap();
+        assert_eq!(
+            RecordReader::new(&payload, RecordOptions::default())
+                .unwrap()
+                .record()
+                .unwrap()
+                .values,
+            vec![
+                OwnedValue::Text(Rc::new("k".repeat(3000))),
+                OwnedValue::Integer(1)
+            ]
+        );
+
+        let table_root = db.schema.borrow().get_table("t").unwrap().root_page;
+        let table_page = db.pager.read_page_blocking(table_root).unwrap();
+        let table_page = table_page.borrow();
+        assert!(read_index_payload(
+            table_page.contents.as_ref().unwrap(),
+            0,
+            db.pager.clone(),
+            usable_size
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_total_freeblock_bytes() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, printf('%.20c', 'a'));
+             INSERT INTO t VALUES (2, printf('%.20c', 'b'));
+             INSERT INTO t VALUES (3, printf('%.20c', 'c'));
+             INSERT INTO t VALUES (4, printf('%.20c', 'd'));
+             INSERT INTO t VALUES (5, printf('%.20c', 'e'));
+             DELETE FROM t WHERE x IN (2, 4);",
+        );
+        let root_page = db.schema.borrow().get_table("t").unwrap().root_page;
+        let page = db.pager.read_page_blocking(root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        assert_eq!(contents.num_frag_free_bytes(), 0);
+        // Each deleted cell is 25 bytes: a payload size, a rowid and a 23-byte record.
+        // They are not adjacent, so each leaves a freeblock of its own.
+        assert_eq!(total_freeblock_bytes(contents).unwrap(), 50);
+
+        contents.write_u8(7, 3);
+        assert_eq!(total_freeblock_bytes(contents).unwrap(), 53);
+
+        // Point the first freeblock back at itself.
+        let first = contents.first_freeblock() as usize;
+        contents.as_ptr()[first..first + 2].copy_from_slice(&(first as u16).to_be_bytes());
+        assert!(total_freeblock_bytes(contents).is_err());
+    }
+
+    #[rstest]
+    #[case::header_size_zero(&[0x00], "Record header size 0 is too small")]
+    #[case::header_size_padded_one(&[0x80, 0x01], "Record header size 1 is too small")]
+    #[case::serial_type_past_header(&[0x02, 0x81, 0x01], "Record header serial type runs past the header")]
+    #[case::header_past_payload(&[0x05, 0x01, 0x01], "Invalid varint")]
+    #[case::value_past_payload(&[0x02, 0x04, 0x00], "Invalid BEInt32 value")]
+    fn test_read_record_malformed(#[case] payload: &[u8], #[case] error: &str) {
+        let err = RecordReader::new(payload, RecordOptions::default())
+            .and_then(|reader| reader.record())
+            .unwrap_err();
+        assert_eq!(err.to_string(), format!("Corrupt database: {}", error));
+    }
+
+    #[test]
+    fn test_read_record_tolerant() {
+        // An integer, then a text value declared as 5 bytes of which 3 remain.
+        let payload = [0x03, 0x01, 0x17, 0x2a, b'a', b'b', b'c'];
+        let err = RecordReader::new(&payload, RecordOptions::default())
+            .and_then(|reader| reader.record())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Corrupt database: Invalid String value, length 3 < expected length 5"
+        );
+
+        let options = RecordOptions {
+            tolerant: true,
+            ..RecordOptions::default()
+        };
+        let reader = RecordReader::new(&payload, options).unwrap();
+        let (record, truncated) = reader.record_with_truncated().unwrap();
+        assert_eq!(
+            record.values,
+            vec![
+                OwnedValue::Integer(42),
+                OwnedValue::Text(Rc::new("abc".to_string()))
+            ]
+        );
+        assert_eq!(
+            truncated,
+            vec![TruncatedValue {
+                column: 1,
+                expected: 5,
+                actual: 3
+            }]
+        );
+
+        let reader = RecordReader::new(&payload[..4], options).unwrap();
+        let (record, truncated) = reader.record_with_truncated().unwrap();
+        assert_eq!(record.values[1], OwnedValue::Text(Rc::new(String::new())));
+        assert_eq!(truncated[0].actual, 0);
+    }
+
+    #[test]
+    fn test_read_record_max_columns() {
+        // A header claiming 100000 bytes of single-byte NULL serial types.
+        let mut payload = vec![0x86, 0x8d, 0x20];
+        payload.resize(100_000, 0);
+        let err = RecordReader::new(&payload, RecordOptions::default())
+            .and_then(|reader| reader.record())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Corrupt database: Record has more than 32768 columns"
+        );
+
+        let payload = [4, 1, 1, 1, 7, 8, 9];
+        let max_columns = |max_columns| RecordOptions {
+            max_columns,
+            ..RecordOptions::default()
+        };
+        let reader = RecordReader::new(&payload, max_columns(3)).unwrap();
+        assert_eq!(reader.record().unwrap().values.len(), 3);
+        assert!(RecordReader::new(&payload, max_columns(2)).is_err());
+    }
+
+    #[test]
+    fn test_read_record_strict() {
+        let record = OwnedRecord::new(vec![
+            OwnedValue::Integer(42),
+            OwnedValue::Text(Rc::new("hello".to_string())),
+        ]);
+        let mut payload = Vec::new();
+        record.serialize(&mut payload);
+        let options = RecordOptions {
+            strict: true,
+            ..RecordOptions::default()
+        };
+        let read_strict = |payload: &[u8]| RecordReader::new(payload, options)?.record();
+        assert_eq!(read_strict(&payload).unwrap(), record);
+
+        payload.extend_from_slice(&[0xde, 0xad, 0xbe]);
+        let err = read_strict(&payload).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Corrupt database: Record has 3 trailing bytes after its last value"
+        );
+        assert_eq!(
+            RecordReader::new(&payload, RecordOptions::default())
+                .unwrap()
+                .record()
+                .unwrap(),
+            record
+        );
+    }
+
+    #[test]
+    fn test_negative_rowid_varint() {
+        let (_dir, db) = open_test_database(&format!(
+            "CREATE TABLE t (x INTEGER PRIMARY KEY);
+             INSERT INTO t VALUES ({}), (-1), ({}), ({});",
+            i64::MIN,
+            i64::MAX - 1,
+            i64::MAX
+        ));
+        let rowids = table_leaf_cells(&db, "t")
+            .iter()
+            .map(|cell| cell._rowid)
+            .collect::<Vec<_>>();
+        // The first two varints exceed i64::MAX, yet are the rowids as written.
+        assert_eq!(
+            rowids,
+            vec![
+                i64::MIN as u64,
+                u64::MAX,
+                i64::MAX as u64 - 1,
+                i64::MAX as u64
+            ]
+        );
+        assert_eq!(
+            rowids.iter().map(|&r| r as i64).collect::<Vec<_>>(),
+            vec![i64::MIN, -1, i64::MAX - 1, i64::MAX]
+        );
+    }
+
+    #[test]
+    fn test_cell_pointer_past_page() {
+        let (_dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY);
+             INSERT INTO t VALUES (1);",
+        );
+        let table = db.schema.borrow().get_table("t").unwrap();
+        let page = db.pager.read_page_blocking(table.root_page).unwrap();
+        let page = page.borrow();
+        let contents = page.contents.as_ref().unwrap();
+        contents.write_u16(8, 0xffff);
+        let usable_size = db.pager.usable_size();
+        let (max_local, min_local) = payload_thresholds(&contents.page_type(), usable_size);
+        let err = contents
+            .cell_get(0, db.pager.clone(), max_local, min_local, usable_size)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Corrupt database: Cell 0 points past the usable space of the page at offset 65535"
+        );
+    }
+
+    #[rstest]
+    #[case(1, FileFormatVersion::Legacy, true)]
+    #[case(2, FileFormatVersion::Wal, true)]
+    #[case(3, FileFormatVersion::Unknown(3), false)]
+    fn test_write_version(
+        #[case] write_version: u8,
+        #[case] expected: FileFormatVersion,
+        #[case] can_write: bool,
+    ) {
+        let header = DatabaseHeader {
+            write_version,
+            ..DatabaseHeader::default()
+        };
+        assert_eq!(header.write_version(), expected);
+        assert_eq!(header.read_version(), FileFormatVersion::Wal);
+        assert_eq!(header.can_write(), can_write);
+    }
+
+    #[test]
+    fn test_refuse_write_to_unknown_format() {
+        let (_dir, db) = open_test_database("CREATE TABLE t (x INTEGER PRIMARY KEY);");
+        db.header.borrow_mut().write_version = 3;
+        let conn = db.connect();
+        let err = conn.execute("INSERT INTO t VALUES (1)").unwrap_err();
+        assert!(matches!(err, LimboError::ReadOnly), "{:?}", err);
+    }
 }
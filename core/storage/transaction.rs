@@ -0,0 +1,281 @@
+//! Page-level write transactions.
+//!
+//! A `Transaction` buffers whole page images in memory. Nothing reaches the pager
+//! until commit, so rolling back only drops the buffered pages.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use crate::storage::pager::{Page, Pager};
+use crate::storage::sqlite3_ondisk::DatabaseHeader;
+use crate::storage::wal::CheckpointStatus;
+use crate::{LimboError, Result};
+
+/// A page a commit replaced in the page cache, and its image before the commit.
+type ReplacedPage = (usize, Rc<RefCell<Page>>, Vec<u8>);
+
+/// A set of page writes that is committed atomically through the WAL.
+pub struct Transaction {
+    pager: Rc<Pager>,
+    header: Rc<RefCell<DatabaseHeader>>,
+    pages: BTreeMap<usize, Vec<u8>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(pager: Rc<Pager>, header: Rc<RefCell<DatabaseHeader>>) -> Self {
+        Self {
+            pager,
+            header,
+            pages: BTreeMap::new(),
+        }
+    }
+
+    /// Replaces the contents of page `page_idx` with `data`, a full page image.
+    ///
+    /// A later write to the same page replaces the earlier one.
+    pub fn put(&mut self, page_idx: usize, data: &[u8]) -> Result<()> {
+        let (page_size, database_size) = {
+            let header = self.header.borrow();
+            (header.page_size() as usize, header.database_size as usize)
+        };
+        if data.len() != page_size {
+            return Err(LimboError::InternalError(format!(
+                "page data is {} bytes, expected {}",
+                data.len(),
+                page_size
+            )));
+        }
+        if page_idx == 0 || page_idx > database_size {
+            return Err(LimboError::InternalError(format!(
+                "page {} is out of range, the database has {} pages",
+                page_idx, database_size
+            )));
+        }
+        self.pages.insert(page_idx, data.to_vec());
+        Ok(())
+    }
+
+    /// Writes the buffered pages to the WAL and syncs it, then bumps the file
+    /// change counter in the same commit.
+    ///
+    /// If the commit fails, the cached pages and the header are put back as they
+    /// were, so the failed commit does not show to later reads.
+    pub fn commit(self) -> Result<()> {
+        if self.pages.is_empty() {
+            return Ok(());
+        }
+        let header = self.header.borrow().clone();
+        let mut replaced = Vec::with_capacity(self.pages.len() + 1);
+        let result = self.apply(&mut replaced).and_then(|()| self.flush());
+        if result.is_err() {
+            // Restore in reverse, as page 1 is replaced twice when it is in the
+            // transaction.
+            for (page_idx, page, image) in replaced.iter().rev() {
+                let page = page.borrow();
+                page.contents
+                    .as_ref()
+                    .unwrap()
+                    .as_ptr()
+                    .copy_from_slice(image);
+                page.clear_dirty();
+                self.pager.remove_dirty(*page_idx);
+            }
+            *self.header.borrow_mut() = header;
+        }
+        result
+    }
+
+    /// Copies the buffered pages and the bumped header into the page cache,
+    /// recording each page and its previous image in `replaced`.
+    fn apply(&self, replaced: &mut Vec<ReplacedPage>) -> Result<()> {
+        for (&page_idx, data) in &self.pages {
+            let page = self.pager.read_page_blocking(page_idx)?;
+            {
+                let page_ref = page.borrow();
+                let buf = page_ref.contents.as_ref().unwrap().as_ptr();
+                replaced.push((page_idx, page.clone(), buf.to_vec()));
+                buf.copy_from_slice(data);
+                page_ref.set_dirty();
+            }
+            self.pager.add_dirty(page_idx);
+        }
+        let mut header = self.header.borrow_mut();
+        header.bump_change_counter();
+        let page1 = self.pager.read_page_blocking(1)?;
+        {
+            let page1_ref = page1.borrow();
+            let contents = page1_ref.contents.as_ref().unwrap();
+            replaced.push((1, page1.clone(), contents.as_ptr().to_vec()));
+            contents.write_database_header(&header);
+            page1_ref.set_dirty();
+        }
+        self.pager.add_dirty(1);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        loop {
+            match self.pager.cacheflush()? {
+                CheckpointStatus::Done => return Ok(()),
+                CheckpointStatus::IO => self.pager.io.run_once()?,
+            }
+        }
+    }
+
+    /// Discards the buffered pages, leaving the database unchanged.
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::io::OpenFlags;
+    use crate::storage::btree::tests::{open_test_database, test_io};
+    use crate::storage::buffer_pool::BufferPool;
+    use crate::storage::pager::{Page, Pager};
+    use crate::storage::wal::{CheckpointStatus, Wal};
+    use crate::{Database, FileStorage, LimboError, Result};
+
+    /// A WAL holding no frames that fails every write.
+    struct FailingWal;
+
+    impl Wal for FailingWal {
+        fn begin_read_tx(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn begin_write_tx(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn end_read_tx(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn end_write_tx(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn find_frame(&self, _page_id: u64) -> Result<Option<u64>> {
+            Ok(None)
+        }
+
+        fn read_frame(
+            &self,
+            frame_id: u64,
+            _page: Rc<RefCell<Page>>,
+            _buffer_pool: Rc<BufferPool>,
+        ) -> Result<()> {
+            unreachable!("the WAL has no frame {}", frame_id)
+        }
+
+        fn append_frame(
+            &mut self,
+            _page: Rc<RefCell<Page>>,
+            _db_size: u32,
+            _pager: &Pager,
+            _write_counter: Rc<RefCell<usize>>,
+        ) -> Result<()> {
+            Err(LimboError::InternalError("WAL write failed".to_string()))
+        }
+
+        fn should_checkpoint(&self) -> bool {
+            false
+        }
+
+        fn checkpoint(
+            &mut self,
+            _pager: &Pager,
+            _write_counter: Rc<RefCell<usize>>,
+        ) -> Result<CheckpointStatus> {
+            Ok(CheckpointStatus::Done)
+        }
+
+        fn sync(&mut self) -> Result<CheckpointStatus> {
+            Ok(CheckpointStatus::Done)
+        }
+    }
+
+    fn page_image(db: &Database, page_idx: usize) -> Vec<u8> {
+        let page = db.pager.read_page_blocking(page_idx).unwrap();
+        let page = page.borrow();
+        page.contents.as_ref().unwrap().as_ptr().to_vec()
+    }
+
+    #[test]
+    fn test_transaction_rollback_and_commit() {
+        let (dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE a (x INTEGER PRIMARY KEY);
+             CREATE TABLE b (x INTEGER PRIMARY KEY);",
+        );
+        let originals = [page_image(&db, 2), page_image(&db, 3)];
+        let change_counter = db.header.borrow().change_counter();
+        // Byte 2000 is in the unallocated space of both empty leaves.
+        let updates = originals.clone().map(|mut image| {
+            image[2000] = 0xab;
+            image
+        });
+
+        let mut tx = db.begin();
+        tx.put(2, &updates[0]).unwrap();
+        tx.put(3, &updates[1]).unwrap();
+        assert!(tx.put(4, &updates[1]).is_err());
+        tx.rollback();
+        assert_eq!(page_image(&db, 2), originals[0]);
+        assert_eq!(page_image(&db, 3), originals[1]);
+        assert_eq!(db.header.borrow().change_counter(), change_counter);
+
+        let mut tx = db.begin();
+        tx.put(2, &updates[0]).unwrap();
+        tx.put(3, &updates[1]).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(db.header.borrow().change_counter(), change_counter + 1);
+
+        // Checkpoint the WAL and check the writes reached the database file.
+        loop {
+            match db.pager.checkpoint().unwrap() {
+                CheckpointStatus::Done => break,
+                CheckpointStatus::IO => db.pager.io.run_once().unwrap(),
+            }
+        }
+        let file = std::fs::read(dir.path().join("test.db")).unwrap();
+        assert_eq!(file[4096..8192], updates[0][..]);
+        assert_eq!(file[8192..12288], updates[1][..]);
+        assert_eq!(file[24..28], u32::to_be_bytes(change_counter + 1));
+    }
+
+    #[test]
+    fn test_transaction_commit_fails() {
+        let (dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE a (x INTEGER PRIMARY KEY);",
+        );
+        drop(db);
+        let io = test_io();
+        let path = dir.path().join("test.db");
+        let file = io
+            .open_file(path.to_str().unwrap(), OpenFlags::None, true)
+            .unwrap();
+        let page_io = Rc::new(FileStorage::new(file));
+        let db = Database::open(io, page_io, Rc::new(RefCell::new(FailingWal))).unwrap();
+        let originals = [page_image(&db, 1), page_image(&db, 2)];
+        let header = db.header.borrow().clone();
+        let mut updates = originals.clone();
+        // Byte 2000 is in the unallocated space of both pages.
+        updates[0][2000] = 0xab;
+        updates[1][2000] = 0xab;
+
+        let mut tx = db.begin();
+        tx.put(1, &updates[0]).unwrap();
+        tx.put(2, &updates[1]).unwrap();
+        assert!(matches!(tx.commit(), Err(LimboError::InternalError(_))));
+        assert_eq!(page_image(&db, 1), originals[0]);
+        assert_eq!(page_image(&db, 2), originals[1]);
+        assert_eq!(db.header.borrow().change_counter(), header.change_counter());
+        assert!(!db.pager.read_page_blocking(2).unwrap().borrow().is_dirty());
+    }
+}
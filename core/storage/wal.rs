@@ -7,14 +7,14 @@ use crate::io::{File, SyncCompletion, IO};
 use crate::storage::sqlite3_ondisk::{
     begin_read_wal_frame, begin_write_wal_frame, WAL_FRAME_HEADER_SIZE, WAL_HEADER_SIZE,
 };
-use crate::Completion;
-use crate::{storage::pager::Page, Result};
+use crate::{storage::pager::Page, LimboError, Result};
+use crate::{Buffer, Completion};
 
 use self::sqlite3_ondisk::{checksum_wal, WAL_MAGIC_BE, WAL_MAGIC_LE};
 
 use super::buffer_pool::BufferPool;
 use super::pager::Pager;
-use super::sqlite3_ondisk::{self, begin_write_btree_page, WalHeader};
+use super::sqlite3_ondisk::{self, begin_write_btree_page, PageContent, WalHeader};
 
 /// Write-ahead log (WAL).
 pub trait Wal {
@@ -69,6 +69,8 @@ pub struct WalFile {
     nbackfills: RefCell<u64>,
     // Maps pgno to frame id and offset in wal file
     frame_cache: RefCell<HashMap<u64, Vec<u64>>>, // FIXME: for now let's use a simple hashmap instead of a shm file
+    // Cumulative checksum of the frames preceding each frame, indexed by frame id
+    frame_checksums: RefCell<Vec<(u32, u32)>>,
     checkpoint_threshold: usize,
     ongoing_checkpoint: HashSet<usize>,
 
@@ -122,11 +124,26 @@ impl Wal for WalFile {
     ) -> Result<()> {
         debug!("read_frame({})", frame_id);
         let offset = self.frame_offset(frame_id);
+        let Some(checksums) = self
+            .frame_checksums
+            .borrow()
+            .get(frame_id as usize)
+            .copied()
+        else {
+            return Err(LimboError::InternalError(format!(
+                "no checksum for WAL frame {}",
+                frame_id
+            )));
+        };
+        let header = self.wal_header.borrow();
+        let header = header.as_ref().unwrap().borrow();
         begin_read_wal_frame(
             self.file.borrow().as_ref().unwrap(),
-            offset + WAL_FRAME_HEADER_SIZE,
+            offset,
             buffer_pool,
             page,
+            &header,
+            checksums,
         )?;
         Ok(())
     }
@@ -139,42 +156,7 @@ impl Wal for WalFile {
         _pager: &Pager,
         write_counter: Rc<RefCell<usize>>,
     ) -> Result<()> {
-        self.ensure_init()?;
-        let page_id = page.borrow().id;
-        let frame_id = *self.max_frame.borrow();
-        let offset = self.frame_offset(frame_id);
-        trace!(
-            "append_frame(frame={}, offset={}, page_id={})",
-            frame_id,
-            offset,
-            page_id
-        );
-        let header = self.wal_header.borrow();
-        let header = header.as_ref().unwrap();
-        let header = header.borrow();
-        let checksums = *self.last_checksum.borrow();
-        let checksums = begin_write_wal_frame(
-            self.file.borrow().as_ref().unwrap(),
-            offset,
-            &page,
-            db_size,
-            write_counter,
-            &header,
-            checksums,
-        )?;
-        self.last_checksum.replace(checksums);
-        self.max_frame.replace(frame_id + 1);
-        {
-            let mut frame_cache = self.frame_cache.borrow_mut();
-            let frames = frame_cache.get_mut(&(page_id as u64));
-            match frames {
-                Some(frames) => frames.push(frame_id),
-                None => {
-                    frame_cache.insert(page_id as u64, vec![frame_id]);
-                }
-            }
-        }
-        Ok(())
+        self.append(page, db_size, write_counter)
     }
 
     /// Begin a write transaction
@@ -215,6 +197,7 @@ impl Wal for WalFile {
         }
 
         self.frame_cache.borrow_mut().clear();
+        self.frame_checksums.borrow_mut().clear();
         *self.max_frame.borrow_mut() = 0;
         self.ongoing_checkpoint.clear();
         Ok(CheckpointStatus::Done)
@@ -250,6 +233,7 @@ impl WalFile {
             file: RefCell::new(None),
             wal_header: RefCell::new(None),
             frame_cache: RefCell::new(HashMap::new()),
+            frame_checksums: RefCell::new(Vec::new()),
             min_frame: RefCell::new(0),
             max_frame: RefCell::new(0),
             nbackfills: RefCell::new(0),
@@ -261,6 +245,90 @@ impl WalFile {
         }
     }
 
+    /// Appends a frame holding `page_data` as the image of page `page_no`.
+    ///
+    /// A frame with `commit_db_size` set is a commit frame, which ends a transaction
+    /// and records the size of the database in pages after it. Other frames record
+    /// a size of 0.
+    pub fn append_page_data(
+        &mut self,
+        page_no: u32,
+        page_data: &[u8],
+        commit_db_size: Option<u32>,
+        write_counter: Rc<RefCell<usize>>,
+    ) -> Result<()> {
+        if page_data.len() != self.page_size {
+            return Err(LimboError::InternalError(format!(
+                "page data is {} bytes, expected {}",
+                page_data.len(),
+                self.page_size
+            )));
+        }
+        let drop_fn = Rc::new(|_buf| {});
+        let mut buffer = Buffer::allocate(self.page_size, drop_fn);
+        buffer.as_mut_slice().copy_from_slice(page_data);
+        let mut page = Page::new(page_no as usize);
+        page.contents = Some(PageContent {
+            offset: 0,
+            buffer: Rc::new(RefCell::new(buffer)),
+            overflow_cells: Vec::new(),
+        });
+        self.append(
+            Rc::new(RefCell::new(page)),
+            commit_db_size.unwrap_or(0),
+            write_counter,
+        )
+    }
+
+    fn append(
+        &mut self,
+        page: Rc<RefCell<Page>>,
+        db_size: u32,
+        write_counter: Rc<RefCell<usize>>,
+    ) -> Result<()> {
+        self.ensure_init()?;
+        let page_id = page.borrow().id;
+        let frame_id = *self.max_frame.borrow();
+        let offset = self.frame_offset(frame_id);
+        trace!(
+            "append_frame(frame={}, offset={}, page_id={})",
+            frame_id,
+            offset,
+            page_id
+        );
+        let header = self.wal_header.borrow();
+        let header = header.as_ref().unwrap();
+        let header = header.borrow();
+        let checksums = *self.last_checksum.borrow();
+        let checksums = begin_write_wal_frame(
+            self.file.borrow().as_ref().unwrap(),
+            offset,
+            &page,
+            db_size,
+            write_counter,
+            &header,
+            checksums,
+        )?;
+        {
+            let mut frame_checksums = self.frame_checksums.borrow_mut();
+            frame_checksums.truncate(frame_id as usize);
+            frame_checksums.push(*self.last_checksum.borrow());
+        }
+        self.last_checksum.replace(checksums);
+        self.max_frame.replace(frame_id + 1);
+        {
+            let mut frame_cache = self.frame_cache.borrow_mut();
+            let frames = frame_cache.get_mut(&(page_id as u64));
+            match frames {
+                Some(frames) => frames.push(frame_id),
+                None => {
+                    frame_cache.insert(page_id as u64, vec![frame_id]);
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn ensure_init(&self) -> Result<()> {
         if self.file.borrow().is_none() {
             match self
@@ -275,6 +343,16 @@ impl WalFile {
                         };
                         // TODO: Return a completion instead.
                         self.io.run_once()?;
+                        // Frames are read as whole pages of the database, so a WAL
+                        // left over from before the page size changed cannot be used.
+                        let wal_page_size = wal_header.borrow().page_size as usize;
+                        if wal_page_size != self.page_size {
+                            crate::bail_corrupt_error!(
+                                "WAL page size {} does not match the database page size {}",
+                                wal_page_size,
+                                self.page_size
+                            );
+                        }
                         self.wal_header.replace(Some(wal_header));
                     } else {
                         // magic is a single number represented as WAL_MAGIC_LE but the big endian
@@ -329,3 +407,138 @@ impl WalFile {
         offset as usize
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::btree::tests::{open_test_database, test_io};
+    use std::os::unix::fs::FileExt;
+
+    #[test]
+    fn test_read_frame_validates_checksum() {
+        let (dir, db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, 'a');",
+        );
+        let pager = &db.pager;
+        let wal_path = dir.path().join("frames.wal");
+        let mut wal = WalFile::new(
+            pager.io.clone(),
+            wal_path.to_str().unwrap().to_string(),
+            4096,
+        );
+        let buffer_pool = Rc::new(BufferPool::new(4096));
+        let write_counter = Rc::new(RefCell::new(0));
+        for page_idx in [1, 2] {
+            let page = pager.read_page_blocking(page_idx).unwrap();
+            wal.append_frame(page, 2, pager, write_counter.clone())
+                .unwrap();
+        }
+        while *write_counter.borrow() > 0 {
+            pager.io.run_once().unwrap();
+        }
+
+        let read_frame = |wal: &WalFile, frame_id: u64, page_idx: usize| {
+            let page = Rc::new(RefCell::new(Page::new(page_idx)));
+            page.borrow().set_locked();
+            wal.read_frame(frame_id, page.clone(), buffer_pool.clone())
+                .unwrap();
+            while page.borrow().is_locked() {
+                pager.io.run_once().unwrap();
+            }
+            page
+        };
+        let page = read_frame(&wal, 1, 2);
+        assert!(!page.borrow().is_error());
+        let original = pager.read_page_blocking(2).unwrap();
+        assert_eq!(
+            page.borrow().contents.as_ref().unwrap().as_ptr(),
+            original.borrow().contents.as_ref().unwrap().as_ptr()
+        );
+
+        // Flip a byte in the page image of the second frame, as a torn write would.
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&wal_path)
+            .unwrap();
+        let offset = wal.frame_offset(1) + WAL_FRAME_HEADER_SIZE + 100;
+        file.write_all_at(&[0xff], offset as u64).unwrap();
+
+        assert!(read_frame(&wal, 1, 2).borrow().is_error());
+        assert!(!read_frame(&wal, 0, 1).borrow().is_error());
+    }
+
+    #[test]
+    fn test_wal_page_size_mismatch() {
+        let (dir, _db) = open_test_database(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (x INTEGER PRIMARY KEY);",
+        );
+        let path = dir.path().join("test.db");
+        // A WAL header declaring 1024-byte pages: magic, format, page size, then
+        // the checkpoint sequence, salts and checksums.
+        let bytes = [WAL_MAGIC_BE, 3007000, 1024, 0, 0, 0, 0, 0]
+            .iter()
+            .flat_map(|field| field.to_be_bytes())
+            .collect::<Vec<_>>();
+        std::fs::write(dir.path().join("test.db-wal"), bytes).unwrap();
+
+        let err = match crate::Database::open_file(test_io(), path.to_str().unwrap()) {
+            Ok(_) => panic!("opened a database with a mismatched WAL"),
+            Err(err) => err,
+        };
+        assert_eq!(
+            err.to_string(),
+            "Corrupt database: WAL page size 1024 does not match the database page size 4096"
+        );
+    }
+
+    #[test]
+    fn test_append_page_data() {
+        let (dir, db) = open_test_database("PRAGMA page_size = 4096;");
+        let pager = &db.pager;
+        let wal_path = dir.path().join("frames.wal");
+        let mut wal = WalFile::new(
+            pager.io.clone(),
+            wal_path.to_str().unwrap().to_string(),
+            4096,
+        );
+        let write_counter = Rc::new(RefCell::new(0));
+        let images = [vec![0x11; 4096], vec![0x22; 4096]];
+        wal.append_page_data(2, &images[0], None, write_counter.clone())
+            .unwrap();
+        wal.append_page_data(3, &images[1], Some(3), write_counter.clone())
+            .unwrap();
+        assert!(wal
+            .append_page_data(4, &[0; 100], None, write_counter.clone())
+            .is_err());
+        while *write_counter.borrow() > 0 {
+            pager.io.run_once().unwrap();
+        }
+
+        let file = std::fs::File::open(&wal_path).unwrap();
+        let buffer_pool = Rc::new(BufferPool::new(4096));
+        for (frame_id, (page_no, db_size)) in [(2, 0), (3, 3)].into_iter().enumerate() {
+            let mut frame_header = [0; WAL_FRAME_HEADER_SIZE];
+            file.read_exact_at(&mut frame_header, wal.frame_offset(frame_id as u64) as u64)
+                .unwrap();
+            assert_eq!(frame_header[0..4], u32::to_be_bytes(page_no));
+            assert_eq!(frame_header[4..8], u32::to_be_bytes(db_size));
+
+            let page = Rc::new(RefCell::new(Page::new(page_no as usize)));
+            page.borrow().set_locked();
+            wal.read_frame(frame_id as u64, page.clone(), buffer_pool.clone())
+                .unwrap();
+            while page.borrow().is_locked() {
+                pager.io.run_once().unwrap();
+            }
+            let page = page.borrow();
+            assert!(!page.is_error());
+            assert_eq!(
+                page.contents.as_ref().unwrap().as_ptr(),
+                &images[frame_id][..]
+            );
+        }
+    }
+}
@@ -1159,6 +1159,8 @@ fn group_by_emit(
         .map(|i| Column {
             name: i.to_string(),
             primary_key: false,
+            is_boolean: false,
+            default: None,
             ty: crate::schema::Type::Null,
         })
         .collect::<Vec<_>>();
@@ -1505,6 +1507,8 @@ fn order_by_emit(
             // Names don't matter. We are tracking which result column is in which position in the ORDER BY clause in m.result_column_indexes_in_orderby_sorter.
             name: format!("sort_key_{}", i),
             primary_key: false,
+            is_boolean: false,
+            default: None,
             ty: crate::schema::Type::Null,
         });
     }
@@ -1518,6 +1522,8 @@ fn order_by_emit(
         pseudo_columns.push(Column {
             name: rc.expr.to_string(),
             primary_key: false,
+            is_boolean: false,
+            default: None,
             ty: crate::schema::Type::Null,
         });
     }
@@ -4,9 +4,9 @@ use std::{cell::Ref, rc::Rc};
 use crate::error::LimboError;
 use crate::Result;
 
-use crate::storage::sqlite3_ondisk::write_varint;
+use crate::storage::sqlite3_ondisk::{value_serial_type, write_varint, SerialType};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub enum Value<'a> {
     Null,
     Integer(i64),
@@ -15,14 +15,111 @@ pub enum Value<'a> {
     Blob(&'a Vec<u8>),
 }
 
+impl<'a> Value<'a> {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&'a str> {
+        match self {
+            Value::Text(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn as_blob(&self) -> Option<&'a [u8]> {
+        match self {
+            Value::Blob(b) => Some(b.as_slice()),
+            _ => None,
+        }
+    }
+}
+
+/// Compares values as SQLite sorts them: NULL first, then numbers, text and blobs.
+/// Integers and floats compare by numeric value, so `Integer(1)` equals
+/// `Float(1.0)`, text compares byte by byte as the BINARY collation does, and
+/// blobs compare as with `memcmp`. Only a NaN float is unordered.
+impl<'a> PartialOrd for Value<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        use std::cmp::Ordering;
+        fn type_class(value: &Value) -> u8 {
+            match value {
+                Value::Null => 0,
+                Value::Integer(_) | Value::Float(_) => 1,
+                Value::Text(_) => 2,
+                Value::Blob(_) => 3,
+            }
+        }
+        match (self, other) {
+            (Value::Null, Value::Null) => Some(Ordering::Equal),
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::Integer(a), Value::Float(b)) => compare_int_float(*a, *b),
+            (Value::Float(a), Value::Integer(b)) => {
+                compare_int_float(*b, *a).map(Ordering::reverse)
+            }
+            (Value::Text(a), Value::Text(b)) => Some(a.as_bytes().cmp(b.as_bytes())),
+            (Value::Blob(a), Value::Blob(b)) => Some(a.as_slice().cmp(b.as_slice())),
+            _ => Some(type_class(self).cmp(&type_class(other))),
+        }
+    }
+}
+
+impl<'a> PartialEq for Value<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(std::cmp::Ordering::Equal)
+    }
+}
+
+/// Compares an integer with a float without rounding the integer to the nearest
+/// float, mirroring sqlite3IntFloatCompare().
+fn compare_int_float(i: i64, f: f64) -> Option<std::cmp::Ordering> {
+    use std::cmp::Ordering;
+    if f.is_nan() {
+        return None;
+    }
+    if f < -9223372036854775808.0 {
+        return Some(Ordering::Greater);
+    }
+    if f >= 9223372036854775808.0 {
+        return Some(Ordering::Less);
+    }
+    match i.cmp(&(f as i64)) {
+        Ordering::Equal => (i as f64).partial_cmp(&f),
+        ordering => Some(ordering),
+    }
+}
+
+/// Formats values as SQL literals would show them: floats keep a fractional part
+/// and blobs are written in hex, as in `x'01ff'`.
 impl<'a> Display for Value<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Value::Null => write!(f, "NULL"),
             Value::Integer(i) => write!(f, "{}", i),
-            Value::Float(fl) => write!(f, "{}", fl),
+            Value::Float(fl) => write!(f, "{:?}", fl),
             Value::Text(s) => write!(f, "{}", s),
-            Value::Blob(b) => write!(f, "{:?}", b),
+            Value::Blob(b) => {
+                write!(f, "x'")?;
+                for byte in b.iter() {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, "'")
+            }
         }
     }
 }
@@ -328,6 +425,93 @@ impl<'a> FromValue<'a> for &'a str {
     }
 }
 
+/// Converts integers, and floats with no fractional part that fit in an `i64`.
+impl<'a> TryFrom<&Value<'a>> for i64 {
+    type Error = LimboError;
+
+    fn try_from(value: &Value<'a>) -> Result<Self> {
+        match value {
+            Value::Integer(i) => Ok(*i),
+            Value::Float(f)
+                if f.fract() == 0.0
+                    && *f >= -9223372036854775808.0
+                    && *f < 9223372036854775808.0 =>
+            {
+                Ok(*f as i64)
+            }
+            Value::Float(f) => Err(LimboError::ConversionError(format!(
+                "Float {} cannot be converted to an integer without loss",
+                f
+            ))),
+            _ => Err(LimboError::ConversionError("Expected integer value".into())),
+        }
+    }
+}
+
+/// Converts floats, and integers that a float represents exactly.
+impl<'a> TryFrom<&Value<'a>> for f64 {
+    type Error = LimboError;
+
+    fn try_from(value: &Value<'a>) -> Result<Self> {
+        match value {
+            Value::Float(f) => Ok(*f),
+            Value::Integer(i) => {
+                let f = *i as f64;
+                // i64::MAX rounds up to 2^63, which the cast back would saturate.
+                if f < 9223372036854775808.0 && f as i64 == *i {
+                    Ok(f)
+                } else {
+                    Err(LimboError::ConversionError(format!(
+                        "Integer {} cannot be converted to a float without loss",
+                        i
+                    )))
+                }
+            }
+            _ => Err(LimboError::ConversionError("Expected float value".into())),
+        }
+    }
+}
+
+impl<'a> TryFrom<&Value<'a>> for String {
+    type Error = LimboError;
+
+    fn try_from(value: &Value<'a>) -> Result<Self> {
+        match value {
+            Value::Text(s) => Ok(s.to_string()),
+            _ => Err(LimboError::ConversionError("Expected text value".into())),
+        }
+    }
+}
+
+/// Converts blobs, and text as its UTF-8 bytes.
+impl<'a> TryFrom<&Value<'a>> for Vec<u8> {
+    type Error = LimboError;
+
+    fn try_from(value: &Value<'a>) -> Result<Self> {
+        match value {
+            Value::Blob(b) => Ok(b.to_vec()),
+            Value::Text(s) => Ok(s.as_bytes().to_vec()),
+            _ => Err(LimboError::ConversionError("Expected blob value".into())),
+        }
+    }
+}
+
+macro_rules! impl_try_from_owned_value {
+    ($($ty:ty),*) => {
+        $(
+            impl<'a> TryFrom<Value<'a>> for $ty {
+                type Error = LimboError;
+
+                fn try_from(value: Value<'a>) -> Result<Self> {
+                    <$ty>::try_from(&value)
+                }
+            }
+        )*
+    };
+}
+
+impl_try_from_owned_value!(i64, f64, String, Vec<u8>);
+
 #[derive(Debug)]
 pub struct Record<'a> {
     pub values: Vec<Value<'a>>,
@@ -337,6 +521,23 @@ impl<'a> Record<'a> {
     pub fn new(values: Vec<Value<'a>>) -> Self {
         Self { values }
     }
+
+    /// Returns the value of column `idx`, or `None` if the record has fewer columns.
+    pub fn get(&self, idx: usize) -> Option<&Value<'a>> {
+        self.values.get(idx)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Value<'a>> {
+        self.values.iter()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -344,25 +545,53 @@ pub struct OwnedRecord {
     pub values: Vec<OwnedValue>,
 }
 
+/// Returns true if `a` and `b` have as many values and each pair of values compares
+/// equal, so that e.g. the integer 1 equals the float 1.0. This ignores how values
+/// are stored, unlike `==`.
+pub fn records_equal(a: &OwnedRecord, b: &OwnedRecord) -> bool {
+    a.values.len() == b.values.len()
+        && a.values
+            .iter()
+            .zip(&b.values)
+            .all(|(a, b)| a.partial_cmp(b) == Some(std::cmp::Ordering::Equal))
+}
+
+/// Types that can be built from a decoded record, typically by mapping columns to
+/// fields by index with [`OwnedRecord::get`].
+pub trait FromRecord: Sized {
+    fn from_record(record: &OwnedRecord) -> Result<Self>;
+}
+
 impl OwnedRecord {
     pub fn new(values: Vec<OwnedValue>) -> Self {
         Self { values }
     }
 
+    /// Converts the value of column `idx` to `T`.
+    pub fn get<'a, T: FromValue<'a> + 'a>(&'a self, idx: usize) -> Result<T> {
+        let value = self.values.get(idx).ok_or_else(|| {
+            LimboError::ConversionError(format!(
+                "Column {} out of range for a record of {} values",
+                idx,
+                self.values.len()
+            ))
+        })?;
+        T::from_value(&to_value(value))
+    }
+
+    /// Serializes the record for a database of the latest schema format, the
+    /// format of new databases.
     pub fn serialize(&self, buf: &mut Vec<u8>) {
+        self.serialize_with_schema_format(buf, 4)
+    }
+
+    /// Serializes the record using only the serial types known to databases of
+    /// schema format `schema_format`.
+    pub fn serialize_with_schema_format(&self, buf: &mut Vec<u8>, schema_format: u32) {
         let initial_i = buf.len();
 
         for value in &self.values {
-            let serial_type = match value {
-                OwnedValue::Null => 0,
-                OwnedValue::Integer(_) => 6, // for now let's only do i64
-                OwnedValue::Float(_) => 7,
-                OwnedValue::Text(t) => (t.len() * 2 + 13) as u64,
-                OwnedValue::Blob(b) => (b.len() * 2 + 12) as u64,
-                // not serializable values
-                OwnedValue::Agg(_) => unreachable!(),
-                OwnedValue::Record(_) => unreachable!(),
-            };
+            let serial_type = u64::from(value_serial_type(value, schema_format));
 
             buf.resize(buf.len() + 9, 0); // Ensure space for varint
             let len = buf.len();
@@ -373,10 +602,20 @@ impl OwnedRecord {
         let mut header_size = buf.len() - initial_i;
         // write content
         for value in &self.values {
-            // TODO: make integers and floats with smaller serial types
             match value {
                 OwnedValue::Null => {}
-                OwnedValue::Integer(i) => buf.extend_from_slice(&i.to_be_bytes()),
+                OwnedValue::Integer(i) => {
+                    let width = match value_serial_type(value, schema_format) {
+                        SerialType::ConstInt0 | SerialType::ConstInt1 => 0,
+                        SerialType::UInt8 => 1,
+                        SerialType::BEInt16 => 2,
+                        SerialType::BEInt24 => 3,
+                        SerialType::BEInt32 => 4,
+                        SerialType::BEInt48 => 6,
+                        _ => 8,
+                    };
+                    buf.extend_from_slice(&i.to_be_bytes()[8 - width..]);
+                }
                 OwnedValue::Float(f) => buf.extend_from_slice(&f.to_be_bytes()),
                 OwnedValue::Text(t) => buf.extend_from_slice(t.as_bytes()),
                 OwnedValue::Blob(b) => buf.extend_from_slice(b),
@@ -445,3 +684,166 @@ pub trait Cursor {
     fn get_null_flag(&self) -> bool;
     fn btree_create(&mut self, flags: usize) -> u32;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Row {
+        id: i64,
+        name: String,
+    }
+
+    impl FromRecord for Row {
+        fn from_record(record: &OwnedRecord) -> Result<Self> {
+            Ok(Row {
+                id: record.get(0)?,
+                name: record.get(1)?,
+            })
+        }
+    }
+
+    #[test]
+    fn test_record_accessors() {
+        let text = "a".to_string();
+        let record = Record::new(vec![Value::Integer(1), Value::Text(&text), Value::Null]);
+        assert_eq!(record.len(), 3);
+        assert!(!record.is_empty());
+        assert_eq!(record.get(1), Some(&Value::Text(&text)));
+        assert_eq!(record.get(3), None);
+        assert_eq!(
+            record.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+            ["1", "a", "NULL"]
+        );
+        assert!(Record::new(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_value_try_from() {
+        let text = "abc".to_string();
+        let blob = vec![1u8, 2];
+        assert_eq!(i64::try_from(Value::Integer(7)).unwrap(), 7);
+        assert_eq!(i64::try_from(&Value::Float(-3.0)).unwrap(), -3);
+        assert!(i64::try_from(Value::Float(1.5)).is_err());
+        assert!(i64::try_from(Value::Float(9223372036854775808.0)).is_err());
+        assert!(i64::try_from(Value::Text(&text)).is_err());
+
+        assert_eq!(f64::try_from(Value::Float(0.25)).unwrap(), 0.25);
+        assert_eq!(f64::try_from(&Value::Integer(-5)).unwrap(), -5.0);
+        assert!(f64::try_from(Value::Integer(i64::MAX)).is_err());
+        assert!(f64::try_from(Value::Integer((1 << 53) + 1)).is_err());
+        assert!(f64::try_from(Value::Null).is_err());
+
+        assert_eq!(String::try_from(Value::Text(&text)).unwrap(), "abc");
+        assert!(String::try_from(Value::Blob(&blob)).is_err());
+        assert_eq!(Vec::<u8>::try_from(Value::Blob(&blob)).unwrap(), blob);
+        assert_eq!(Vec::<u8>::try_from(&Value::Text(&text)).unwrap(), b"abc");
+        assert!(Vec::<u8>::try_from(Value::Integer(1)).is_err());
+
+        let id: i64 = Value::Integer(42).try_into().unwrap();
+        assert_eq!(id, 42);
+    }
+
+    #[test]
+    fn test_value_ordering() {
+        let (a, b) = ("a".to_string(), "b".to_string());
+        let upper = "B".to_string();
+        let (short, long) = (vec![1u8], vec![1u8, 0]);
+        let ascending = [
+            Value::Null,
+            Value::Float(-1.5),
+            Value::Integer(0),
+            Value::Float(0.5),
+            Value::Integer(1),
+            Value::Text(&upper),
+            Value::Text(&a),
+            Value::Text(&b),
+            Value::Blob(&short),
+            Value::Blob(&long),
+        ];
+        for (i, x) in ascending.iter().enumerate() {
+            for (j, y) in ascending.iter().enumerate() {
+                assert_eq!(x.partial_cmp(y), Some(i.cmp(&j)), "{:?} vs {:?}", x, y);
+            }
+        }
+
+        assert_eq!(Value::Integer(1), Value::Float(1.0));
+        assert_eq!(Value::Float(1.0), Value::Integer(1));
+        assert_ne!(Value::Integer(1), Value::Text(&"1".to_string()));
+        // i64::MAX rounds up to 2^63 as a float, but is still smaller than it.
+        assert!(Value::Integer(i64::MAX) < Value::Float(9223372036854775808.0));
+        assert!(Value::Integer(i64::MIN) > Value::Float(-1e19));
+        assert!(Value::Integer(i64::MAX - 1) < Value::Integer(i64::MAX));
+        assert_eq!(Value::Float(f64::NAN).partial_cmp(&Value::Integer(0)), None);
+    }
+
+    #[test]
+    fn test_value_display_and_accessors() {
+        let text = "hello".to_string();
+        let blob = vec![0x01, 0xab, 0x00];
+        let values = [
+            Value::Null,
+            Value::Integer(-42),
+            Value::Float(2.0),
+            Value::Text(&text),
+            Value::Blob(&blob),
+        ];
+        let shown = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        assert_eq!(shown, ["NULL", "-42", "2.0", "hello", "x'01ab00'"]);
+        assert_eq!(Value::Blob(&Vec::new()).to_string(), "x''");
+
+        assert!(values[0].is_null());
+        assert_eq!(values[1].as_integer(), Some(-42));
+        assert_eq!(values[2].as_float(), Some(2.0));
+        assert_eq!(values[3].as_text(), Some("hello"));
+        assert_eq!(values[4].as_blob(), Some(&blob[..]));
+        assert_eq!(values[3].as_integer(), None);
+        assert_eq!(values[1].as_text(), None);
+        assert!(!values[1].is_null());
+    }
+
+    #[test]
+    fn test_serialize_integral_float() {
+        let record = OwnedRecord::new(vec![OwnedValue::Float(3.0), OwnedValue::Integer(3)]);
+        let mut buf = Vec::new();
+        record.serialize(&mut buf);
+        // Header size, then the BEFloat64 and UInt8 serial types.
+        assert_eq!(buf[..3], [3, 7, 1]);
+        let decoded = crate::storage::sqlite3_ondisk::RecordReader::new(&buf, Default::default())
+            .unwrap()
+            .record()
+            .unwrap();
+        assert_eq!(decoded.values, record.values);
+        assert!(matches!(decoded.values[0], OwnedValue::Float(f) if f == 3.0));
+    }
+
+    #[test]
+    fn test_records_equal() {
+        let text = |s: &str| OwnedValue::Text(Rc::new(s.to_string()));
+        let a = OwnedRecord::new(vec![OwnedValue::Integer(1), OwnedValue::Null, text("a")]);
+        let b = OwnedRecord::new(vec![OwnedValue::Float(1.0), OwnedValue::Null, text("a")]);
+        assert_ne!(a, b);
+        assert!(records_equal(&a, &b));
+        assert!(records_equal(&b, &a));
+
+        let different = OwnedRecord::new(vec![OwnedValue::Float(1.5), OwnedValue::Null, text("a")]);
+        assert!(!records_equal(&a, &different));
+        let text_one = OwnedRecord::new(vec![text("1"), OwnedValue::Null, text("a")]);
+        assert!(!records_equal(&a, &text_one));
+        let shorter = OwnedRecord::new(vec![OwnedValue::Integer(1), OwnedValue::Null]);
+        assert!(!records_equal(&a, &shorter));
+    }
+
+    #[test]
+    fn test_from_record() {
+        let record = OwnedRecord::new(vec![
+            OwnedValue::Integer(7),
+            OwnedValue::Text(Rc::new("seven".to_string())),
+        ]);
+        let row = Row::from_record(&record).unwrap();
+        assert_eq!(row.id, 7);
+        assert_eq!(row.name, "seven");
+        assert!(record.get::<i64>(1).is_err());
+        assert!(record.get::<i64>(2).is_err());
+    }
+}
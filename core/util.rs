@@ -20,7 +20,27 @@ pub fn parse_schema_rows(rows: Option<Rows>, schema: &mut Schema, io: Arc<dyn IO
             match rows.next_row()? {
                 RowResult::Row(row) => {
                     let ty = row.get::<&str>(0)?;
-                    if ty != "table" && ty != "index" {
+                    let Ok(entry_type) = ty.parse::<schema::SchemaEntryType>() else {
+                        continue;
+                    };
+                    if !entry_type.has_btree() {
+                        let root_page: i64 = row.get::<i64>(3)?;
+                        let name: &str = row.get::<&str>(1)?;
+                        if root_page != 0 {
+                            crate::bail_corrupt_error!(
+                                "{} {} has root page {}, expected 0",
+                                ty,
+                                name,
+                                root_page
+                            );
+                        }
+                        schema.add_entry(Rc::new(schema::SchemaEntry {
+                            entry_type,
+                            name: name.to_string(),
+                            table_name: row.get::<&str>(2)?.to_string(),
+                            root_page: 0,
+                            sql: row.get::<&str>(4)?.to_string(),
+                        }));
                         continue;
                     }
                     match ty {
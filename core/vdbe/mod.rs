@@ -1361,8 +1361,14 @@ impl Program {
                         let null_flag = cursor.get_null_flag();
                         state.registers[*dest] = if null_flag {
                             OwnedValue::Null
+                        } else if let Some(value) = record.values.get(*column) {
+                            value.clone()
                         } else {
-                            record.values[*column].clone()
+                            // Rows written before the column was added
+                            match &self.cursor_ref[*cursor_id].1 {
+                                Some(table) => table.column_default(*column),
+                                None => OwnedValue::Null,
+                            }
                         };
                     } else {
                         state.registers[*dest] = OwnedValue::Null;
@@ -1454,6 +1460,12 @@ impl Program {
                 Insn::Transaction { write } => {
                     let connection = self.connection.upgrade().unwrap();
                     if let Some(db) = connection.db.upgrade() {
+                        if *write && !db.header.borrow().can_write() {
+                            return Err(LimboError::ReadOnly);
+                        }
+                        if *write {
+                            db.last_insert_rowid.set(0);
+                        }
                         // TODO(pere): are backpointers good ?? this looks ugly af
                         // upgrade transaction if needed
                         let new_transaction_state =
@@ -2309,6 +2321,12 @@ impl Program {
                     };
                     let key = &state.registers[*key_reg];
                     return_if_io!(cursor.insert(key, record, true));
+                    if let OwnedValue::Integer(rowid) = key {
+                        let db = self.connection.upgrade().and_then(|c| c.db.upgrade());
+                        if let Some(db) = db {
+                            db.last_insert_rowid.set(*rowid);
+                        }
+                    }
                     state.pc += 1;
                 }
                 Insn::InsertAwait { cursor_id } => {
@@ -397,6 +397,106 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_last_insert_rowid() -> anyhow::Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_db = TempDatabase::new("CREATE TABLE test (x INTEGER PRIMARY KEY, t TEXT);");
+        let db = Database::open_file(tmp_db.io.clone(), tmp_db.path.to_str().unwrap())?;
+        let conn = db.connect();
+        assert_eq!(db.last_insert_rowid(), 0);
+        for (rowid, text) in [(3, "a"), (8, "b")] {
+            let mut rows = conn
+                .query(format!("INSERT INTO test VALUES ({}, '{}')", rowid, text))?
+                .unwrap();
+            loop {
+                match rows.next_row()? {
+                    RowResult::IO => {
+                        tmp_db.io.run_once()?;
+                    }
+                    RowResult::Done => break,
+                    _ => unreachable!(),
+                }
+            }
+            assert_eq!(db.last_insert_rowid(), rowid);
+        }
+        do_flush(&conn, &tmp_db)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_added_column_default() -> anyhow::Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_db = TempDatabase::new("CREATE TABLE test (x INTEGER PRIMARY KEY, y TEXT);");
+        {
+            let connection = rusqlite::Connection::open(&tmp_db.path).unwrap();
+            connection
+                .execute_batch(
+                    "INSERT INTO test VALUES (1, 'old');
+                     ALTER TABLE test ADD COLUMN z INTEGER DEFAULT 5;
+                     INSERT INTO test VALUES (2, 'new', 7);",
+                )
+                .unwrap();
+        }
+        let conn = tmp_db.connect_limbo();
+        let mut rows = conn.query("SELECT x, z FROM test")?.unwrap();
+        let mut values = Vec::new();
+        loop {
+            match rows.next_row()? {
+                RowResult::Row(row) => {
+                    values.push((row.get::<i64>(0)?, row.get::<i64>(1)?));
+                }
+                RowResult::IO => {
+                    tmp_db.io.run_once()?;
+                }
+                RowResult::Done => break,
+            }
+        }
+        // The row written before the column was added reads the declared default.
+        assert_eq!(values, vec![(1, 5), (2, 7)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_utf16_database() -> anyhow::Result<()> {
+        let _ = env_logger::try_init();
+        let strings = ["hello", "héllo wörld", "日本語", "emoji 🦀", ""];
+        let mut path = TempDir::new().unwrap().into_path();
+        path.push("test.db");
+        {
+            let connection = rusqlite::Connection::open(&path).unwrap();
+            connection
+                .execute_batch(
+                    "PRAGMA encoding = 'UTF-16le';
+                     CREATE TABLE strings (x INTEGER PRIMARY KEY, s TEXT);
+                     CREATE INDEX strings_s ON strings (s);",
+                )
+                .unwrap();
+            for (i, s) in strings.iter().enumerate() {
+                connection
+                    .execute("INSERT INTO strings VALUES (?1, ?2)", (i as i64, s))
+                    .unwrap();
+            }
+            let encoding: String = connection
+                .query_row("PRAGMA encoding", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(encoding, "UTF-16le");
+        }
+        let io: Arc<dyn limbo_core::IO> = Arc::new(limbo_core::PlatformIO::new().unwrap());
+        let db = Database::open_file(io.clone(), path.to_str().unwrap())?;
+        let conn = db.connect();
+        let mut rows = conn.query("SELECT s FROM strings ORDER BY x")?.unwrap();
+        let mut values = Vec::new();
+        loop {
+            match rows.next_row()? {
+                RowResult::Row(row) => values.push(row.get::<String>(0)?),
+                RowResult::IO => io.run_once()?,
+                RowResult::Done => break,
+            }
+        }
+        assert_eq!(values, strings);
+        Ok(())
+    }
+
     fn do_flush(conn: &Rc<Connection>, tmp_db: &TempDatabase) -> anyhow::Result<()> {
         loop {
             match conn.cacheflush()? {